@@ -0,0 +1,306 @@
+//! This contains code for recording packets to a capture file and reading them back again
+
+use std::io::{Read, Write};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
+
+/// The bytes at the start of every capture file
+const MAGIC: &[u8; 6] = b"RLCAP\0";
+
+/// The version of the capture format written by this code
+const VERSION: u8 = 1;
+
+/// The number of entries that can wait to be written before new entries are dropped
+const QUEUE_SIZE: usize = 1024;
+
+/// The errors that can occur when reading or writing a capture file
+#[derive(Debug)]
+pub enum CaptureError {
+    /// An io error occurred
+    IoError(std::io::Error),
+    /// The file does not start with the capture file header
+    BadMagic,
+    /// The file was written with a version of the format that is not supported
+    UnsupportedVersion(u8),
+    /// The direction of an entry is not valid
+    InvalidDirection(u8),
+    /// The file ends in the middle of an entry
+    Truncated,
+}
+
+impl From<std::io::Error> for CaptureError {
+    fn from(a: std::io::Error) -> CaptureError {
+        if a.kind() == std::io::ErrorKind::UnexpectedEof {
+            CaptureError::Truncated
+        } else {
+            CaptureError::IoError(a)
+        }
+    }
+}
+
+/// The direction a captured packet was travelling
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    /// The packet was sent by the client to the server
+    ClientToServer = 0,
+    /// The packet was sent by the server to the client
+    ServerToClient = 1,
+}
+
+impl TryFrom<u8> for Direction {
+    type Error = CaptureError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::ClientToServer),
+            1 => Ok(Self::ServerToClient),
+            v => Err(CaptureError::InvalidDirection(v)),
+        }
+    }
+}
+
+/// A single packet in a capture file
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaptureEntry {
+    /// The direction the packet was travelling
+    pub direction: Direction,
+    /// The time since the start of the capture
+    pub timestamp: Duration,
+    /// The key used to encrypt or decrypt the packet, None when the packet was not encrypted
+    pub key: Option<u64>,
+    /// The decrypted contents of the packet
+    pub data: Vec<u8>,
+}
+
+/// Writes entries to a capture file.
+/// Every entry is stored as the direction (u8), the timestamp in milliseconds (u64),
+/// a flag (u8) followed by the key (u64), the payload length (u32) and the payload itself.
+/// All numbers are little endian.
+pub struct CaptureWriter<W: Write> {
+    /// Where the capture is written to
+    writer: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Construct a new capture writer, writing the file header
+    pub fn new(mut writer: W) -> Result<Self, CaptureError> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        Ok(Self { writer })
+    }
+
+    /// Write a single entry to the capture
+    pub fn write_entry(&mut self, e: &CaptureEntry) -> Result<(), CaptureError> {
+        let mut buf = Vec::with_capacity(e.data.len() + 22);
+        buf.push(e.direction as u8);
+        buf.extend_from_slice(&(e.timestamp.as_millis() as u64).to_le_bytes());
+        buf.push(e.key.is_some() as u8);
+        buf.extend_from_slice(&e.key.unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(&(e.data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&e.data);
+        self.writer.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Flush the entries written so far
+    pub fn flush(&mut self) -> Result<(), CaptureError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads entries from a capture file
+pub struct CaptureReader<R: Read> {
+    /// Where the capture is read from
+    reader: R,
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Construct a new capture reader, checking the file header
+    pub fn new(mut reader: R) -> Result<Self, CaptureError> {
+        let mut magic = [0; 6];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(CaptureError::BadMagic);
+        }
+        let mut version = [0; 1];
+        reader.read_exact(&mut version)?;
+        if version[0] != VERSION {
+            return Err(CaptureError::UnsupportedVersion(version[0]));
+        }
+        Ok(Self { reader })
+    }
+
+    /// Read the next entry, returns None at the end of the capture
+    pub fn read_entry(&mut self) -> Result<Option<CaptureEntry>, CaptureError> {
+        let mut direction = [0; 1];
+        if self.reader.read(&mut direction)? == 0 {
+            return Ok(None);
+        }
+        let direction = Direction::try_from(direction[0])?;
+        let mut header = [0; 21];
+        self.reader.read_exact(&mut header)?;
+        let timestamp = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let key = u64::from_le_bytes(header[9..17].try_into().unwrap());
+        let length = u32::from_le_bytes(header[17..21].try_into().unwrap());
+        let mut data = vec![0; length as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok(Some(CaptureEntry {
+            direction,
+            timestamp: Duration::from_millis(timestamp),
+            key: if header[8] != 0 { Some(key) } else { None },
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CaptureEntry, CaptureError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry().transpose()
+    }
+}
+
+/// A shareable handle for recording the packets of a single connection.
+/// The entries are written on a thread of their own, so recording never waits for the disk.
+#[derive(Clone)]
+pub struct Recorder {
+    /// Sends entries to the thread writing the capture
+    sender: SyncSender<CaptureEntry>,
+    /// When the capture was started
+    start: Instant,
+}
+
+impl Recorder {
+    /// Construct a recorder that writes to the given writer.
+    /// The writer is closed when every copy of the recorder is dropped.
+    pub fn new<W: Write + Send + 'static>(w: W) -> Result<Self, CaptureError> {
+        let writer = CaptureWriter::new(w)?;
+        let (sender, receiver) = std::sync::mpsc::sync_channel(QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name("capture writer".to_string())
+            .spawn(move || Self::write_entries(writer, receiver))?;
+        Ok(Self {
+            sender,
+            start: Instant::now(),
+        })
+    }
+
+    /// Write entries until every recorder is dropped, flushing whenever no more entries are waiting
+    fn write_entries<W: Write>(mut writer: CaptureWriter<W>, receiver: Receiver<CaptureEntry>) {
+        while let Ok(e) = receiver.recv() {
+            let mut result = writer.write_entry(&e);
+            for e in receiver.try_iter() {
+                if result.is_err() {
+                    break;
+                }
+                result = writer.write_entry(&e);
+            }
+            if let Err(e) = result.and_then(|()| writer.flush()) {
+                log::error!("Failed to record packets, the capture is stopped: {:?}", e);
+                return;
+            }
+        }
+    }
+
+    /// Create a new capture file and construct a recorder for it
+    pub fn create<P: AsRef<std::path::Path>>(path: P) -> Result<Self, CaptureError> {
+        let f = std::fs::File::create(path)?;
+        Self::new(std::io::BufWriter::new(f))
+    }
+
+    /// Record a packet. Failures are logged, they should not interrupt the connection being recorded.
+    pub fn record(&self, direction: Direction, key: Option<u64>, data: &[u8]) {
+        let e = CaptureEntry {
+            direction,
+            timestamp: self.start.elapsed(),
+            key,
+            data: data.to_vec(),
+        };
+        match self.sender.try_send(e) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                log::error!("The capture can not keep up, dropping a packet");
+            }
+            //the writer already logged why it stopped
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that entries survive being written and read back again
+    #[test]
+    fn write_read() {
+        let entries = vec![
+            CaptureEntry {
+                direction: Direction::ServerToClient,
+                timestamp: Duration::from_millis(0),
+                key: None,
+                data: vec![65, 1, 2, 3, 4],
+            },
+            CaptureEntry {
+                direction: Direction::ClientToServer,
+                timestamp: Duration::from_millis(1234),
+                key: Some(0x24700c1a554e71f5),
+                data: vec![71, 1, 2, 3],
+            },
+            CaptureEntry {
+                direction: Direction::ServerToClient,
+                timestamp: Duration::from_millis(5000),
+                key: Some(0),
+                data: vec![],
+            },
+        ];
+        let mut w = CaptureWriter::new(Vec::new()).unwrap();
+        for e in &entries {
+            w.write_entry(e).unwrap();
+        }
+        let data = w.writer;
+        let r = CaptureReader::new(&data[..]).unwrap();
+        let read: Vec<CaptureEntry> = r.map(|e| e.unwrap()).collect();
+        assert_eq!(read, entries);
+
+        let mut r = CaptureReader::new(&data[0..data.len() - 1]).unwrap();
+        r.read_entry().unwrap();
+        r.read_entry().unwrap();
+        assert!(matches!(r.read_entry(), Err(CaptureError::Truncated)));
+        assert!(matches!(
+            CaptureReader::new(&b"RLCAP\0\x02"[..]),
+            Err(CaptureError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            CaptureReader::new(&b"garbage"[..]),
+            Err(CaptureError::BadMagic)
+        ));
+    }
+
+    /// Tests that a recorder writes its entries on the writer thread
+    #[test]
+    fn recorder() {
+        let path = std::env::temp_dir().join(format!("recorder-{}.rlcap", std::process::id()));
+        let r = Recorder::create(&path).unwrap();
+        r.record(Direction::ServerToClient, None, &[65, 1, 2, 3, 4]);
+        r.clone()
+            .record(Direction::ClientToServer, Some(1234), &[71, 1, 2]);
+        drop(r);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let read = loop {
+            let f = std::fs::File::open(&path).unwrap();
+            let read: Vec<CaptureEntry> = CaptureReader::new(f)
+                .map(|r| r.filter_map(|e| e.ok()).collect())
+                .unwrap_or_default();
+            if read.len() == 2 || Instant::now() > deadline {
+                break read;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].data, vec![65, 1, 2, 3, 4]);
+        assert_eq!(read[1].key, Some(1234));
+    }
+}
@@ -0,0 +1,600 @@
+//! This contains the typed model of the extended description of an item, see `InventoryElement::ed`
+
+use crate::packet::{Packet, PacketDecodeError};
+
+/// The material an item is made of
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Material {
+    /// Liquid
+    Liquid,
+    /// Web
+    Web,
+    /// Vegetation
+    Vegetation,
+    /// Animal Matter
+    AnimalMatter,
+    /// Paper
+    Paper,
+    /// Cloth
+    Cloth,
+    /// Leather
+    Leather,
+    /// Wood
+    Wood,
+    /// Bone
+    Bone,
+    /// Dragon Scale
+    DragonScale,
+    /// Iron
+    Iron,
+    /// Metal
+    Metal,
+    /// Copper
+    Copper,
+    /// Silver
+    Silver,
+    /// Gold
+    Gold,
+    /// Platinum
+    Platinum,
+    /// Mithril
+    Mithril,
+    /// Black Mithril
+    BlackMithril,
+    /// Glass
+    Glass,
+    /// Gemstone
+    Gemstone,
+    /// Mineral
+    Mineral,
+    /// Oriharukon
+    Oriharukon,
+    /// A material that does not have a name yet, 0 is used for no material
+    Other(u8),
+}
+
+impl Material {
+    /// The value sent in a packet
+    pub fn value(&self) -> u8 {
+        match self {
+            Self::Liquid => 1,
+            Self::Web => 2,
+            Self::Vegetation => 3,
+            Self::AnimalMatter => 4,
+            Self::Paper => 5,
+            Self::Cloth => 6,
+            Self::Leather => 7,
+            Self::Wood => 8,
+            Self::Bone => 9,
+            Self::DragonScale => 10,
+            Self::Iron => 11,
+            Self::Metal => 12,
+            Self::Copper => 13,
+            Self::Silver => 14,
+            Self::Gold => 15,
+            Self::Platinum => 16,
+            Self::Mithril => 17,
+            Self::BlackMithril => 18,
+            Self::Glass => 19,
+            Self::Gemstone => 20,
+            Self::Mineral => 21,
+            Self::Oriharukon => 22,
+            Self::Other(v) => *v,
+        }
+    }
+}
+
+impl From<u8> for Material {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Liquid,
+            2 => Self::Web,
+            3 => Self::Vegetation,
+            4 => Self::AnimalMatter,
+            5 => Self::Paper,
+            6 => Self::Cloth,
+            7 => Self::Leather,
+            8 => Self::Wood,
+            9 => Self::Bone,
+            10 => Self::DragonScale,
+            11 => Self::Iron,
+            12 => Self::Metal,
+            13 => Self::Copper,
+            14 => Self::Silver,
+            15 => Self::Gold,
+            16 => Self::Platinum,
+            17 => Self::Mithril,
+            18 => Self::BlackMithril,
+            19 => Self::Glass,
+            20 => Self::Gemstone,
+            21 => Self::Mineral,
+            22 => Self::Oriharukon,
+            v => Self::Other(v),
+        }
+    }
+}
+
+/// A single element of the extended description of an item
+#[derive(Clone, Debug, PartialEq)]
+pub enum ItemDescriptor {
+    /// 1 - Weapon damage against small and large targets
+    Damage {
+        /// Damage against small targets
+        small: u8,
+        /// Damage against large targets
+        large: u8,
+        /// The material of the item
+        material: Material,
+        /// The weight of the item
+        weight: u32,
+    },
+    /// 2 - The enchantment level, follows a Damage or ArmorClass
+    Enchant(u8),
+    /// 3 - Damage d
+    FixedDamage(u8),
+    /// 4 - Two handed weapon
+    TwoHanded,
+    /// 5 - Hit bonus
+    HitBonus(u8),
+    /// 6 - Damage bonus
+    DamageBonus(u8),
+    /// 7 - The classes that can use the item. 1 = Prince/Princess, 2 = Knight, 4 = Elf, 8 = Wizard, 16 = Dark Elf, 32 = Dragon Knight, 64 = Illusionist, 128 = High Pet
+    ClassMask(u8),
+    /// 8 - Strength bonus
+    Strength(u8),
+    /// 9 - Dexterity bonus
+    Dexterity(u8),
+    /// 10 - Constitution bonus
+    Constitution(u8),
+    /// 11 - Wisdom bonus
+    Wisdom(u8),
+    /// 12 - Intelligence bonus
+    Intelligence(u8),
+    /// 13 - Charisma bonus
+    Charisma(u8),
+    /// 14 - Maximum hp bonus
+    MaxHp(u16),
+    /// 15 - Magic defense bonus
+    MagicDefense(u16),
+    /// 16 - Mana absorption
+    ManaAbsorption,
+    /// 17 - Spell power bonus
+    SpellPower(u8),
+    /// 18 - Maintains haste when held
+    Haste,
+    /// 19 - Armor class
+    ArmorClass {
+        /// The armor class
+        ac: u8,
+        /// The grade, high = 0, medium = 1, low = 2
+        grade: u8,
+        /// The material of the item
+        material: Material,
+        /// The weight of the item
+        weight: u32,
+    },
+    /// 20 - Luck bonus
+    Luck(u8),
+    /// 21 - Nutrition of food
+    Nutrition {
+        /// The nutrition value
+        nutrition: u16,
+        /// The material of the item
+        material: Material,
+        /// The weight of the item
+        weight: u32,
+    },
+    /// 22 - Lightness of a light source
+    Lightness {
+        /// The lightness value
+        lightness: u16,
+        /// The material of the item
+        material: Material,
+        /// The weight of the item
+        weight: u32,
+    },
+    /// 23 - Only the material and weight
+    Weight {
+        /// The material of the item
+        material: Material,
+        /// The weight of the item
+        weight: u32,
+    },
+    /// 24 - Bow hit bonus
+    BowHitBonus(u8),
+    /// 25 - Class $d, unsure of valid values
+    Class(u16),
+    /// 26 - Level
+    Level(u16),
+    /// 27 - Fire elemental
+    FireElemental(u8),
+    /// 28 - Water elemental
+    WaterElemental(u8),
+    /// 29 - Wind elemental
+    WindElemental(u8),
+    /// 30 - Earth elemental
+    EarthElemental(u8),
+    /// 31 - Maximum hp modifier
+    MaxHpModifier(i16),
+    /// 32 - Maximum mp bonus
+    MaxMp(i8),
+    /// 33 - Modifies the magic defense, must follow a MagicDefense. 1 = Freeze, 2 = Petrify, 3 = Sleep, 4 = Darkness, 5 = Stun, 6 = Hold, 7 = None
+    Resistance(u8),
+    /// 34 - Life suction
+    LifeSuction,
+    /// 35 - Bow damage bonus
+    BowDamage(u8),
+    /// 36 - dummy for branch
+    Branch(u8),
+    /// 37 - Healing rate
+    HealingRate(u8),
+    /// 38 - Mana healing rate
+    ManaHealingRate(u8),
+    /// 39 - A plain string
+    Text(String),
+    /// 40 - Unknown
+    Unknown40(u8),
+}
+
+impl ItemDescriptor {
+    /// Add the descriptor to a packet
+    fn add(&self, p: &mut Packet) {
+        match self {
+            Self::Damage {
+                small,
+                large,
+                material,
+                weight,
+            } => {
+                p.add_u8(1)
+                    .add_u8(*small)
+                    .add_u8(*large)
+                    .add_u8(material.value())
+                    .add_u32(*weight);
+            }
+            Self::Enchant(v) => {
+                p.add_u8(2).add_u8(*v);
+            }
+            Self::FixedDamage(v) => {
+                p.add_u8(3).add_u8(*v);
+            }
+            Self::TwoHanded => {
+                p.add_u8(4);
+            }
+            Self::HitBonus(v) => {
+                p.add_u8(5).add_u8(*v);
+            }
+            Self::DamageBonus(v) => {
+                p.add_u8(6).add_u8(*v);
+            }
+            Self::ClassMask(v) => {
+                p.add_u8(7).add_u8(*v);
+            }
+            Self::Strength(v) => {
+                p.add_u8(8).add_u8(*v);
+            }
+            Self::Dexterity(v) => {
+                p.add_u8(9).add_u8(*v);
+            }
+            Self::Constitution(v) => {
+                p.add_u8(10).add_u8(*v);
+            }
+            Self::Wisdom(v) => {
+                p.add_u8(11).add_u8(*v);
+            }
+            Self::Intelligence(v) => {
+                p.add_u8(12).add_u8(*v);
+            }
+            Self::Charisma(v) => {
+                p.add_u8(13).add_u8(*v);
+            }
+            Self::MaxHp(v) => {
+                p.add_u8(14).add_u16(*v);
+            }
+            Self::MagicDefense(v) => {
+                p.add_u8(15).add_u16(*v);
+            }
+            Self::ManaAbsorption => {
+                p.add_u8(16);
+            }
+            Self::SpellPower(v) => {
+                p.add_u8(17).add_u8(*v);
+            }
+            Self::Haste => {
+                p.add_u8(18);
+            }
+            Self::ArmorClass {
+                ac,
+                grade,
+                material,
+                weight,
+            } => {
+                p.add_u8(19)
+                    .add_u8(*ac)
+                    .add_u8(*grade)
+                    .add_u8(material.value())
+                    .add_u32(*weight);
+            }
+            Self::Luck(v) => {
+                p.add_u8(20).add_u8(*v);
+            }
+            Self::Nutrition {
+                nutrition,
+                material,
+                weight,
+            } => {
+                p.add_u8(21)
+                    .add_u16(*nutrition)
+                    .add_u8(material.value())
+                    .add_u32(*weight);
+            }
+            Self::Lightness {
+                lightness,
+                material,
+                weight,
+            } => {
+                p.add_u8(22)
+                    .add_u16(*lightness)
+                    .add_u8(material.value())
+                    .add_u32(*weight);
+            }
+            Self::Weight { material, weight } => {
+                p.add_u8(23).add_u8(material.value()).add_u32(*weight);
+            }
+            Self::BowHitBonus(v) => {
+                p.add_u8(24).add_u8(*v);
+            }
+            Self::Class(v) => {
+                p.add_u8(25).add_u16(*v);
+            }
+            Self::Level(v) => {
+                p.add_u8(26).add_u16(*v);
+            }
+            Self::FireElemental(v) => {
+                p.add_u8(27).add_u8(*v);
+            }
+            Self::WaterElemental(v) => {
+                p.add_u8(28).add_u8(*v);
+            }
+            Self::WindElemental(v) => {
+                p.add_u8(29).add_u8(*v);
+            }
+            Self::EarthElemental(v) => {
+                p.add_u8(30).add_u8(*v);
+            }
+            Self::MaxHpModifier(v) => {
+                p.add_u8(31).add_i16(*v);
+            }
+            Self::MaxMp(v) => {
+                p.add_u8(32).add_i8(*v);
+            }
+            Self::Resistance(v) => {
+                p.add_u8(33).add_u8(*v);
+            }
+            Self::LifeSuction => {
+                p.add_u8(34);
+            }
+            Self::BowDamage(v) => {
+                p.add_u8(35).add_u8(*v);
+            }
+            Self::Branch(v) => {
+                p.add_u8(36).add_u8(*v);
+            }
+            Self::HealingRate(v) => {
+                p.add_u8(37).add_u8(*v);
+            }
+            Self::ManaHealingRate(v) => {
+                p.add_u8(38).add_u8(*v);
+            }
+            Self::Text(s) => {
+                p.add_u8(39).add_string(s);
+            }
+            Self::Unknown40(v) => {
+                p.add_u8(40).add_u8(*v);
+            }
+        }
+    }
+
+    /// Read a descriptor from a packet, returns None for an opcode that ends the description
+    fn pull(p: &mut Packet) -> Result<Option<Self>, PacketDecodeError> {
+        let opcode = p.pull_u8()?;
+        Ok(Some(match opcode {
+            1 => Self::Damage {
+                small: p.pull_u8()?,
+                large: p.pull_u8()?,
+                material: p.pull_u8()?.into(),
+                weight: p.pull_u32()?,
+            },
+            2 => Self::Enchant(p.pull_u8()?),
+            3 => Self::FixedDamage(p.pull_u8()?),
+            4 => Self::TwoHanded,
+            5 => Self::HitBonus(p.pull_u8()?),
+            6 => Self::DamageBonus(p.pull_u8()?),
+            7 => Self::ClassMask(p.pull_u8()?),
+            8 => Self::Strength(p.pull_u8()?),
+            9 => Self::Dexterity(p.pull_u8()?),
+            10 => Self::Constitution(p.pull_u8()?),
+            11 => Self::Wisdom(p.pull_u8()?),
+            12 => Self::Intelligence(p.pull_u8()?),
+            13 => Self::Charisma(p.pull_u8()?),
+            14 => Self::MaxHp(p.pull_u16()?),
+            15 => Self::MagicDefense(p.pull_u16()?),
+            16 => Self::ManaAbsorption,
+            17 => Self::SpellPower(p.pull_u8()?),
+            18 => Self::Haste,
+            19 => Self::ArmorClass {
+                ac: p.pull_u8()?,
+                grade: p.pull_u8()?,
+                material: p.pull_u8()?.into(),
+                weight: p.pull_u32()?,
+            },
+            20 => Self::Luck(p.pull_u8()?),
+            21 => Self::Nutrition {
+                nutrition: p.pull_u16()?,
+                material: p.pull_u8()?.into(),
+                weight: p.pull_u32()?,
+            },
+            22 => Self::Lightness {
+                lightness: p.pull_u16()?,
+                material: p.pull_u8()?.into(),
+                weight: p.pull_u32()?,
+            },
+            23 => Self::Weight {
+                material: p.pull_u8()?.into(),
+                weight: p.pull_u32()?,
+            },
+            24 => Self::BowHitBonus(p.pull_u8()?),
+            25 => Self::Class(p.pull_u16()?),
+            26 => Self::Level(p.pull_u16()?),
+            27 => Self::FireElemental(p.pull_u8()?),
+            28 => Self::WaterElemental(p.pull_u8()?),
+            29 => Self::WindElemental(p.pull_u8()?),
+            30 => Self::EarthElemental(p.pull_u8()?),
+            31 => Self::MaxHpModifier(p.pull_i16()?),
+            32 => Self::MaxMp(p.pull_i8()?),
+            33 => Self::Resistance(p.pull_u8()?),
+            34 => Self::LifeSuction,
+            35 => Self::BowDamage(p.pull_u8()?),
+            36 => Self::Branch(p.pull_u8()?),
+            37 => Self::HealingRate(p.pull_u8()?),
+            38 => Self::ManaHealingRate(p.pull_u8()?),
+            39 => Self::Text(p.pull_string()?),
+            40 => Self::Unknown40(p.pull_u8()?),
+            _ => return Ok(None),
+        }))
+    }
+
+    /// Build the extended description bytes for a list of descriptors
+    pub fn encode(descriptors: &[ItemDescriptor]) -> Vec<u8> {
+        let mut p = Packet::new();
+        for d in descriptors {
+            d.add(&mut p);
+        }
+        p.buf()
+    }
+
+    /// Parse extended description bytes. Like the game client, parsing stops at the first unknown opcode.
+    pub fn decode(ed: &[u8]) -> Result<Vec<ItemDescriptor>, PacketDecodeError> {
+        let mut p = Packet::raw_packet(ed.to_vec());
+        let mut descriptors = Vec::new();
+        while !p.is_empty() {
+            match Self::pull(&mut p)? {
+                Some(d) => descriptors.push(d),
+                None => break,
+            }
+        }
+        Ok(descriptors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that every documented opcode survives being encoded and decoded again
+    #[test]
+    fn round_trip() {
+        let descriptors = vec![
+            ItemDescriptor::Damage {
+                small: 6,
+                large: 8,
+                material: Material::Iron,
+                weight: 1500,
+            },
+            ItemDescriptor::Enchant(3),
+            ItemDescriptor::FixedDamage(4),
+            ItemDescriptor::TwoHanded,
+            ItemDescriptor::HitBonus(1),
+            ItemDescriptor::DamageBonus(2),
+            ItemDescriptor::ClassMask(127),
+            ItemDescriptor::Strength(1),
+            ItemDescriptor::Dexterity(2),
+            ItemDescriptor::Constitution(3),
+            ItemDescriptor::Wisdom(4),
+            ItemDescriptor::Intelligence(5),
+            ItemDescriptor::Charisma(6),
+            ItemDescriptor::MaxHp(300),
+            ItemDescriptor::MagicDefense(10),
+            ItemDescriptor::Resistance(3),
+            ItemDescriptor::ManaAbsorption,
+            ItemDescriptor::SpellPower(2),
+            ItemDescriptor::Haste,
+            ItemDescriptor::ArmorClass {
+                ac: 3,
+                grade: 1,
+                material: Material::Leather,
+                weight: 200,
+            },
+            ItemDescriptor::Luck(1),
+            ItemDescriptor::Nutrition {
+                nutrition: 20,
+                material: Material::Vegetation,
+                weight: 1,
+            },
+            ItemDescriptor::Lightness {
+                lightness: 14,
+                material: Material::Metal,
+                weight: 100,
+            },
+            ItemDescriptor::Weight {
+                material: Material::Other(0),
+                weight: 0,
+            },
+            ItemDescriptor::BowHitBonus(1),
+            ItemDescriptor::Class(2),
+            ItemDescriptor::Level(52),
+            ItemDescriptor::FireElemental(1),
+            ItemDescriptor::WaterElemental(2),
+            ItemDescriptor::WindElemental(3),
+            ItemDescriptor::EarthElemental(4),
+            ItemDescriptor::MaxHpModifier(-20),
+            ItemDescriptor::MaxMp(-5),
+            ItemDescriptor::LifeSuction,
+            ItemDescriptor::BowDamage(3),
+            ItemDescriptor::Branch(1),
+            ItemDescriptor::HealingRate(2),
+            ItemDescriptor::ManaHealingRate(3),
+            ItemDescriptor::Text("text".to_string()),
+            ItemDescriptor::Unknown40(7),
+        ];
+        for d in &descriptors {
+            let single = vec![d.clone()];
+            assert_eq!(
+                ItemDescriptor::decode(&ItemDescriptor::encode(&single)),
+                Ok(single)
+            );
+        }
+        let ed = ItemDescriptor::encode(&descriptors);
+        assert_eq!(ItemDescriptor::decode(&ed), Ok(descriptors));
+    }
+
+    /// Tests the exact bytes for a weapon and that decoding stops at unknown opcodes
+    #[test]
+    fn known_bytes() {
+        let ed = ItemDescriptor::encode(&[
+            ItemDescriptor::Damage {
+                small: 6,
+                large: 8,
+                material: Material::Iron,
+                weight: 1500,
+            },
+            ItemDescriptor::Enchant(3),
+        ]);
+        assert_eq!(ed, vec![1, 6, 8, 11, 0xdc, 0x05, 0, 0, 2, 3]);
+
+        assert_eq!(
+            ItemDescriptor::decode(&[4, 41, 4, 4]),
+            Ok(vec![ItemDescriptor::TwoHanded])
+        );
+        assert_eq!(
+            ItemDescriptor::decode(&[1, 6, 8]),
+            Err(PacketDecodeError::ShortRead {
+                needed: 1,
+                remaining: 0
+            })
+        );
+        for m in 0..=255u8 {
+            assert_eq!(Material::from(m).value(), m);
+        }
+    }
+}
@@ -3,4 +3,7 @@
 
 //! This crate contains code common to both the server and client implementations
 
+pub mod capture;
+pub mod item;
+pub mod opcode;
 pub mod packet;
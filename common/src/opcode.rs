@@ -0,0 +1,334 @@
+//! Opcode tables, used to map the opcodes of packets for different versions of the game client.
+//! Only the table of the classic client is known so far, every client version uses it.
+
+use crate::packet::PacketError;
+
+/// The kinds of packets sent by the client
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ClientOpcode {
+    /// The client wants to use an item
+    UseItem,
+    /// Login to an account
+    Login,
+    /// Whisper to another player
+    WhisperChat,
+    /// Create a bookmark
+    CreateBookmark,
+    /// Remove a friend
+    RemoveFriend,
+    /// Delete a character
+    DeleteCharacter,
+    /// Chat to the whole world
+    GlobalChat,
+    /// The news has been viewed
+    NewsDone,
+    /// Attack an object
+    AttackObject,
+    /// Restart or go back to character select
+    Restart,
+    /// Keep the connection alive
+    KeepAlive,
+    /// The version of the client
+    Version,
+    /// Create a new character
+    NewCharacter,
+    /// Change the direction of the character
+    ChangeDirection,
+    /// Add a friend
+    AddFriend,
+    /// Select a character to play
+    CharacterSelect,
+    /// Move the character
+    MoveFrom,
+    /// The game has finished loading
+    GameInitDone,
+    /// The game window was activated
+    WindowActivate,
+    /// Change the password of an account
+    ChangePassword,
+    /// The various forms of regular chat
+    Chat,
+    /// Save the character
+    Save,
+    /// A ping
+    Ping,
+    /// Look for other players
+    WhoCommand,
+}
+
+/// The kinds of packets sent by the server. Some kinds are shared by multiple types of packets, distinguished by a sub-type.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ServerOpcode {
+    /// Inventory elements
+    Inventory,
+    /// Chat from an object in the world
+    ObjectChat,
+    /// Remove an object
+    RemoveObject,
+    /// The version of the server
+    ServerVersion,
+    /// Disconnect the client
+    Disconnect,
+    /// Results of logging in
+    LoginResult,
+    /// An attack
+    Attack,
+    /// Change the direction of an object, no table has an opcode for it until one is confirmed
+    ChangeDirection,
+    /// Update the description of an inventory item
+    InventoryDescriptionUpdate,
+    /// Results of deleting a character
+    DeleteCharacter,
+    /// Chat from an npc
+    NpcChat,
+    /// Modify an inventory item
+    InventoryMod,
+    /// A list of inventory elements
+    InventoryVec,
+    /// Move an object
+    MoveObject,
+    /// Start the game
+    StartGame,
+    /// Put an object in the world
+    PutObject,
+    /// The initial encryption key
+    EncryptionKey,
+    /// The details of the character
+    CharacterDetails,
+    /// The map of the character
+    MapId,
+    /// Spell power and magic resistance bonus
+    CharSpMrBonus,
+    /// The weather
+    Weather,
+    /// A predefined message
+    Message,
+    /// The news
+    News,
+    /// Whisper chat
+    WhisperChat,
+    /// Details of a newly created character
+    NewCharacterDetails,
+    /// Details of a character at login
+    LoginCharacterDetails,
+    /// Chat from the server, global, pledge and party chat
+    ServerChat,
+    /// The status of creating a new character
+    CharacterCreationStatus,
+    /// Return to character select
+    BackToCharacterSelect,
+    /// The number of characters and slots
+    NumberCharacters,
+    /// Set the criminal count of an object
+    SetCriminalCount,
+    /// Clone an object
+    CloneObject,
+}
+
+/// A mapping of opcodes for a range of client versions
+#[derive(Debug)]
+pub struct OpcodeTable {
+    /// The name of the client build the table applies to
+    pub name: &'static str,
+    /// The first client version this table applies to
+    pub first_version: u32,
+    /// The opcodes of packets sent by the client
+    client: &'static [(u8, ClientOpcode)],
+    /// The opcodes of packets sent by the server
+    server: &'static [(ServerOpcode, u8)],
+}
+
+impl OpcodeTable {
+    /// Get the kind of packet for an opcode sent by the client
+    pub fn client_kind(&self, opcode: u8) -> Option<ClientOpcode> {
+        self.client
+            .iter()
+            .find(|(o, _)| *o == opcode)
+            .map(|(_, k)| *k)
+    }
+
+    /// Get the opcode for a kind of packet sent by the client
+    pub fn client_opcode(&self, kind: ClientOpcode) -> Result<u8, PacketError> {
+        self.client
+            .iter()
+            .find(|(_, k)| *k == kind)
+            .map(|(o, _)| *o)
+            .ok_or(PacketError::NoClientOpcode {
+                table: self.name,
+                kind,
+            })
+    }
+
+    /// Get the kind of packet for an opcode sent by the server
+    pub fn server_kind(&self, opcode: u8) -> Option<ServerOpcode> {
+        self.server
+            .iter()
+            .find(|(_, o)| *o == opcode)
+            .map(|(k, _)| *k)
+    }
+
+    /// Get the opcode for a kind of packet sent by the server
+    pub fn server_opcode(&self, kind: ServerOpcode) -> Result<u8, PacketError> {
+        self.server
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, o)| *o)
+            .ok_or(PacketError::NoServerOpcode {
+                table: self.name,
+                kind,
+            })
+    }
+
+    /// Get the table for the version of a client, as reported in the version packet.
+    /// This is the table with the highest first version that is not newer than the client.
+    /// While [TABLES] only has the classic table, this is always the classic table.
+    pub fn for_version(version: u32) -> &'static OpcodeTable {
+        TABLES
+            .iter()
+            .filter(|t| t.first_version <= version)
+            .max_by_key(|t| t.first_version)
+            .copied()
+            .unwrap_or(&CLASSIC)
+    }
+}
+
+/// The opcodes of the classic client
+pub static CLASSIC: OpcodeTable = OpcodeTable {
+    name: "classic",
+    first_version: 0,
+    client: &[
+        (1, ClientOpcode::UseItem),
+        (12, ClientOpcode::Login),
+        (13, ClientOpcode::WhisperChat),
+        (20, ClientOpcode::CreateBookmark),
+        (30, ClientOpcode::RemoveFriend),
+        (34, ClientOpcode::DeleteCharacter),
+        (40, ClientOpcode::GlobalChat),
+        (43, ClientOpcode::NewsDone),
+        (45, ClientOpcode::AttackObject),
+        (47, ClientOpcode::Restart),
+        (57, ClientOpcode::KeepAlive),
+        (71, ClientOpcode::Version),
+        (72, ClientOpcode::NewCharacter),
+        (74, ClientOpcode::ChangeDirection),
+        (79, ClientOpcode::AddFriend),
+        (83, ClientOpcode::CharacterSelect),
+        (88, ClientOpcode::MoveFrom),
+        (92, ClientOpcode::GameInitDone),
+        (97, ClientOpcode::WindowActivate),
+        (100, ClientOpcode::ChangePassword),
+        (104, ClientOpcode::Chat),
+        (111, ClientOpcode::Save),
+        (112, ClientOpcode::Ping),
+        (119, ClientOpcode::WhoCommand),
+    ],
+    server: &[
+        (ServerOpcode::Inventory, 6),
+        (ServerOpcode::ObjectChat, 8),
+        (ServerOpcode::RemoveObject, 9),
+        (ServerOpcode::ServerVersion, 10),
+        (ServerOpcode::Disconnect, 18),
+        (ServerOpcode::LoginResult, 21),
+        (ServerOpcode::Attack, 22),
+        (ServerOpcode::InventoryDescriptionUpdate, 29),
+        (ServerOpcode::DeleteCharacter, 33),
+        (ServerOpcode::NpcChat, 42),
+        (ServerOpcode::InventoryMod, 43),
+        (ServerOpcode::InventoryVec, 47),
+        (ServerOpcode::MoveObject, 61),
+        (ServerOpcode::StartGame, 63),
+        (ServerOpcode::PutObject, 64),
+        (ServerOpcode::EncryptionKey, 65),
+        (ServerOpcode::CharacterDetails, 69),
+        (ServerOpcode::MapId, 76),
+        (ServerOpcode::CharSpMrBonus, 80),
+        (ServerOpcode::Weather, 83),
+        (ServerOpcode::Message, 87),
+        (ServerOpcode::News, 90),
+        (ServerOpcode::WhisperChat, 91),
+        (ServerOpcode::NewCharacterDetails, 98),
+        (ServerOpcode::LoginCharacterDetails, 99),
+        (ServerOpcode::ServerChat, 105),
+        (ServerOpcode::CharacterCreationStatus, 106),
+        (ServerOpcode::BackToCharacterSelect, 107),
+        (ServerOpcode::NumberCharacters, 113),
+        (ServerOpcode::SetCriminalCount, 121),
+        (ServerOpcode::CloneObject, 126),
+    ],
+};
+
+/// All known opcode tables. Only the classic table is known, tables for other client builds go here once their opcodes have been mapped out.
+pub static TABLES: &[&OpcodeTable] = &[&CLASSIC];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::ServerPacket;
+
+    /// Checks that no opcode or kind is listed twice in any table
+    #[test]
+    fn tables_are_unique() {
+        for t in TABLES {
+            for (i, (o, k)) in t.client.iter().enumerate() {
+                for (o2, k2) in &t.client[i + 1..] {
+                    assert_ne!(o, o2, "{} duplicate client opcode", t.name);
+                    assert_ne!(k, k2, "{} duplicate client kind", t.name);
+                }
+            }
+            for (i, (k, o)) in t.server.iter().enumerate() {
+                for (k2, o2) in &t.server[i + 1..] {
+                    assert_ne!(o, o2, "{} duplicate server opcode", t.name);
+                    assert_ne!(k, k2, "{} duplicate server kind", t.name);
+                }
+            }
+        }
+    }
+
+    /// Checks that a kind missing from a table is an error
+    #[test]
+    fn missing_opcode() {
+        let t = OpcodeTable {
+            name: "empty",
+            first_version: 0,
+            client: &[],
+            server: &[],
+        };
+        assert!(matches!(
+            t.client_opcode(ClientOpcode::Login),
+            Err(PacketError::NoClientOpcode {
+                table: "empty",
+                kind: ClientOpcode::Login
+            })
+        ));
+        assert!(matches!(
+            t.server_opcode(ServerOpcode::Disconnect),
+            Err(PacketError::NoServerOpcode {
+                table: "empty",
+                kind: ServerOpcode::Disconnect
+            })
+        ));
+        assert!(matches!(
+            ServerPacket::Disconnect.build_with(&t),
+            Err(PacketError::NoServerOpcode { .. })
+        ));
+        assert!(matches!(
+            ServerPacket::ChangeDirection {
+                id: 1,
+                direction: 2
+            }
+            .build(),
+            Err(PacketError::NoServerOpcode {
+                table: "classic",
+                kind: ServerOpcode::ChangeDirection
+            })
+        ));
+    }
+
+    /// Checks that version lookup falls back to the classic table
+    #[test]
+    fn version_lookup() {
+        assert_eq!(OpcodeTable::for_version(0).name, "classic");
+        assert_eq!(OpcodeTable::for_version(101101).name, "classic");
+        assert_eq!(OpcodeTable::for_version(u32::MAX).name, "classic");
+    }
+}
@@ -3,6 +3,8 @@
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 
+use crate::capture::{Direction, Recorder};
+use crate::opcode::{ClientOpcode, OpcodeTable, ServerOpcode, CLASSIC};
 use std::convert::TryInto;
 use std::vec::Vec;
 
@@ -11,18 +13,90 @@ use std::vec::Vec;
 pub enum PacketError {
     /// An io error occurred
     IoError(std::io::Error),
+    /// The contents of a packet could not be decoded
+    DecodeError(PacketDecodeError),
+    /// A packet was received that is not valid at this point of the connection
+    UnexpectedPacket(Box<ServerPacket>),
+    /// The length of a frame is too short to hold a packet
+    FrameTooShort(usize),
+    /// The length of a frame is larger than allowed
+    FrameTooLarge {
+        /// The length of the frame
+        length: usize,
+        /// The maximum allowed length
+        max: usize,
+    },
+    /// The packet only exists inside the server and has no encoding
+    NoEncoding,
+    /// The opcode table in use has no opcode for a kind of packet sent by the client
+    NoClientOpcode {
+        /// The name of the opcode table
+        table: &'static str,
+        /// The kind of packet
+        kind: ClientOpcode,
+    },
+    /// The opcode table in use has no opcode for a kind of packet sent by the server
+    NoServerOpcode {
+        /// The name of the opcode table
+        table: &'static str,
+        /// The kind of packet
+        kind: ServerOpcode,
+    },
 }
 
+/// The default maximum size of a frame received from a client, including the two length bytes
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 8192;
+
+/// The smallest payload of a frame, packets are always padded to at least this size
+const MIN_FRAME_PAYLOAD: usize = 4;
+
 impl From<std::io::Error> for PacketError {
     fn from(a: std::io::Error) -> PacketError {
         PacketError::IoError(a)
     }
 }
 
+impl From<PacketDecodeError> for PacketError {
+    fn from(a: PacketDecodeError) -> PacketError {
+        PacketError::DecodeError(a)
+    }
+}
+
+/// The errors that can occur when decoding the contents of a packet
+#[derive(Debug, PartialEq)]
+pub enum PacketDecodeError {
+    /// The packet ended before a value could be read
+    ShortRead {
+        /// The number of bytes required to read the value
+        needed: usize,
+        /// The number of bytes left in the packet
+        remaining: usize,
+    },
+    /// A string was not null terminated before the end of the packet
+    UnterminatedString,
+    /// The packet has a sub-type that is not known for its opcode
+    UnknownSubtype {
+        /// The opcode of the packet
+        opcode: u8,
+        /// The unrecognized sub-type
+        subtype: u8,
+    },
+    /// The opcode of the packet is not known
+    UnknownOpcode(u8),
+    /// A field of the packet contains a value that is not valid for it
+    InvalidValue {
+        /// The opcode of the packet
+        opcode: u8,
+        /// The invalid value
+        value: u32,
+    },
+}
+
 /// The 'ClientPacket' type. Represents packets sent by the client
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ClientPacket {
-    /// The client specifies the version of itself
+    /// The client specifies the version of itself.
+    /// The second value appears to be the windows code page (1252 for english), the last value is the client version.
     Version(u16, u32, u8, u32),
     /// The client attempts to log in with an account
     Login(String, String, u32, u32, u32, u32, u32, u32, u32),
@@ -154,7 +228,7 @@ pub enum ClientPacket {
 }
 
 /// An item of inventory for a player
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct InventoryElement {
     /// Item id
     pub id: u32,
@@ -172,7 +246,7 @@ pub struct InventoryElement {
     pub identified: u8,
     /// description, $numeric references use a string from the stringtable of the game client, as long as the $ is not the first character
     pub description: String,
-    /// extended description.
+    /// extended description, see `crate::item::ItemDescriptor` for building and parsing it.
     /// # opcodes
     /// * 1 - Hit value small+?/large+?, small: u8, large: u8, material: u8, weight: u32, if followed by 2, the next u8 is the + value for large and small
     /// * 3 - Damage d, d: u8
@@ -256,10 +330,40 @@ impl InventoryElement {
             p.add_vec(&self.ed);
         }
     }
+
+    /// Reads an inventory element from a packet
+    fn pull(p: &mut Packet, opcode: u8) -> Result<Self, PacketDecodeError> {
+        let id = p.pull_u32()?;
+        let i_type = p.pull_i8()?;
+        let n_use = p.pull_u8()?;
+        let icon = p.pull_i16()?;
+        let blessing = p.pull_u8()?;
+        let blessing =
+            ItemBlessing::try_from(blessing).map_err(|_| PacketDecodeError::InvalidValue {
+                opcode,
+                value: blessing as u32,
+            })?;
+        let count = p.pull_u32()?;
+        let identified = p.pull_u8()?;
+        let description = p.pull_string()?;
+        let ed_len = p.pull_u8()?;
+        let ed = p.pull_vec(ed_len as usize)?;
+        Ok(Self {
+            id,
+            i_type,
+            n_use,
+            icon,
+            blessing,
+            count,
+            identified,
+            description,
+            ed,
+        })
+    }
 }
 
 /// USed to create a inventory update packet
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct InventoryUpdate {
     /// Item id
     pub id: u32,
@@ -271,10 +375,160 @@ pub struct InventoryUpdate {
     pub ed: Vec<u8>,
 }
 
-//TODO create enums for the option values
+/// The result of a login attempt, or of changing a password
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LoginResult {
+    /// The login succeeded
+    Ok,
+    /// The account already exists
+    AccountAlreadyExists,
+    /// The account does not exist or the password is wrong
+    WrongCredentials,
+    /// The account is already logged in
+    AccountInUse,
+    /// The password was changed
+    PasswordChanged,
+    /// The account is banned
+    AccountBanned,
+    /// A code that does not have a name yet
+    Other(u8),
+}
+
+impl LoginResult {
+    /// The value sent in a packet
+    pub fn value(&self) -> u8 {
+        match self {
+            Self::Ok => 0,
+            Self::AccountAlreadyExists => 7,
+            Self::WrongCredentials => 8,
+            Self::AccountInUse => 0x16,
+            Self::PasswordChanged => 0x30,
+            Self::AccountBanned => 0x1a,
+            Self::Other(v) => *v,
+        }
+    }
+}
+
+impl From<u8> for LoginResult {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Ok,
+            7 => Self::AccountAlreadyExists,
+            8 => Self::WrongCredentials,
+            0x16 => Self::AccountInUse,
+            0x30 => Self::PasswordChanged,
+            0x1a => Self::AccountBanned,
+            v => Self::Other(v),
+        }
+    }
+}
+
+/// The id of a message predefined in the client
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MessageId {
+    /// %0 is not playing
+    NotPlaying,
+    /// %0 cannot be used
+    CannotUse,
+    /// That cannot be done here
+    CannotDoHere,
+    /// A message that does not have a name yet
+    Other(u16),
+}
+
+impl MessageId {
+    /// The value sent in a packet
+    pub fn value(&self) -> u16 {
+        match self {
+            Self::NotPlaying => 73,
+            Self::CannotUse => 74,
+            Self::CannotDoHere => 563,
+            Self::Other(v) => *v,
+        }
+    }
+}
+
+impl From<u16> for MessageId {
+    fn from(value: u16) -> Self {
+        match value {
+            73 => Self::NotPlaying,
+            74 => Self::CannotUse,
+            563 => Self::CannotDoHere,
+            v => Self::Other(v),
+        }
+    }
+}
+
+/// The status of creating a new character
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CharacterCreationStatus {
+    /// The character was created
+    Ok,
+    /// The character could not be created
+    Failed,
+    /// A status that does not have a name yet
+    Other(u8),
+}
+
+impl CharacterCreationStatus {
+    /// The value sent in a packet
+    pub fn value(&self) -> u8 {
+        match self {
+            Self::Ok => 0,
+            Self::Failed => 1,
+            Self::Other(v) => *v,
+        }
+    }
+}
+
+impl From<u8> for CharacterCreationStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Ok,
+            1 => Self::Failed,
+            v => Self::Other(v),
+        }
+    }
+}
+
+/// The weather of the world
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Weather {
+    /// Clear skies
+    Clear,
+    /// Snow, with an intensity of 1 to 3
+    Snow(u8),
+    /// Rain, with an intensity of 1 to 3
+    Rain(u8),
+    /// A weather value that does not have a name yet
+    Other(u8),
+}
+
+impl Weather {
+    /// The value sent in a packet
+    pub fn value(&self) -> u8 {
+        match self {
+            Self::Clear => 0,
+            Self::Snow(i) => *i,
+            Self::Rain(i) => 16 + *i,
+            Self::Other(v) => *v,
+        }
+    }
+}
+
+impl From<u8> for Weather {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Clear,
+            1..=3 => Self::Snow(value),
+            17..=19 => Self::Rain(value - 16),
+            v => Self::Other(v),
+        }
+    }
+}
 
 /// Represents packets sent to the client, from the server
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ServerPacket {
     /// The version details of the server
     ServerVersion {
@@ -298,12 +552,12 @@ pub enum ServerPacket {
     /// The result of a login attempt
     LoginResult {
         /// The actual result
-        code: u8,
+        code: LoginResult,
     },
     /// The news to present to the user
     News(String),
     /// The status of creating a new character
-    CharacterCreationStatus(u8),
+    CharacterCreationStatus(CharacterCreationStatus),
     /// New character name already exists
     CharacterNameAlreadyExists,
     /// The details of a new chracter just created
@@ -351,7 +605,7 @@ pub enum ServerPacket {
         name: String,
         /// name of pledge
         pledge: String,
-        /// The character type
+        /// The character type, with 32 added when the character is marked for deletion
         ctype: u8,
         /// gender
         gender: u8,
@@ -491,7 +745,7 @@ pub enum ServerPacket {
         mr: u8,
     },
     /// weather update
-    Weather(u8),
+    Weather(Weather),
     /// A system message for the player
     SystemMessage(String),
     /// An npc chat message
@@ -559,7 +813,7 @@ pub enum ServerPacket {
         /// The new item description
         description: String,
     },
-    /// change direction packet
+    /// change direction packet, it can not be sent until the opcode is known
     ChangeDirection {
         /// The id of the userobject
         id: u32,
@@ -595,7 +849,7 @@ pub enum ServerPacket {
     /// A message presented to the user
     Message {
         /// message type
-        ty: u16,
+        ty: MessageId,
         /// message strings
         msgs: Vec<String>,
     },
@@ -617,7 +871,7 @@ pub enum ServerPacket {
 }
 
 /// The effect for an attack?
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct AttackEffect {
     /// The actual effect
     id: u32,
@@ -641,7 +895,7 @@ pub struct AttackEffect {
 
 /// Potential bless status for an item
 #[repr(u8)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum ItemBlessing {
     /// The item is blessed
     Blessed = 0,
@@ -653,13 +907,230 @@ pub enum ItemBlessing {
     Unidentified = 3,
 }
 
+impl TryFrom<u8> for ItemBlessing {
+    type Error = ();
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Blessed),
+            1 => Ok(Self::Normal),
+            2 => Ok(Self::Cursed),
+            3 => Ok(Self::Unidentified),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ClientPacket {
+    /// Remove the passwords from login and password change packets, so the packet can be written to disk
+    pub fn redacted(self) -> ClientPacket {
+        match self {
+            ClientPacket::Login(user, _, v1, v2, v3, v4, v5, v6, v7) => {
+                ClientPacket::Login(user, String::new(), v1, v2, v3, v4, v5, v6, v7)
+            }
+            ClientPacket::ChangePassword { account, .. } => ClientPacket::ChangePassword {
+                account,
+                oldpass: String::new(),
+                newpass: String::new(),
+            },
+            p => p,
+        }
+    }
+
+    /// Build a `Packet` from the ClientPacket
+    /// The npc chat variants are only generated inside the server, building them is an error.
+    pub fn build(self) -> Result<Packet, PacketError> {
+        self.build_with(&CLASSIC)
+    }
+
+    /// Build a `Packet` from the ClientPacket, using the opcodes of the given table
+    /// The npc chat variants are only generated inside the server, building them is an error.
+    pub fn build_with(self, t: &OpcodeTable) -> Result<Packet, PacketError> {
+        let mut p = Packet::new();
+        match self {
+            ClientPacket::Version(val1, val2, val3, val4) => {
+                p.add_u8(t.client_opcode(ClientOpcode::Version)?)
+                    .add_u16(val1)
+                    .add_u32(val2)
+                    .add_u8(val3)
+                    .add_u32(val4);
+            }
+            ClientPacket::Login(user, pass, v1, v2, v3, v4, v5, v6, v7) => {
+                p.add_u8(t.client_opcode(ClientOpcode::Login)?)
+                    .add_string(&user)
+                    .add_string(&pass)
+                    .add_u32(v1)
+                    .add_u32(v2)
+                    .add_u32(v3)
+                    .add_u32(v4)
+                    .add_u32(v5)
+                    .add_u32(v6)
+                    .add_u32(v7);
+            }
+            ClientPacket::CharacterSelect { name } => {
+                p.add_u8(t.client_opcode(ClientOpcode::CharacterSelect)?)
+                    .add_string(&name);
+            }
+            ClientPacket::NewsDone => {
+                p.add_u8(t.client_opcode(ClientOpcode::NewsDone)?);
+            }
+            ClientPacket::KeepAlive => {
+                p.add_u8(t.client_opcode(ClientOpcode::KeepAlive)?);
+            }
+            ClientPacket::GameInitDone => {
+                p.add_u8(t.client_opcode(ClientOpcode::GameInitDone)?);
+            }
+            ClientPacket::WindowActivate(v) => {
+                p.add_u8(t.client_opcode(ClientOpcode::WindowActivate)?)
+                    .add_u8(0)
+                    .add_u8(v);
+            }
+            ClientPacket::Save => {
+                p.add_u8(t.client_opcode(ClientOpcode::Save)?);
+            }
+            ClientPacket::MoveFrom { x, y, heading } => {
+                p.add_u8(t.client_opcode(ClientOpcode::MoveFrom)?)
+                    .add_u16(x)
+                    .add_u16(y)
+                    .add_u8(heading);
+            }
+            ClientPacket::ChangeDirection(d) => {
+                p.add_u8(t.client_opcode(ClientOpcode::ChangeDirection)?)
+                    .add_u8(d);
+            }
+            ClientPacket::Chat(m) => {
+                p.add_u8(t.client_opcode(ClientOpcode::Chat)?)
+                    .add_u8(0)
+                    .add_string(&m);
+            }
+            ClientPacket::YellChat(m) => {
+                p.add_u8(t.client_opcode(ClientOpcode::Chat)?)
+                    .add_u8(0)
+                    .add_string(&format!("!{}", m));
+            }
+            ClientPacket::CommandChat(m) => {
+                p.add_u8(t.client_opcode(ClientOpcode::Chat)?)
+                    .add_u8(0)
+                    .add_string(&format!("-{}", m));
+            }
+            ClientPacket::SpecialCommandChat(m) => {
+                p.add_u8(t.client_opcode(ClientOpcode::Chat)?)
+                    .add_u8(0)
+                    .add_string(&format!(".{}", m));
+            }
+            ClientPacket::PledgeChat(m) => {
+                p.add_u8(t.client_opcode(ClientOpcode::Chat)?)
+                    .add_u8(4)
+                    .add_string(&m);
+            }
+            ClientPacket::PartyChat(m) => {
+                p.add_u8(t.client_opcode(ClientOpcode::Chat)?)
+                    .add_u8(11)
+                    .add_string(&m);
+            }
+            ClientPacket::NpcChat { .. }
+            | ClientPacket::NpcShout { .. }
+            | ClientPacket::NpcGlobalChat { .. } => {
+                return Err(PacketError::NoEncoding);
+            }
+            ClientPacket::WhisperChat(name, m) => {
+                p.add_u8(t.client_opcode(ClientOpcode::WhisperChat)?)
+                    .add_string(&name)
+                    .add_string(&m);
+            }
+            ClientPacket::GlobalChat(m) => {
+                p.add_u8(t.client_opcode(ClientOpcode::GlobalChat)?)
+                    .add_u8(0)
+                    .add_string(&m);
+            }
+            ClientPacket::ChangePassword {
+                account,
+                oldpass,
+                newpass,
+            } => {
+                p.add_u8(t.client_opcode(ClientOpcode::ChangePassword)?)
+                    .add_string(&account)
+                    .add_string(&oldpass)
+                    .add_string(&newpass);
+            }
+            ClientPacket::NewCharacter {
+                name,
+                class,
+                gender,
+                strength,
+                dexterity,
+                constitution,
+                wisdom,
+                charisma,
+                intelligence,
+            } => {
+                p.add_u8(t.client_opcode(ClientOpcode::NewCharacter)?)
+                    .add_string(&name)
+                    .add_u8(class)
+                    .add_u8(gender)
+                    .add_u8(strength)
+                    .add_u8(dexterity)
+                    .add_u8(constitution)
+                    .add_u8(wisdom)
+                    .add_u8(charisma)
+                    .add_u8(intelligence);
+            }
+            ClientPacket::DeleteCharacter(name) => {
+                p.add_u8(t.client_opcode(ClientOpcode::DeleteCharacter)?)
+                    .add_string(&name);
+            }
+            ClientPacket::CreateBookmark(name) => {
+                p.add_u8(t.client_opcode(ClientOpcode::CreateBookmark)?)
+                    .add_string(&name);
+            }
+            ClientPacket::WhoCommand(name) => {
+                p.add_u8(t.client_opcode(ClientOpcode::WhoCommand)?)
+                    .add_string(&name);
+            }
+            ClientPacket::AddFriend(name) => {
+                p.add_u8(t.client_opcode(ClientOpcode::AddFriend)?)
+                    .add_string(&name);
+            }
+            ClientPacket::RemoveFriend(name) => {
+                p.add_u8(t.client_opcode(ClientOpcode::RemoveFriend)?)
+                    .add_string(&name);
+            }
+            ClientPacket::Ping(v) => {
+                p.add_u8(t.client_opcode(ClientOpcode::Ping)?).add_u8(v);
+            }
+            ClientPacket::Restart => {
+                p.add_u8(t.client_opcode(ClientOpcode::Restart)?);
+            }
+            ClientPacket::UseItem { id, remainder } => {
+                p.add_u8(t.client_opcode(ClientOpcode::UseItem)?)
+                    .add_u32(id)
+                    .add_vec(&remainder);
+            }
+            ClientPacket::AttackObject { id, x, y } => {
+                p.add_u8(t.client_opcode(ClientOpcode::AttackObject)?)
+                    .add_u32(id)
+                    .add_u16(x)
+                    .add_u16(y);
+            }
+            ClientPacket::Unknown(d) => {
+                p.add_vec(&d);
+            }
+        }
+        Ok(p)
+    }
+}
+
 impl ServerPacket {
     /// Build a `Packet` from the ServerPacket
-    pub fn build(self) -> Packet {
+    pub fn build(self) -> Result<Packet, PacketError> {
+        self.build_with(&CLASSIC)
+    }
+
+    /// Build a `Packet` from the ServerPacket, using the opcodes of the given table
+    pub fn build_with(self, t: &OpcodeTable) -> Result<Packet, PacketError> {
         let mut p = Packet::new();
         match self {
             ServerPacket::NpcChat { id, message } => {
-                p.add_u8(42)
+                p.add_u8(t.server_opcode(ServerOpcode::NpcChat)?)
                     .add_u8(0)
                     .add_u32(id)
                     .add_string(&message)
@@ -667,7 +1138,7 @@ impl ServerPacket {
                     .add_u16(0);
             }
             ServerPacket::NpcShout { id, message } => {
-                p.add_u8(42)
+                p.add_u8(t.server_opcode(ServerOpcode::NpcChat)?)
                     .add_u8(2)
                     .add_u32(id)
                     .add_string(&message)
@@ -675,7 +1146,7 @@ impl ServerPacket {
                     .add_u16(0);
             }
             ServerPacket::NpcGlobalChat { id, message } => {
-                p.add_u8(42)
+                p.add_u8(t.server_opcode(ServerOpcode::NpcChat)?)
                     .add_u8(3)
                     .add_u32(id)
                     .add_string(&message)
@@ -683,7 +1154,8 @@ impl ServerPacket {
                     .add_u16(0);
             }
             ServerPacket::EncryptionKey(k) => {
-                p.add_u8(65).add_u32(k);
+                p.add_u8(t.server_opcode(ServerOpcode::EncryptionKey)?)
+                    .add_u32(k);
             }
             ServerPacket::Attack {
                 attack_type: u1,
@@ -693,7 +1165,7 @@ impl ServerPacket {
                 direction,
                 effect,
             } => {
-                p.add_u8(22)
+                p.add_u8(t.server_opcode(ServerOpcode::Attack)?)
                     .add_u8(u1)
                     .add_u32(id)
                     .add_u32(id2)
@@ -717,7 +1189,9 @@ impl ServerPacket {
                 }
             }
             ServerPacket::Message { ty, msgs } => {
-                p.add_u8(87).add_u16(ty).add_u8(msgs.len() as u8);
+                p.add_u8(t.server_opcode(ServerOpcode::Message)?)
+                    .add_u16(ty.value())
+                    .add_u8(msgs.len() as u8);
                 for m in msgs {
                     p.add_string(&m);
                 }
@@ -728,20 +1202,24 @@ impl ServerPacket {
                 y,
                 direction,
             } => {
-                p.add_u8(61)
+                p.add_u8(t.server_opcode(ServerOpcode::MoveObject)?)
                     .add_u32(id)
                     .add_u16(x)
                     .add_u16(y)
                     .add_u8(direction);
             }
             ServerPacket::BackToCharacterSelect => {
-                p.add_u8(107).add_u8(42);
+                p.add_u8(t.server_opcode(ServerOpcode::BackToCharacterSelect)?)
+                    .add_u8(42);
             }
             ServerPacket::RemoveObject(id) => {
-                p.add_u8(9).add_u32(id);
+                p.add_u8(t.server_opcode(ServerOpcode::RemoveObject)?)
+                    .add_u32(id);
             }
             ServerPacket::SetCriminalCount { id, count } => {
-                p.add_u8(121).add_u32(id).add_u8(count);
+                p.add_u8(t.server_opcode(ServerOpcode::SetCriminalCount)?)
+                    .add_u32(id)
+                    .add_u8(count);
             }
             ServerPacket::CloneObject {
                 id,
@@ -751,7 +1229,7 @@ impl ServerPacket {
                 poly_action: poly_arg,
                 title,
             } => {
-                p.add_u8(126)
+                p.add_u8(t.server_opcode(ServerOpcode::CloneObject)?)
                     .add_u32(id)
                     .add_u32(speed)
                     .add_u16(poly_id)
@@ -767,7 +1245,7 @@ impl ServerPacket {
                 english,
                 country,
             } => {
-                p.add_u8(10)
+                p.add_u8(t.server_opcode(ServerOpcode::ServerVersion)?)
                     .add_u8(0)
                     .add_u8(id)
                     .add_u32(version)
@@ -781,20 +1259,30 @@ impl ServerPacket {
             }
             //TODO sometimes the client crashes when sending this packet, after they click ok
             ServerPacket::Disconnect => {
-                p.add_u8(18).add_u16(500).add_u32(0);
+                p.add_u8(t.server_opcode(ServerOpcode::Disconnect)?)
+                    .add_u16(500)
+                    .add_u32(0);
             }
             ServerPacket::LoginResult { code } => {
-                p.add_u8(21).add_u8(code).add_u32(0);
+                p.add_u8(t.server_opcode(ServerOpcode::LoginResult)?)
+                    .add_u8(code.value())
+                    .add_u32(0);
             }
             ServerPacket::CharacterNameAlreadyExists => {
-                p.add_u8(21).add_u8(6).add_u32(0);
+                p.add_u8(t.server_opcode(ServerOpcode::LoginResult)?)
+                    .add_u8(6)
+                    .add_u32(0);
             }
             ServerPacket::News(news) => {
-                p.add_u8(90).add_string(&news);
+                p.add_u8(t.server_opcode(ServerOpcode::News)?)
+                    .add_string(&news);
             }
             //TODO verify this
             ServerPacket::CharacterCreationStatus(v) => {
-                p.add_u8(106).add_u8(v).add_u32(0).add_u32(0);
+                p.add_u8(t.server_opcode(ServerOpcode::CharacterCreationStatus)?)
+                    .add_u8(v.value())
+                    .add_u32(0)
+                    .add_u32(0);
             }
             ServerPacket::NewCharacterDetails {
                 name,
@@ -813,7 +1301,7 @@ impl ServerPacket {
                 charisma,
                 intelligence,
             } => {
-                p.add_u8(98)
+                p.add_u8(t.server_opcode(ServerOpcode::NewCharacterDetails)?)
                     .add_string(&name)
                     .add_string(&pledge)
                     .add_u8(class)
@@ -834,13 +1322,15 @@ impl ServerPacket {
                     .add_u32(3);
             }
             ServerPacket::DeleteCharacterOk => {
-                p.add_u8(33).add_u8(0x05);
+                p.add_u8(t.server_opcode(ServerOpcode::DeleteCharacter)?)
+                    .add_u8(0x05);
             }
             ServerPacket::DeleteCharacterWait => {
-                p.add_u8(33).add_u8(0x51);
+                p.add_u8(t.server_opcode(ServerOpcode::DeleteCharacter)?)
+                    .add_u8(0x51);
             }
             ServerPacket::NumberCharacters(num, max) => {
-                p.add_u8(113)
+                p.add_u8(t.server_opcode(ServerOpcode::NumberCharacters)?)
                     .add_u8(num) //number of characters
                     .add_u8(max); //number of slots
             }
@@ -861,7 +1351,7 @@ impl ServerPacket {
                 charisma,
                 intelligence,
             } => {
-                p.add_u8(99)
+                p.add_u8(t.server_opcode(ServerOpcode::LoginCharacterDetails)?)
                     .add_string(&name)
                     .add_string(&pledge)
                     .add_u8(ctype)
@@ -904,7 +1394,7 @@ impl ServerPacket {
                 wind_resist,
                 earth_resist,
             } => {
-                p.add_u8(69)
+                p.add_u8(t.server_opcode(ServerOpcode::CharacterDetails)?)
                     .add_u32(id)
                     .add_u8(level)
                     .add_u32(xp)
@@ -929,10 +1419,14 @@ impl ServerPacket {
                     .add_u8(earth_resist);
             }
             ServerPacket::StartGame(i) => {
-                p.add_u8(63).add_u8(3).add_u32(i);
+                p.add_u8(t.server_opcode(ServerOpcode::StartGame)?)
+                    .add_u8(3)
+                    .add_u32(i);
             }
             ServerPacket::MapId(map, underwater) => {
-                p.add_u8(76).add_u16(map).add_u8(underwater);
+                p.add_u8(t.server_opcode(ServerOpcode::MapId)?)
+                    .add_u16(map)
+                    .add_u8(underwater);
             }
             ServerPacket::PutObject {
                 x,
@@ -956,7 +1450,7 @@ impl ServerPacket {
                 v2,
                 level: v3,
             } => {
-                p.add_u8(64)
+                p.add_u8(t.server_opcode(ServerOpcode::PutObject)?)
                     .add_u16(x)
                     .add_u16(y)
                     .add_u32(id)
@@ -979,19 +1473,27 @@ impl ServerPacket {
                     .add_u8(v3);
             }
             ServerPacket::CharSpMrBonus { sp, mr } => {
-                p.add_u8(80).add_u8(sp).add_u8(mr);
+                p.add_u8(t.server_opcode(ServerOpcode::CharSpMrBonus)?)
+                    .add_u8(sp)
+                    .add_u8(mr);
             }
             ServerPacket::Weather(w) => {
-                p.add_u8(83).add_u8(w);
+                p.add_u8(t.server_opcode(ServerOpcode::Weather)?)
+                    .add_u8(w.value());
             }
             ServerPacket::SystemMessage(m) => {
-                p.add_u8(105).add_u8(9).add_string(&m);
+                p.add_u8(t.server_opcode(ServerOpcode::ServerChat)?)
+                    .add_u8(9)
+                    .add_string(&m);
             }
             ServerPacket::RegularChat { id, msg } => {
-                p.add_u8(8).add_u8(0).add_u32(id).add_string(&msg);
+                p.add_u8(t.server_opcode(ServerOpcode::ObjectChat)?)
+                    .add_u8(0)
+                    .add_u32(id)
+                    .add_string(&msg);
             }
             ServerPacket::YellChat { id, msg, x, y } => {
-                p.add_u8(8)
+                p.add_u8(t.server_opcode(ServerOpcode::ObjectChat)?)
                     .add_u8(2)
                     .add_u32(id)
                     .add_string(&msg)
@@ -999,33 +1501,44 @@ impl ServerPacket {
                     .add_u16(y);
             }
             ServerPacket::GlobalChat(msg) => {
-                p.add_u8(105).add_u8(3).add_string(&msg);
+                p.add_u8(t.server_opcode(ServerOpcode::ServerChat)?)
+                    .add_u8(3)
+                    .add_string(&msg);
             }
             ServerPacket::PledgeChat(msg) => {
-                p.add_u8(105).add_u8(4).add_string(&msg);
+                p.add_u8(t.server_opcode(ServerOpcode::ServerChat)?)
+                    .add_u8(4)
+                    .add_string(&msg);
             }
             ServerPacket::PartyChat(msg) => {
-                p.add_u8(105).add_u8(11).add_string(&msg);
+                p.add_u8(t.server_opcode(ServerOpcode::ServerChat)?)
+                    .add_u8(11)
+                    .add_string(&msg);
             }
             ServerPacket::WhisperChat { name, msg } => {
-                p.add_u8(91).add_string(&name).add_string(&msg);
+                p.add_u8(t.server_opcode(ServerOpcode::WhisperChat)?)
+                    .add_string(&name)
+                    .add_string(&msg);
             }
             ServerPacket::InventoryVec(v) => {
-                p.add_u8(47);
+                p.add_u8(t.server_opcode(ServerOpcode::InventoryVec)?);
                 p.add_u8(v.len() as u8);
                 for e in v {
                     e.add(&mut p);
                 }
             }
             ServerPacket::Inventory(e) => {
-                p.add_u8(6);
+                p.add_u8(t.server_opcode(ServerOpcode::Inventory)?);
                 e.add(&mut p);
             }
+            //TODO verify this
             ServerPacket::ChangeDirection { id, direction } => {
-                p.add_u32(id).add_u8(direction);
+                p.add_u8(t.server_opcode(ServerOpcode::ChangeDirection)?)
+                    .add_u32(id)
+                    .add_u8(direction);
             }
             ServerPacket::InventoryMod(m) => {
-                p.add_u8(43)
+                p.add_u8(t.server_opcode(ServerOpcode::InventoryMod)?)
                     .add_u32(m.id)
                     .add_string(&m.description)
                     .add_u32(m.count)
@@ -1035,10 +1548,12 @@ impl ServerPacket {
                 }
             }
             ServerPacket::InventoryDescriptionUpdate { id, description } => {
-                p.add_u8(29).add_u32(id).add_string(&description);
+                p.add_u8(t.server_opcode(ServerOpcode::InventoryDescriptionUpdate)?)
+                    .add_u32(id)
+                    .add_string(&description);
             }
         }
-        p
+        Ok(p)
     }
 }
 
@@ -1110,102 +1625,404 @@ impl Packet {
     }
 
     /// Convert the packet to a `ClientPacket`
-    pub fn convert(mut self) -> ClientPacket {
-        let opcode: u8 = self.pull_u8();
-        match opcode {
-            1 => ClientPacket::UseItem {
-                id: self.pull_u32(),
+    pub fn convert(self) -> Result<ClientPacket, PacketDecodeError> {
+        self.convert_with(&CLASSIC)
+    }
+
+    /// Convert the packet to a `ClientPacket`, using the opcodes of the given table
+    pub fn convert_with(mut self, t: &OpcodeTable) -> Result<ClientPacket, PacketDecodeError> {
+        let opcode: u8 = self.pull_u8()?;
+        Ok(match t.client_kind(opcode) {
+            Some(ClientOpcode::UseItem) => ClientPacket::UseItem {
+                id: self.pull_u32()?,
                 remainder: self.pull_remainder(),
             },
-            12 => ClientPacket::Login(
-                self.pull_string(),
-                self.pull_string(),
-                self.pull_u32(),
-                self.pull_u32(),
-                self.pull_u32(),
-                self.pull_u32(),
-                self.pull_u32(),
-                self.pull_u32(),
-                self.pull_u32(),
+            Some(ClientOpcode::Login) => ClientPacket::Login(
+                self.pull_string()?,
+                self.pull_string()?,
+                self.pull_u32()?,
+                self.pull_u32()?,
+                self.pull_u32()?,
+                self.pull_u32()?,
+                self.pull_u32()?,
+                self.pull_u32()?,
+                self.pull_u32()?,
             ),
-            13 => ClientPacket::WhisperChat(self.pull_string(), self.pull_string()),
-            20 => ClientPacket::CreateBookmark(self.pull_string()),
-            30 => ClientPacket::RemoveFriend(self.pull_string()),
-            34 => ClientPacket::DeleteCharacter(self.pull_string()),
-            40 => {
-                self.pull_u8();
-                ClientPacket::GlobalChat(self.pull_string())
-            }
-            43 => ClientPacket::NewsDone,
-            45 => ClientPacket::AttackObject {
-                id: self.pull_u32(),
-                x: self.pull_u16(),
-                y: self.pull_u16(),
+            Some(ClientOpcode::WhisperChat) => {
+                ClientPacket::WhisperChat(self.pull_string()?, self.pull_string()?)
+            }
+            Some(ClientOpcode::CreateBookmark) => ClientPacket::CreateBookmark(self.pull_string()?),
+            Some(ClientOpcode::RemoveFriend) => ClientPacket::RemoveFriend(self.pull_string()?),
+            Some(ClientOpcode::DeleteCharacter) => {
+                ClientPacket::DeleteCharacter(self.pull_string()?)
+            }
+            Some(ClientOpcode::GlobalChat) => {
+                self.pull_u8()?;
+                ClientPacket::GlobalChat(self.pull_string()?)
+            }
+            Some(ClientOpcode::NewsDone) => ClientPacket::NewsDone,
+            Some(ClientOpcode::AttackObject) => ClientPacket::AttackObject {
+                id: self.pull_u32()?,
+                x: self.pull_u16()?,
+                y: self.pull_u16()?,
             },
-            47 => ClientPacket::Restart,
-            57 => ClientPacket::KeepAlive,
-            71 => {
-                let val1: u16 = self.pull_u16();
-                let val2: u32 = self.pull_u32();
-                let val3: u8 = self.pull_u8();
-                let val4: u32 = self.pull_u32();
+            Some(ClientOpcode::Restart) => ClientPacket::Restart,
+            Some(ClientOpcode::KeepAlive) => ClientPacket::KeepAlive,
+            Some(ClientOpcode::Version) => {
+                let val1: u16 = self.pull_u16()?;
+                let val2: u32 = self.pull_u32()?;
+                let val3: u8 = self.pull_u8()?;
+                let val4: u32 = self.pull_u32()?;
                 log::info!("client: found a client version packet");
                 ClientPacket::Version(val1, val2, val3, val4)
             }
-            72 => ClientPacket::NewCharacter {
-                name: self.pull_string(),
-                class: self.pull_u8(),
-                gender: self.pull_u8(),
-                strength: self.pull_u8(),
-                dexterity: self.pull_u8(),
-                constitution: self.pull_u8(),
-                wisdom: self.pull_u8(),
-                charisma: self.pull_u8(),
-                intelligence: self.pull_u8(),
+            Some(ClientOpcode::NewCharacter) => ClientPacket::NewCharacter {
+                name: self.pull_string()?,
+                class: self.pull_u8()?,
+                gender: self.pull_u8()?,
+                strength: self.pull_u8()?,
+                dexterity: self.pull_u8()?,
+                constitution: self.pull_u8()?,
+                wisdom: self.pull_u8()?,
+                charisma: self.pull_u8()?,
+                intelligence: self.pull_u8()?,
             },
-            74 => ClientPacket::ChangeDirection(self.pull_u8()),
-            79 => ClientPacket::AddFriend(self.pull_string()),
-            83 => ClientPacket::CharacterSelect {
-                name: self.pull_string(),
+            Some(ClientOpcode::ChangeDirection) => ClientPacket::ChangeDirection(self.pull_u8()?),
+            Some(ClientOpcode::AddFriend) => ClientPacket::AddFriend(self.pull_string()?),
+            Some(ClientOpcode::CharacterSelect) => ClientPacket::CharacterSelect {
+                name: self.pull_string()?,
             },
-            88 => ClientPacket::MoveFrom {
-                x: self.pull_u16(),
-                y: self.pull_u16(),
-                heading: self.pull_u8(),
+            Some(ClientOpcode::MoveFrom) => ClientPacket::MoveFrom {
+                x: self.pull_u16()?,
+                y: self.pull_u16()?,
+                heading: self.pull_u8()?,
             },
-            92 => ClientPacket::GameInitDone,
-            97 => {
-                self.pull_u8();
-                let v2 = self.pull_u8();
+            Some(ClientOpcode::GameInitDone) => ClientPacket::GameInitDone,
+            Some(ClientOpcode::WindowActivate) => {
+                self.pull_u8()?;
+                let v2 = self.pull_u8()?;
                 ClientPacket::WindowActivate(v2)
             }
-            100 => ClientPacket::ChangePassword {
-                account: self.pull_string(),
-                oldpass: self.pull_string(),
-                newpass: self.pull_string(),
+            Some(ClientOpcode::ChangePassword) => ClientPacket::ChangePassword {
+                account: self.pull_string()?,
+                oldpass: self.pull_string()?,
+                newpass: self.pull_string()?,
             },
-            104 => {
-                let t = self.pull_u8();
-                let m = self.pull_string();
+            Some(ClientOpcode::Chat) => {
+                let t = self.pull_u8()?;
+                let m = self.pull_string()?;
                 match t {
-                    0 => match m.chars().take(1).last().unwrap() {
-                        '!' => ClientPacket::YellChat(m[1..].to_string()),
-                        '-' => ClientPacket::CommandChat(m[1..].to_string()),
-                        '.' => ClientPacket::SpecialCommandChat(m[1..].to_string()),
+                    0 => match m.chars().next() {
+                        Some('!') => ClientPacket::YellChat(m[1..].to_string()),
+                        Some('-') => ClientPacket::CommandChat(m[1..].to_string()),
+                        Some('.') => ClientPacket::SpecialCommandChat(m[1..].to_string()),
                         _ => ClientPacket::Chat(m),
                     },
                     4 => ClientPacket::PledgeChat(m),        //@
                     11 => ClientPacket::PartyChat(m),        //#
                     13 => ClientPacket::Unknown(self.buf()), //%
                     15 => ClientPacket::Unknown(self.buf()), //~
-                    _ => ClientPacket::Unknown(self.buf()),
+                    _ => return Err(PacketDecodeError::UnknownSubtype { opcode, subtype: t }),
                 }
             }
-            111 => ClientPacket::Save,
-            112 => ClientPacket::Ping(self.pull_u8()),
-            119 => ClientPacket::WhoCommand(self.pull_string()),
-            _ => ClientPacket::Unknown(self.buf()),
-        }
+            Some(ClientOpcode::Save) => ClientPacket::Save,
+            Some(ClientOpcode::Ping) => ClientPacket::Ping(self.pull_u8()?),
+            Some(ClientOpcode::WhoCommand) => ClientPacket::WhoCommand(self.pull_string()?),
+            None => ClientPacket::Unknown(self.buf()),
+        })
+    }
+
+    /// Convert the packet to a `ServerPacket`
+    pub fn convert_server(self) -> Result<ServerPacket, PacketDecodeError> {
+        self.convert_server_with(&CLASSIC)
+    }
+
+    /// Convert the packet to a `ServerPacket`, using the opcodes of the given table
+    pub fn convert_server_with(
+        mut self,
+        t: &OpcodeTable,
+    ) -> Result<ServerPacket, PacketDecodeError> {
+        let opcode: u8 = self.pull_u8()?;
+        Ok(match t.server_kind(opcode) {
+            Some(ServerOpcode::Inventory) => {
+                ServerPacket::Inventory(InventoryElement::pull(&mut self, opcode)?)
+            }
+            Some(ServerOpcode::ObjectChat) => {
+                let t = self.pull_u8()?;
+                match t {
+                    0 => ServerPacket::RegularChat {
+                        id: self.pull_u32()?,
+                        msg: self.pull_string()?,
+                    },
+                    2 => ServerPacket::YellChat {
+                        id: self.pull_u32()?,
+                        msg: self.pull_string()?,
+                        x: self.pull_u16()?,
+                        y: self.pull_u16()?,
+                    },
+                    _ => return Err(PacketDecodeError::UnknownSubtype { opcode, subtype: t }),
+                }
+            }
+            Some(ServerOpcode::RemoveObject) => ServerPacket::RemoveObject(self.pull_u32()?),
+            Some(ServerOpcode::ServerVersion) => {
+                self.pull_u8()?;
+                let id = self.pull_u8()?;
+                let version = self.pull_u32()?;
+                self.pull_u32()?;
+                self.pull_u32()?;
+                self.pull_u32()?;
+                ServerPacket::ServerVersion {
+                    id,
+                    version,
+                    time: self.pull_u32()?,
+                    new_accounts: self.pull_u8()?,
+                    english: self.pull_u8()?,
+                    country: self.pull_u8()?,
+                }
+            }
+            Some(ServerOpcode::Disconnect) => ServerPacket::Disconnect,
+            Some(ServerOpcode::LoginResult) => {
+                let code = self.pull_u8()?;
+                if code == 6 {
+                    ServerPacket::CharacterNameAlreadyExists
+                } else {
+                    ServerPacket::LoginResult { code: code.into() }
+                }
+            }
+            Some(ServerOpcode::Attack) => {
+                let attack_type = self.pull_u8()?;
+                let id = self.pull_u32()?;
+                let id2 = self.pull_u32()?;
+                let impact = self.pull_u16()?;
+                let direction = self.pull_u8()?;
+                let effect_id = self.pull_u32()?;
+                let effect = if effect_id != 0 {
+                    Some(AttackEffect {
+                        id: effect_id,
+                        sprite: self.pull_u16()?,
+                        light: self.pull_u8()?,
+                        x1: self.pull_u16()?,
+                        y1: self.pull_u16()?,
+                        x2: self.pull_u16()?,
+                        y2: self.pull_u16()?,
+                        leave: self.pull_u8()?,
+                        desc: self.pull_string()?,
+                    })
+                } else {
+                    None
+                };
+                ServerPacket::Attack {
+                    attack_type,
+                    id,
+                    id2,
+                    impact,
+                    direction,
+                    effect,
+                }
+            }
+            Some(ServerOpcode::ChangeDirection) => ServerPacket::ChangeDirection {
+                id: self.pull_u32()?,
+                direction: self.pull_u8()?,
+            },
+            Some(ServerOpcode::InventoryDescriptionUpdate) => {
+                ServerPacket::InventoryDescriptionUpdate {
+                    id: self.pull_u32()?,
+                    description: self.pull_string()?,
+                }
+            }
+            Some(ServerOpcode::DeleteCharacter) => {
+                let t = self.pull_u8()?;
+                match t {
+                    0x05 => ServerPacket::DeleteCharacterOk,
+                    0x51 => ServerPacket::DeleteCharacterWait,
+                    _ => return Err(PacketDecodeError::UnknownSubtype { opcode, subtype: t }),
+                }
+            }
+            Some(ServerOpcode::NpcChat) => {
+                let t = self.pull_u8()?;
+                let id = self.pull_u32()?;
+                let message = self.pull_string()?;
+                match t {
+                    0 => ServerPacket::NpcChat { id, message },
+                    2 => ServerPacket::NpcShout { id, message },
+                    3 => ServerPacket::NpcGlobalChat { id, message },
+                    _ => return Err(PacketDecodeError::UnknownSubtype { opcode, subtype: t }),
+                }
+            }
+            Some(ServerOpcode::InventoryMod) => {
+                let id = self.pull_u32()?;
+                let description = self.pull_string()?;
+                let count = self.pull_u32()?;
+                let ed_len = self.pull_u8()?;
+                ServerPacket::InventoryMod(InventoryUpdate {
+                    id,
+                    description,
+                    count,
+                    ed: self.pull_vec(ed_len as usize)?,
+                })
+            }
+            Some(ServerOpcode::InventoryVec) => {
+                let n = self.pull_u8()?;
+                let mut v = Vec::with_capacity(n as usize);
+                for _ in 0..n {
+                    v.push(InventoryElement::pull(&mut self, opcode)?);
+                }
+                ServerPacket::InventoryVec(v)
+            }
+            Some(ServerOpcode::MoveObject) => ServerPacket::MoveObject {
+                id: self.pull_u32()?,
+                x: self.pull_u16()?,
+                y: self.pull_u16()?,
+                direction: self.pull_u8()?,
+            },
+            Some(ServerOpcode::StartGame) => {
+                self.pull_u8()?;
+                ServerPacket::StartGame(self.pull_u32()?)
+            }
+            Some(ServerOpcode::PutObject) => ServerPacket::PutObject {
+                x: self.pull_u16()?,
+                y: self.pull_u16()?,
+                id: self.pull_u32()?,
+                icon: self.pull_u16()?,
+                status: self.pull_u8()?,
+                direction: self.pull_u8()?,
+                light: self.pull_u8()?,
+                speed: self.pull_u8()?,
+                xp: self.pull_u32()?,
+                alignment: self.pull_i16()?,
+                name: self.pull_string()?,
+                title: self.pull_string()?,
+                status2: self.pull_u8()?,
+                pledgeid: self.pull_u32()?,
+                pledgename: self.pull_string()?,
+                owner_name: self.pull_string()?,
+                v1: self.pull_u8()?,
+                hp_bar: self.pull_u8()?,
+                v2: self.pull_u8()?,
+                level: self.pull_u8()?,
+            },
+            Some(ServerOpcode::EncryptionKey) => ServerPacket::EncryptionKey(self.pull_u32()?),
+            Some(ServerOpcode::CharacterDetails) => {
+                let id = self.pull_u32()?;
+                let level = self.pull_u8()?;
+                let xp = self.pull_u32()?;
+                let strength = self.pull_u8()?;
+                let intelligence = self.pull_u8()?;
+                let wisdom = self.pull_u8()?;
+                let dexterity = self.pull_u8()?;
+                let constitution = self.pull_u8()?;
+                let charisma = self.pull_u8()?;
+                ServerPacket::CharacterDetails {
+                    id,
+                    level,
+                    xp,
+                    strength,
+                    dexterity,
+                    constitution,
+                    wisdom,
+                    charisma,
+                    intelligence,
+                    curr_hp: self.pull_u16()?,
+                    max_hp: self.pull_u16()?,
+                    curr_mp: self.pull_u16()?,
+                    max_mp: self.pull_u16()?,
+                    ac: self.pull_i8()?,
+                    time: self.pull_u32()?,
+                    food: self.pull_u8()? as f32 / 29.0,
+                    weight: self.pull_u8()? as f32 / 29.0,
+                    alignment: self.pull_i16()?,
+                    fire_resist: self.pull_u8()?,
+                    water_resist: self.pull_u8()?,
+                    wind_resist: self.pull_u8()?,
+                    earth_resist: self.pull_u8()?,
+                }
+            }
+            Some(ServerOpcode::MapId) => ServerPacket::MapId(self.pull_u16()?, self.pull_u8()?),
+            Some(ServerOpcode::CharSpMrBonus) => ServerPacket::CharSpMrBonus {
+                sp: self.pull_u8()?,
+                mr: self.pull_u8()?,
+            },
+            Some(ServerOpcode::Weather) => ServerPacket::Weather(self.pull_u8()?.into()),
+            Some(ServerOpcode::Message) => {
+                let ty = self.pull_u16()?.into();
+                let n = self.pull_u8()?;
+                let mut msgs = Vec::with_capacity(n as usize);
+                for _ in 0..n {
+                    msgs.push(self.pull_string()?);
+                }
+                ServerPacket::Message { ty, msgs }
+            }
+            Some(ServerOpcode::News) => ServerPacket::News(self.pull_string()?),
+            Some(ServerOpcode::WhisperChat) => ServerPacket::WhisperChat {
+                name: self.pull_string()?,
+                msg: self.pull_string()?,
+            },
+            Some(ServerOpcode::NewCharacterDetails) => ServerPacket::NewCharacterDetails {
+                name: self.pull_string()?,
+                pledge: self.pull_string()?,
+                class: self.pull_u8()?,
+                gender: self.pull_u8()?,
+                alignment: self.pull_i16()?,
+                hp: self.pull_u16()?,
+                mp: self.pull_u16()?,
+                ac: self.pull_i8()?,
+                level: self.pull_u8()?,
+                strength: self.pull_u8()?,
+                dexterity: self.pull_u8()?,
+                constitution: self.pull_u8()?,
+                wisdom: self.pull_u8()?,
+                charisma: self.pull_u8()?,
+                intelligence: self.pull_u8()?,
+            },
+            Some(ServerOpcode::LoginCharacterDetails) => ServerPacket::LoginCharacterDetails {
+                name: self.pull_string()?,
+                pledge: self.pull_string()?,
+                ctype: self.pull_u8()?,
+                gender: self.pull_u8()?,
+                alignment: self.pull_i16()?,
+                hp: self.pull_u16()?,
+                mp: self.pull_u16()?,
+                ac: self.pull_i8()?,
+                level: self.pull_u8()?,
+                strength: self.pull_u8()?,
+                dexterity: self.pull_u8()?,
+                constitution: self.pull_u8()?,
+                wisdom: self.pull_u8()?,
+                charisma: self.pull_u8()?,
+                intelligence: self.pull_u8()?,
+            },
+            Some(ServerOpcode::ServerChat) => {
+                let t = self.pull_u8()?;
+                let m = self.pull_string()?;
+                match t {
+                    3 => ServerPacket::GlobalChat(m),
+                    4 => ServerPacket::PledgeChat(m),
+                    9 => ServerPacket::SystemMessage(m),
+                    11 => ServerPacket::PartyChat(m),
+                    _ => return Err(PacketDecodeError::UnknownSubtype { opcode, subtype: t }),
+                }
+            }
+            Some(ServerOpcode::CharacterCreationStatus) => {
+                ServerPacket::CharacterCreationStatus(self.pull_u8()?.into())
+            }
+            Some(ServerOpcode::BackToCharacterSelect) => ServerPacket::BackToCharacterSelect,
+            Some(ServerOpcode::NumberCharacters) => {
+                ServerPacket::NumberCharacters(self.pull_u8()?, self.pull_u8()?)
+            }
+            Some(ServerOpcode::SetCriminalCount) => ServerPacket::SetCriminalCount {
+                id: self.pull_u32()?,
+                count: self.pull_u8()?,
+            },
+            Some(ServerOpcode::CloneObject) => ServerPacket::CloneObject {
+                id: self.pull_u32()?,
+                speed: self.pull_u32()?,
+                poly_id: self.pull_u16()?,
+                alignment: self.pull_i16()?,
+                poly_action: self.pull_u8()?,
+                title: self.pull_string()?,
+            },
+            None => return Err(PacketDecodeError::UnknownOpcode(opcode)),
+        })
     }
 
     /// Get the length of data currently in the packet
@@ -1214,7 +2031,7 @@ impl Packet {
     }
 
     /// Get a copy of the raw packet data
-    fn buf(&self) -> Vec<u8> {
+    pub(crate) fn buf(&self) -> Vec<u8> {
         self.data.to_vec()
     }
 
@@ -1268,6 +2085,21 @@ impl Packet {
         self
     }
 
+    /// Make sure the packet has at least the specified number of unread bytes
+    fn require(&self, needed: usize) -> Result<(), PacketDecodeError> {
+        let remaining = self.data.len().saturating_sub(self.read);
+        if remaining < needed {
+            Err(PacketDecodeError::ShortRead { needed, remaining })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns true when all data in the packet has been read
+    pub(crate) fn is_empty(&self) -> bool {
+        self.read >= self.data.len()
+    }
+
     /// Fetch the rest of the packet as a vector
     fn pull_remainder(&mut self) -> Vec<u8> {
         let v = self.data[self.read..].to_vec();
@@ -1276,42 +2108,67 @@ impl Packet {
     }
 
     /// Fetch a u8 from the packet
-    pub fn pull_u8(&mut self) -> u8 {
+    pub fn pull_u8(&mut self) -> Result<u8, PacketDecodeError> {
+        self.require(1)?;
         let val: u8 = self.data[self.read];
         self.read += 1;
-        val
+        Ok(val)
+    }
+
+    /// Fetch an i8 from the packet
+    pub fn pull_i8(&mut self) -> Result<i8, PacketDecodeError> {
+        let a: U8Converter = U8Converter { u: self.pull_u8()? };
+        Ok(unsafe { a.i })
+    }
+
+    /// Fetch the specified number of bytes from the packet
+    pub fn pull_vec(&mut self, len: usize) -> Result<Vec<u8>, PacketDecodeError> {
+        self.require(len)?;
+        let v = self.data[self.read..self.read + len].to_vec();
+        self.read += len;
+        Ok(v)
     }
 
     /// Fetch a u16 from the packet
-    pub fn pull_u16(&mut self) -> u16 {
+    pub fn pull_u16(&mut self) -> Result<u16, PacketDecodeError> {
+        self.require(2)?;
         let mut val: u16 = (self.data[self.read + 1] as u16) << 8;
         val |= self.data[self.read] as u16;
         self.read += 2;
-        val
+        Ok(val)
+    }
+
+    /// Fetch an i16 from the packet
+    pub fn pull_i16(&mut self) -> Result<i16, PacketDecodeError> {
+        let a: U16Converter = U16Converter {
+            u: self.pull_u16()?,
+        };
+        Ok(unsafe { a.i })
     }
 
     /// Fetch a u32 from the packet
-    pub fn pull_u32(&mut self) -> u32 {
+    pub fn pull_u32(&mut self) -> Result<u32, PacketDecodeError> {
+        self.require(4)?;
         let mut val: u32 = self.data[self.read + 3] as u32;
         val = (val << 8) | (self.data[self.read + 2] as u32);
         val = (val << 8) | (self.data[self.read + 1] as u32);
         val = (val << 8) | (self.data[self.read] as u32);
         self.read += 4;
-        val
+        Ok(val)
     }
 
-    /// Fetch a string from the packet
-    pub fn pull_string(&mut self) -> String {
-        let mut v: String = "".to_string();
-        //a do while loop
-        while {
-            let digit = self.pull_u8();
-            if digit != 0 {
-                v.push(digit as char);
-            }
-            digit != 0
-        } {}
-        v
+    /// Fetch a null terminated string from the packet
+    pub fn pull_string(&mut self) -> Result<String, PacketDecodeError> {
+        let end = self.data[self.read..]
+            .iter()
+            .position(|d| *d == 0)
+            .ok_or(PacketDecodeError::UnterminatedString)?;
+        let v: String = self.data[self.read..self.read + end]
+            .iter()
+            .map(|d| *d as char)
+            .collect();
+        self.read += end + 1;
+        Ok(v)
     }
 
     /// Peek into the packet
@@ -1426,14 +2283,501 @@ mod tests {
         let d = packet.encrypt(key).buf();
         assert_eq!(d, required);
     }
+
+    /// Tests that truncated packets produce errors instead of panicking
+    #[test]
+    fn truncated_packets() {
+        for opcode in 0..=255u8 {
+            let mut data = vec![opcode];
+            data.extend_from_slice(&[1; 40]);
+            for length in 0..data.len() {
+                let _ = Packet::raw_packet(data[0..length].to_vec()).convert();
+            }
+        }
+        assert_eq!(
+            Packet::raw_packet(vec![]).convert().unwrap_err(),
+            PacketDecodeError::ShortRead {
+                needed: 1,
+                remaining: 0
+            }
+        );
+        assert_eq!(
+            Packet::raw_packet(vec![45, 1, 2, 3, 4, 5])
+                .convert()
+                .unwrap_err(),
+            PacketDecodeError::ShortRead {
+                needed: 2,
+                remaining: 1
+            }
+        );
+        assert_eq!(
+            Packet::raw_packet(vec![34, b'a', b'b'])
+                .convert()
+                .unwrap_err(),
+            PacketDecodeError::UnterminatedString
+        );
+        assert_eq!(
+            Packet::raw_packet(vec![104, 99, 0]).convert().unwrap_err(),
+            PacketDecodeError::UnknownSubtype {
+                opcode: 104,
+                subtype: 99
+            }
+        );
+        assert!(matches!(
+            Packet::raw_packet(vec![104, 0, 0]).convert(),
+            Ok(ClientPacket::Chat(m)) if m.is_empty()
+        ));
+    }
+
+    /// Tests that client packets survive being built and converted again
+    #[test]
+    fn client_round_trip() {
+        let packets = vec![
+            ClientPacket::Version(1, 2, 3, 4),
+            ClientPacket::Login("user".to_string(), "pass".to_string(), 1, 2, 3, 4, 5, 6, 7),
+            ClientPacket::CharacterSelect {
+                name: "bob".to_string(),
+            },
+            ClientPacket::NewsDone,
+            ClientPacket::KeepAlive,
+            ClientPacket::GameInitDone,
+            ClientPacket::WindowActivate(5),
+            ClientPacket::Save,
+            ClientPacket::MoveFrom {
+                x: 32700,
+                y: 32800,
+                heading: 3,
+            },
+            ClientPacket::ChangeDirection(7),
+            ClientPacket::Chat("hello".to_string()),
+            ClientPacket::YellChat("hello".to_string()),
+            ClientPacket::CommandChat("hello".to_string()),
+            ClientPacket::SpecialCommandChat("hello".to_string()),
+            ClientPacket::PledgeChat("hello".to_string()),
+            ClientPacket::PartyChat("hello".to_string()),
+            ClientPacket::WhisperChat("bob".to_string(), "hello".to_string()),
+            ClientPacket::GlobalChat("hello".to_string()),
+            ClientPacket::ChangePassword {
+                account: "user".to_string(),
+                oldpass: "old".to_string(),
+                newpass: "new".to_string(),
+            },
+            ClientPacket::NewCharacter {
+                name: "bob".to_string(),
+                class: 1,
+                gender: 0,
+                strength: 12,
+                dexterity: 13,
+                constitution: 14,
+                wisdom: 15,
+                charisma: 16,
+                intelligence: 17,
+            },
+            ClientPacket::DeleteCharacter("bob".to_string()),
+            ClientPacket::CreateBookmark("home".to_string()),
+            ClientPacket::WhoCommand("bob".to_string()),
+            ClientPacket::AddFriend("bob".to_string()),
+            ClientPacket::RemoveFriend("bob".to_string()),
+            ClientPacket::Ping(9),
+            ClientPacket::Restart,
+            ClientPacket::UseItem {
+                id: 1234,
+                remainder: vec![1, 2, 3],
+            },
+            ClientPacket::AttackObject {
+                id: 1234,
+                x: 32700,
+                y: 32800,
+            },
+            ClientPacket::Unknown(vec![250, 1, 2]),
+        ];
+        for p in packets {
+            assert_eq!(p.clone().build().unwrap().convert(), Ok(p));
+        }
+    }
+
+    /// Tests that passwords are removed from packets before they are recorded
+    #[test]
+    fn recorded_without_passwords() {
+        let login = ClientPacket::Login(
+            "user".to_string(),
+            "secret".to_string(),
+            1,
+            2,
+            3,
+            4,
+            5,
+            6,
+            7,
+        );
+        let recorded = recorded_contents(&login.build().unwrap(), &CLASSIC);
+        assert_eq!(
+            Packet::raw_packet(recorded).convert(),
+            Ok(ClientPacket::Login(
+                "user".to_string(),
+                String::new(),
+                1,
+                2,
+                3,
+                4,
+                5,
+                6,
+                7
+            ))
+        );
+        let change = ClientPacket::ChangePassword {
+            account: "user".to_string(),
+            oldpass: "secret".to_string(),
+            newpass: "hidden".to_string(),
+        };
+        let recorded = recorded_contents(&change.build().unwrap(), &CLASSIC);
+        assert!(!recorded
+            .windows(6)
+            .any(|w| w == b"secret" || w == b"hidden"));
+        let chat = ClientPacket::Chat("secret".to_string()).build().unwrap();
+        assert_eq!(recorded_contents(&chat, &CLASSIC), chat.buf());
+    }
+
+    /// Tests that the server only npc chat packets can not be built by a client
+    #[test]
+    fn npc_chat_has_no_encoding() {
+        let p = ClientPacket::NpcChat {
+            id: 1234,
+            message: "hello".to_string(),
+        };
+        assert!(matches!(p.build(), Err(PacketError::NoEncoding)));
+    }
+
+    /// Tests that server packets survive being built and converted again
+    #[test]
+    fn server_round_trip() {
+        let element = InventoryElement {
+            id: 1234,
+            i_type: -1,
+            n_use: 2,
+            icon: -300,
+            blessing: ItemBlessing::Cursed,
+            count: 5,
+            identified: 1,
+            description: "a sword".to_string(),
+            ed: vec![23, 1, 5],
+        };
+        let packets = vec![
+            ServerPacket::NpcChat {
+                id: 1,
+                message: "hi".to_string(),
+            },
+            ServerPacket::NpcShout {
+                id: 1,
+                message: "hi".to_string(),
+            },
+            ServerPacket::NpcGlobalChat {
+                id: 1,
+                message: "hi".to_string(),
+            },
+            ServerPacket::EncryptionKey(0x12345678),
+            ServerPacket::Attack {
+                attack_type: 1,
+                id: 2,
+                id2: 3,
+                impact: 4,
+                direction: 5,
+                effect: None,
+            },
+            ServerPacket::Attack {
+                attack_type: 1,
+                id: 2,
+                id2: 3,
+                impact: 4,
+                direction: 5,
+                effect: Some(AttackEffect {
+                    id: 6,
+                    sprite: 7,
+                    light: 8,
+                    x1: 9,
+                    y1: 10,
+                    x2: 11,
+                    y2: 12,
+                    leave: 13,
+                    desc: "arrow".to_string(),
+                }),
+            },
+            ServerPacket::Message {
+                ty: MessageId::CannotDoHere,
+                msgs: vec!["a".to_string(), "b".to_string()],
+            },
+            ServerPacket::MoveObject {
+                id: 1,
+                x: 2,
+                y: 3,
+                direction: 4,
+            },
+            ServerPacket::BackToCharacterSelect,
+            ServerPacket::RemoveObject(5),
+            ServerPacket::SetCriminalCount { id: 1, count: 2 },
+            ServerPacket::CloneObject {
+                id: 1,
+                speed: 2,
+                poly_id: 3,
+                alignment: -4,
+                poly_action: 5,
+                title: "title".to_string(),
+            },
+            ServerPacket::ServerVersion {
+                id: 1,
+                version: 2,
+                time: 3,
+                new_accounts: 1,
+                english: 1,
+                country: 0,
+            },
+            ServerPacket::Disconnect,
+            ServerPacket::LoginResult {
+                code: LoginResult::WrongCredentials,
+            },
+            ServerPacket::LoginResult {
+                code: LoginResult::AccountBanned,
+            },
+            ServerPacket::LoginResult {
+                code: LoginResult::Other(99),
+            },
+            ServerPacket::CharacterNameAlreadyExists,
+            ServerPacket::News("news".to_string()),
+            ServerPacket::CharacterCreationStatus(CharacterCreationStatus::Failed),
+            ServerPacket::NewCharacterDetails {
+                name: "bob".to_string(),
+                pledge: "".to_string(),
+                class: 1,
+                gender: 0,
+                alignment: -5,
+                hp: 10,
+                mp: 11,
+                ac: -10,
+                level: 1,
+                strength: 12,
+                dexterity: 13,
+                constitution: 14,
+                wisdom: 15,
+                charisma: 16,
+                intelligence: 17,
+            },
+            ServerPacket::DeleteCharacterOk,
+            ServerPacket::DeleteCharacterWait,
+            ServerPacket::NumberCharacters(2, 8),
+            ServerPacket::LoginCharacterDetails {
+                name: "bob".to_string(),
+                pledge: "pledge".to_string(),
+                ctype: 1,
+                gender: 0,
+                alignment: 32767,
+                hp: 10,
+                mp: 11,
+                ac: 10,
+                level: 1,
+                strength: 12,
+                dexterity: 13,
+                constitution: 14,
+                wisdom: 15,
+                charisma: 16,
+                intelligence: 17,
+            },
+            ServerPacket::CharacterDetails {
+                id: 1,
+                level: 2,
+                xp: 3,
+                strength: 12,
+                dexterity: 13,
+                constitution: 14,
+                wisdom: 15,
+                charisma: 16,
+                intelligence: 17,
+                curr_hp: 4,
+                max_hp: 5,
+                curr_mp: 6,
+                max_mp: 7,
+                ac: -8,
+                time: 9,
+                food: 0.0,
+                weight: 1.0,
+                alignment: -10,
+                fire_resist: 11,
+                water_resist: 12,
+                wind_resist: 13,
+                earth_resist: 14,
+            },
+            ServerPacket::StartGame(1),
+            ServerPacket::MapId(4, 0),
+            ServerPacket::PutObject {
+                x: 1,
+                y: 2,
+                id: 3,
+                icon: 4,
+                status: 5,
+                direction: 6,
+                light: 7,
+                speed: 8,
+                xp: 9,
+                alignment: -10,
+                name: "bob".to_string(),
+                title: "title".to_string(),
+                status2: 11,
+                pledgeid: 12,
+                pledgename: "pledge".to_string(),
+                owner_name: "owner".to_string(),
+                v1: 13,
+                hp_bar: 255,
+                v2: 14,
+                level: 15,
+            },
+            ServerPacket::CharSpMrBonus { sp: 1, mr: 2 },
+            ServerPacket::Weather(Weather::Rain(1)),
+            ServerPacket::Weather(Weather::Snow(3)),
+            ServerPacket::Weather(Weather::Clear),
+            ServerPacket::SystemMessage("system".to_string()),
+            ServerPacket::RegularChat {
+                id: 1,
+                msg: "hi".to_string(),
+            },
+            ServerPacket::YellChat {
+                id: 1,
+                msg: "hi".to_string(),
+                x: 2,
+                y: 3,
+            },
+            ServerPacket::GlobalChat("hi".to_string()),
+            ServerPacket::PledgeChat("hi".to_string()),
+            ServerPacket::PartyChat("hi".to_string()),
+            ServerPacket::WhisperChat {
+                name: "bob".to_string(),
+                msg: "hi".to_string(),
+            },
+            ServerPacket::InventoryVec(vec![element.clone(), element.clone()]),
+            ServerPacket::Inventory(element),
+            ServerPacket::InventoryMod(InventoryUpdate {
+                id: 1,
+                description: "a sword".to_string(),
+                count: 2,
+                ed: vec![],
+            }),
+            ServerPacket::InventoryDescriptionUpdate {
+                id: 1,
+                description: "a sword".to_string(),
+            },
+        ];
+        for p in packets {
+            assert_eq!(p.clone().build().unwrap().convert_server(), Ok(p));
+        }
+        assert_eq!(
+            Packet::raw_packet(vec![255]).convert_server(),
+            Err(PacketDecodeError::UnknownOpcode(255))
+        );
+    }
+
+    /// Tests that invalid frame lengths produce errors instead of panicking
+    #[tokio::test]
+    async fn frame_lengths() {
+        let mut data: &[u8] = &[3, 0, 1, 2, 3];
+        assert!(matches!(
+            FrameReader::new(100).read_frame(&mut data).await,
+            Err(PacketError::FrameTooShort(3))
+        ));
+        let mut data: &[u8] = &[0xff, 0xff, 1, 2, 3, 4];
+        assert!(matches!(
+            FrameReader::new(100).read_frame(&mut data).await,
+            Err(PacketError::FrameTooLarge {
+                length: 65535,
+                max: 100
+            })
+        ));
+        let mut data: &[u8] = &[8, 0, 1, 2, 3];
+        assert!(matches!(
+            FrameReader::new(100).read_frame(&mut data).await,
+            Err(PacketError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+        let mut data: &[u8] = &[6, 0, 1, 2, 3, 4, 7, 0, 5, 6, 7, 8, 9];
+        let mut f = FrameReader::new(100);
+        assert_eq!(
+            f.read_frame(&mut data).await.unwrap().buf(),
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!(
+            f.read_frame(&mut data).await.unwrap().buf(),
+            vec![5, 6, 7, 8, 9]
+        );
+    }
+
+    /// Tests that cancelling a read in the middle of a frame does not lose any data
+    #[tokio::test]
+    async fn frame_cancel() {
+        let (mut w, mut r) = tokio::io::duplex(64);
+        let mut f = FrameReader::new(100);
+        w.write_all(&[8, 0, 1, 2]).await.unwrap();
+        let timeout = std::time::Duration::from_millis(50);
+        assert!(tokio::time::timeout(timeout, f.read_frame(&mut r))
+            .await
+            .is_err());
+        w.write_all(&[3, 4, 5, 6]).await.unwrap();
+        assert_eq!(
+            f.read_frame(&mut r).await.unwrap().buf(),
+            vec![1, 2, 3, 4, 5, 6]
+        );
+    }
+
+    /// Tests a client connection talking to the server side packet handlers over loopback
+    #[tokio::test]
+    async fn client_connection_loopback() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (r, w) = socket.into_split();
+            let mut recv = ServerPacketReceiver::new(r, 0x12345678);
+            let mut send = ServerPacketSender::new(w);
+            send.set_future_encryption_key(0x12345678);
+            send.queue_packet(ServerPacket::News("news".to_string()));
+            send.send_all_current_packets(Some(&mut recv))
+                .await
+                .unwrap();
+            for _ in 0..3 {
+                let p = recv.read_packet().await.unwrap().convert().unwrap();
+                if let ClientPacket::Chat(m) = p {
+                    send.queue_packet(ServerPacket::SystemMessage(m));
+                    send.send_all_current_packets(None).await.unwrap();
+                } else {
+                    panic!("Unexpected packet {:?}", p);
+                }
+            }
+        });
+        let (mut send, mut recv) = ClientConnection::connect(addr).await.unwrap().split();
+        assert_eq!(
+            recv.read_server_packet().await.unwrap(),
+            ServerPacket::News("news".to_string())
+        );
+        for m in ["a", "hello", "a much longer message"] {
+            send.send_packet(ClientPacket::Chat(m.to_string()))
+                .await
+                .unwrap();
+            assert_eq!(
+                recv.read_server_packet().await.unwrap(),
+                ServerPacket::SystemMessage(m.to_string())
+            );
+        }
+        server.await.unwrap();
+    }
 }
 
 /// Used for receiving packets from a user
 pub struct ServerPacketReceiver {
     /// The read half of a tcp connectio to a user
     reader: tokio::net::tcp::OwnedReadHalf,
+    /// Splits the received data into frames
+    frames: FrameReader,
     /// The decryption key for receiving packets
     decryption_key: u64,
+    /// Records every packet received, when present
+    recorder: Option<Recorder>,
+    /// The opcodes used by the client, used to find the packets that must not be recorded as they are
+    opcodes: &'static OpcodeTable,
 }
 
 impl ServerPacketReceiver {
@@ -1441,10 +2785,28 @@ impl ServerPacketReceiver {
     pub fn new(r: tokio::net::tcp::OwnedReadHalf, key: u32) -> ServerPacketReceiver {
         ServerPacketReceiver {
             reader: r,
+            frames: FrameReader::new(DEFAULT_MAX_FRAME_SIZE),
             decryption_key: key_init(key),
+            recorder: None,
+            opcodes: &CLASSIC,
         }
     }
 
+    /// Set the opcodes used by the client
+    pub fn set_opcode_table(&mut self, t: &'static OpcodeTable) {
+        self.opcodes = t;
+    }
+
+    /// Set the maximum size of a frame that can be received, including the two length bytes
+    pub fn set_max_frame_size(&mut self, max: usize) {
+        self.frames.max_size = max;
+    }
+
+    /// Record all received packets with the given recorder
+    pub fn set_recorder(&mut self, r: Recorder) {
+        self.recorder = Some(r);
+    }
+
     /// Get the decryption key
     pub fn get_key(&self) -> u64 {
         self.decryption_key
@@ -1452,18 +2814,117 @@ impl ServerPacketReceiver {
 
     /// Read a packet from the user
     pub async fn read_packet(&mut self) -> Result<Packet, PacketError> {
-        let mut packet = Packet::new();
-        let length: usize = self.reader.read_i16_le().await?.try_into().unwrap();
-        let mut contents: Vec<u8> = vec![0; length - 2];
-        self.reader.read_exact(&mut contents).await?;
-        packet.add_vec(&contents);
+        let mut packet = self.frames.read_frame(&mut self.reader).await?;
         packet.decrypt(self.decryption_key);
+        if let Some(r) = &self.recorder {
+            r.record(
+                Direction::ClientToServer,
+                Some(self.decryption_key),
+                &recorded_contents(&packet, self.opcodes),
+            );
+        }
         let kcv = packet.peek_u32();
         self.decryption_key = change_key(self.decryption_key, kcv);
         Ok(packet)
     }
 }
 
+/// The contents of a received packet as they are recorded.
+/// Passwords are never written to a capture, login and password change packets are recorded without them.
+fn recorded_contents(p: &Packet, t: &OpcodeTable) -> Vec<u8> {
+    let opcode = match p.data.first() {
+        Some(o) => *o,
+        None => return Vec::new(),
+    };
+    match t.client_kind(opcode) {
+        Some(ClientOpcode::Login) | Some(ClientOpcode::ChangePassword) => p
+            .clone()
+            .convert_with(t)
+            .ok()
+            .and_then(|c| c.redacted().build_with(t).ok())
+            .map(|p| p.buf())
+            .unwrap_or_else(|| vec![opcode]),
+        _ => p.buf(),
+    }
+}
+
+/// Reads length prefixed frames. Partial frames are kept in a buffer, so a read can be cancelled without losing data.
+struct FrameReader {
+    /// The data received that has not been returned as a frame yet
+    buf: Vec<u8>,
+    /// The maximum size of a frame, including the two length bytes
+    max_size: usize,
+}
+
+impl FrameReader {
+    /// Construct a new frame reader
+    fn new(max_size: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            max_size,
+        }
+    }
+
+    /// Read the contents of a single frame, without decrypting it
+    async fn read_frame<R: tokio::io::AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Packet, PacketError> {
+        loop {
+            if let Some(p) = self.take_frame()? {
+                return Ok(p);
+            }
+            if reader.read_buf(&mut self.buf).await? == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+    }
+
+    /// Remove a complete frame from the buffer, if one is present
+    fn take_frame(&mut self) -> Result<Option<Packet>, PacketError> {
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+        let length = u16::from_le_bytes([self.buf[0], self.buf[1]]) as usize;
+        if length < 2 + MIN_FRAME_PAYLOAD {
+            return Err(PacketError::FrameTooShort(length));
+        }
+        if length > self.max_size {
+            return Err(PacketError::FrameTooLarge {
+                length,
+                max: self.max_size,
+            });
+        }
+        if self.buf.len() < length {
+            self.buf.reserve(length - self.buf.len());
+            return Ok(None);
+        }
+        let contents = self.buf[2..length].to_vec();
+        self.buf.drain(..length);
+        Ok(Some(Packet::raw_packet(contents)))
+    }
+}
+
+/// Pad, encrypt (if a key is present) and write a single packet as a length prefixed frame.
+/// Returns the encryption key to use for the next packet.
+async fn write_frame<W: tokio::io::AsyncWrite + Unpin>(
+    writer: &mut W,
+    mut data: Packet,
+    key: Option<u64>,
+) -> Result<Option<u64>, PacketError> {
+    while data.buf().len() < 4 {
+        data.add_u8(0);
+    }
+    let kcv = data.peek_u32();
+    let key = key.map(|key| {
+        data.encrypt(key);
+        change_key(key, kcv)
+    });
+    writer.write_u16_le(data.len() + 2).await?;
+    writer.write_all(&data.buf()).await?;
+    Ok(key)
+}
+
 /// Used for sending packets to a single user
 pub struct ServerPacketSender {
     /// The write half of a tcp connection
@@ -1474,6 +2935,10 @@ pub struct ServerPacketSender {
     future_encryption_key: Option<u32>,
     /// The packets pending being sent out
     pending_packets: Vec<ServerPacket>,
+    /// Records every packet sent, when present
+    recorder: Option<Recorder>,
+    /// The opcodes used by the client
+    opcodes: &'static OpcodeTable,
 }
 
 impl ServerPacketSender {
@@ -1484,9 +2949,21 @@ impl ServerPacketSender {
             encryption_key: None,
             future_encryption_key: None,
             pending_packets: Vec::new(),
+            recorder: None,
+            opcodes: &CLASSIC,
         }
     }
 
+    /// Set the opcodes to use for packets sent to the client
+    pub fn set_opcode_table(&mut self, t: &'static OpcodeTable) {
+        self.opcodes = t;
+    }
+
+    /// Record all sent packets with the given recorder
+    pub fn set_recorder(&mut self, r: Recorder) {
+        self.recorder = Some(r);
+    }
+
     /// Set the future encryption key
     pub fn set_future_encryption_key(&mut self, ek: u32) {
         self.future_encryption_key = Some(ek);
@@ -1531,19 +3008,19 @@ impl ServerPacketSender {
 
     async fn send_packet(&mut self, data: ServerPacket) -> Result<(), PacketError> {
         //log::info!("Sending packet {:?}", data);
-        let mut data = data.build();
-        self.writer.writable().await?;
-        while data.buf().len() < 4 {
-            data.add_u8(0);
-        }
-        let kcv = data.peek_u32();
-
-        if let Some(key) = self.encryption_key {
-            data.encrypt(key);
-            self.encryption_key = Some(change_key(key, kcv));
+        let data = match data.build_with(self.opcodes) {
+            Ok(d) => d,
+            Err(e) => {
+                //one packet the client can not receive is no reason to drop the connection
+                log::error!("Not sending a packet: {:?}", e);
+                return Ok(());
+            }
+        };
+        if let Some(r) = &self.recorder {
+            r.record(Direction::ServerToClient, self.encryption_key, &data.buf());
         }
-        self.writer.write_u16_le(data.len() + 2).await?;
-        self.writer.write_all(&data.buf()).await?;
+        self.writer.writable().await?;
+        self.encryption_key = write_frame(&mut self.writer, data, self.encryption_key).await?;
         Ok(())
     }
 
@@ -1552,3 +3029,110 @@ impl ServerPacketSender {
         self.pending_packets.push(data);
     }
 }
+
+/// A connection from a client to a game server
+pub struct ClientConnection {
+    /// Used to send packets to the server
+    pub sender: ClientPacketSender,
+    /// Used to receive packets from the server
+    pub receiver: ClientPacketReceiver,
+}
+
+impl ClientConnection {
+    /// Connect to a game server and wait for the initial encryption key
+    pub async fn connect<A: tokio::net::ToSocketAddrs>(
+        addr: A,
+    ) -> Result<ClientConnection, PacketError> {
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        Self::new(stream).await
+    }
+
+    /// Perform the initial encryption key exchange over an already connected stream
+    pub async fn new(stream: tokio::net::TcpStream) -> Result<ClientConnection, PacketError> {
+        let (r, w) = stream.into_split();
+        let mut reader = r;
+        let mut frames = FrameReader::new(u16::MAX as usize);
+        let packet = frames.read_frame(&mut reader).await?;
+        let seed = match packet.convert_server()? {
+            ServerPacket::EncryptionKey(seed) => seed,
+            p => return Err(PacketError::UnexpectedPacket(Box::new(p))),
+        };
+        let key = key_init(seed);
+        Ok(ClientConnection {
+            sender: ClientPacketSender {
+                writer: w,
+                encryption_key: key,
+                opcodes: &CLASSIC,
+            },
+            receiver: ClientPacketReceiver {
+                reader,
+                frames,
+                decryption_key: key,
+                opcodes: &CLASSIC,
+            },
+        })
+    }
+
+    /// Set the opcodes to use for both directions of the connection
+    pub fn set_opcode_table(&mut self, t: &'static OpcodeTable) {
+        self.sender.opcodes = t;
+        self.receiver.opcodes = t;
+    }
+
+    /// Split the connection into the sending and receiving halves
+    pub fn split(self) -> (ClientPacketSender, ClientPacketReceiver) {
+        (self.sender, self.receiver)
+    }
+}
+
+/// Used for receiving packets from a game server
+pub struct ClientPacketReceiver {
+    /// The read half of a tcp connection to a server
+    reader: tokio::net::tcp::OwnedReadHalf,
+    /// Splits the received data into frames
+    frames: FrameReader,
+    /// The decryption key for receiving packets
+    decryption_key: u64,
+    /// The opcodes used by the client
+    opcodes: &'static OpcodeTable,
+}
+
+impl ClientPacketReceiver {
+    /// Read a packet from the server
+    pub async fn read_packet(&mut self) -> Result<Packet, PacketError> {
+        let mut packet = self.frames.read_frame(&mut self.reader).await?;
+        packet.decrypt(self.decryption_key);
+        let kcv = packet.peek_u32();
+        self.decryption_key = change_key(self.decryption_key, kcv);
+        Ok(packet)
+    }
+
+    /// Read a packet from the server and decode it
+    pub async fn read_server_packet(&mut self) -> Result<ServerPacket, PacketError> {
+        Ok(self
+            .read_packet()
+            .await?
+            .convert_server_with(self.opcodes)?)
+    }
+}
+
+/// Used for sending packets to a game server
+pub struct ClientPacketSender {
+    /// The write half of a tcp connection
+    writer: tokio::net::tcp::OwnedWriteHalf,
+    /// The encryption key to use for the next data to send out
+    encryption_key: u64,
+    /// The opcodes used by the client
+    opcodes: &'static OpcodeTable,
+}
+
+impl ClientPacketSender {
+    /// Send a packet to the server
+    pub async fn send_packet(&mut self, data: ClientPacket) -> Result<(), PacketError> {
+        let data = data.build_with(self.opcodes)?;
+        if let Some(key) = write_frame(&mut self.writer, data, Some(self.encryption_key)).await? {
+            self.encryption_key = key;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,5 @@
+-- update 67
+
+# Record when a ban on an account ends and why it was banned
+ALTER TABLE accounts ADD ban_expires datetime default NULL after banned;
+ALTER TABLE accounts ADD ban_reason varchar(255) default NULL after ban_expires;
@@ -0,0 +1,4 @@
+-- update 68
+
+# Passwords are stored with scrypt, which needs a longer column than the old sha256 hashes
+ALTER TABLE accounts MODIFY password varchar(255) NOT NULL;
@@ -0,0 +1,6 @@
+-- update 69
+
+# Ids for characters, items, warehouses, clans and pets are handed out in blocks from this table,
+# instead of looking for the largest id in use every time
+CREATE TABLE id_sequence (name varchar(32) NOT NULL PRIMARY KEY, next_id int unsigned NOT NULL);
+INSERT INTO id_sequence (name, next_id) SELECT 'object', GREATEST(COALESCE(MAX(id), 0) + 1, 2) FROM (select id from character_items union all select id from character_teleport union all select id from character_warehouse union all select id from character_elf_warehouse union all select objid as id from characters union all select clan_id as id from clan_data union all select id from clan_warehouse union all select objid as id from pets) t;
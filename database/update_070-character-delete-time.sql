@@ -0,0 +1,8 @@
+-- update 70
+
+# Characters above the delete wait level are marked with the time they will be deleted, instead of being deleted at once.
+# Some copies of the database already have this column, so it is only added when it is missing.
+SET @add_delete_time = (SELECT IF(COUNT(*) = 0, 'ALTER TABLE characters ADD DeleteTime datetime default NULL', 'SELECT 1') FROM information_schema.columns WHERE table_schema = DATABASE() AND table_name = 'characters' AND column_name = 'DeleteTime');
+PREPARE add_delete_time FROM @add_delete_time;
+EXECUTE add_delete_time;
+DEALLOCATE PREPARE add_delete_time;
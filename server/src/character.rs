@@ -5,13 +5,14 @@ use std::{
     convert::TryInto,
 };
 
-use common::packet::{ServerPacket, ServerPacketSender};
-use mysql::{prelude::Queryable, Params};
+use common::packet::{MessageId, ServerPacket, ServerPacketSender};
+use mysql::Params;
 
 use crate::{
     server::ClientError,
+    storage::Storage,
     world::{
-        item::{ItemInstance, ItemInstanceWithoutDefinition, ItemUsage, Weapon},
+        item::{ItemInstance, ItemUsage, Weapon},
         object::ObjectList,
         Map, WorldObjectId, WorldResponse,
     },
@@ -62,6 +63,8 @@ pub struct FullCharacter {
     details: ExtraCharacterDetails,
     /// All the items the character holds
     items: HashMap<u32, crate::world::item::ItemInstance>,
+    /// The ids of the items that are in storage
+    stored_items: HashSet<u32>,
     /// The known objects for the character
     known_objects: ObjectList,
     /// How to send messages to the async task for this character
@@ -151,6 +154,7 @@ impl PartialCharacter {
             charisma: self.charisma,
             intelligence: self.intelligence,
             details: self.details,
+            stored_items: items.keys().copied().collect(),
             items,
             known_objects: ObjectList::new(),
             sender: None,
@@ -467,6 +471,37 @@ impl FullCharacter {
         &self.location
     }
 
+    /// Take a copy of the state of the character and the changes to its items, to be saved to the database.
+    /// The items are considered saved afterwards, so the copy must not be dropped when writing it fails.
+    /// The [crate::storage::persistence::PersistenceWorker] keeps failed saves until they are written.
+    pub fn saved(&mut self) -> SavedCharacter {
+        let mut items = crate::world::item::ItemChanges::default();
+        for (id, i) in self.items.iter_mut() {
+            if !self.stored_items.contains(id) {
+                items.inserted.insert(*id, i.take_saved(self.id));
+            } else if i.dirty() {
+                items.updated.insert(*id, i.take_saved(self.id));
+            }
+        }
+        items.deleted = self
+            .stored_items
+            .iter()
+            .filter(|id| !self.items.contains_key(id))
+            .copied()
+            .collect();
+        self.stored_items = self.items.keys().copied().collect();
+        SavedCharacter {
+            character: (&*self).into(),
+            details: self.details,
+            items,
+        }
+    }
+
+    /// Give an item to the character
+    pub fn add_item(&mut self, item: ItemInstance) {
+        self.items.insert(item.id(), item);
+    }
+
     pub fn add_sender(&mut self, s: tokio::sync::mpsc::Sender<crate::world::WorldResponse>) {
         self.sender = Some(s);
     }
@@ -483,7 +518,7 @@ impl FullCharacter {
             ////TODO Check to see if there is a delay timer in effect for the item being used
             if crate::world::item::ItemUsage::None == item.usage() {
                 p2.packets.push(ServerPacket::Message {
-                    ty: 74,
+                    ty: MessageId::CannotUse,
                     msgs: vec![item.name()],
                 });
                 return Ok(());
@@ -491,7 +526,7 @@ impl FullCharacter {
             // Or when the map you are on disallows item usage
             if !map.can_use_items() {
                 p2.packets.push(ServerPacket::Message {
-                    ty: 563,
+                    ty: MessageId::CannotDoHere,
                     msgs: vec![],
                 });
                 return Ok(());
@@ -500,13 +535,13 @@ impl FullCharacter {
             let mut item_target = None;
             match item.usage() {
                 ItemUsage::Polymorph => {
-                    poly_name = Some(p2.p.pull_string());
+                    poly_name = Some(p2.p.pull_string()?);
                 }
                 ItemUsage::EnchantArmorScroll
                 | ItemUsage::EnchantWeaponScroll
                 | ItemUsage::IdentifyScroll
                 | ItemUsage::WithTarget => {
-                    item_target = Some(p2.p.pull_u32());
+                    item_target = Some(p2.p.pull_u32()?);
                 }
                 ItemUsage::Normal => {
                     //item 41048..=41057 glued logbook page is a pull u32
@@ -516,22 +551,22 @@ impl FullCharacter {
                     //items 41255..=41259 probably need to be fixed in db, they are a pull_u8, pull_u8
                 }
                 ItemUsage::TeleportScroll1 | ItemUsage::BlessedTeleport => {
-                    item_target = Some(p2.p.pull_u32());
+                    item_target = Some(p2.p.pull_u32()?);
                 }
                 ItemUsage::BlankScroll => {
-                    let spell_id = p2.p.pull_u8();
+                    let spell_id = p2.p.pull_u8()?;
                 }
                 ItemUsage::SpellBuff => {
                     //item 40870 and 40879 change to choice in db?
-                    let spell_id = p2.p.pull_u32();
+                    let spell_id = p2.p.pull_u32()?;
                 }
                 ItemUsage::WandNearbyTarget | ItemUsage::WandWithTarget => {
-                    let spell_id = p2.p.pull_u32();
-                    let spell_x = p2.p.pull_u16();
-                    let spell_y = p2.p.pull_u16();
+                    let spell_id = p2.p.pull_u32()?;
+                    let spell_x = p2.p.pull_u16()?;
+                    let spell_y = p2.p.pull_u16()?;
                 }
                 ItemUsage::ResurrectScroll => {
-                    let id = p2.p.pull_u32();
+                    let id = p2.p.pull_u32()?;
                 }
                 ItemUsage::Letter
                 | ItemUsage::Letter2
@@ -541,13 +576,13 @@ impl FullCharacter {
                 | ItemUsage::ValentinesCard2
                 | ItemUsage::WhiteDayCard
                 | ItemUsage::WhiteDayCard2 => {
-                    let code = p2.p.pull_u16();
-                    let receiver = p2.p.pull_string();
-                    let body = p2.p.pull_u8();
+                    let code = p2.p.pull_u16()?;
+                    let receiver = p2.p.pull_string()?;
+                    let body = p2.p.pull_u8()?;
                 }
                 ItemUsage::FishingRod => {
-                    let fishx = p2.p.pull_u16();
-                    let fishy = p2.p.pull_u16();
+                    let fishx = p2.p.pull_u16()?;
+                    let fishy = p2.p.pull_u16()?;
                 }
                 ItemUsage::Armor => {
                     log::info!("Need to check armor equipping");
@@ -791,8 +826,28 @@ impl mysql::prelude::FromRow for ExtraCharacterDetails {
     }
 }
 
+/// The state of a character that is being played, as it is saved to the database
+#[derive(Clone, Debug)]
+pub struct SavedCharacter {
+    /// The character
+    pub character: Character,
+    /// The extra details of the character
+    pub details: ExtraCharacterDetails,
+    /// The changes to the items of the character
+    pub items: crate::world::item::ItemChanges,
+}
+
+impl SavedCharacter {
+    /// Combine with a newer save of the same character, so only one save needs to be written
+    pub fn merge(&mut self, newer: SavedCharacter) {
+        self.character = newer.character;
+        self.details = newer.details;
+        self.items.merge(newer.items);
+    }
+}
+
 /// Represents a playable character in the game
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Character {
     /// The account name for the character
     account_name: String,
@@ -832,6 +887,8 @@ pub struct Character {
     intelligence: u8,
     /// Character location
     location: Location,
+    /// When the character will be deleted, if it is marked for deletion
+    delete_time: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 /// The possible classes for a character
@@ -1021,6 +1078,37 @@ impl Character {
         &self.name
     }
 
+    /// Get the id of the character in the database
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Set the id of the character in the database, used when the character is first stored
+    pub fn set_id(&mut self, id: u32) {
+        self.id = id;
+    }
+
+    /// Get the name of the account the character belongs to
+    pub fn account_name(&self) -> &str {
+        &self.account_name
+    }
+
+    /// The extra details of a character that has just been created
+    pub fn initial_details(&self) -> ExtraCharacterDetails {
+        ExtraCharacterDetails {
+            exp: 0,
+            curr_hp: self.hp_max,
+            curr_mp: self.mp_max,
+            time: 1,
+            food: 40,
+            weight: 32,
+            fire_resist: 1,
+            water_resist: 2,
+            wind_resist: 3,
+            earth_resist: 4,
+        }
+    }
+
     /// Get the player alignment
     pub fn alignment(&self) -> i16 {
         self.alignment
@@ -1031,12 +1119,28 @@ impl Character {
         self.level >= 30
     }
 
+    /// When the character will be deleted, if it is marked for deletion
+    pub fn delete_time(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.delete_time
+    }
+
+    /// Set when the character will be deleted, None to keep the character
+    pub fn set_delete_time(&mut self, when: Option<chrono::DateTime<chrono::Utc>>) {
+        self.delete_time = when;
+    }
+
     /// Construct a details packet for informing the user of a character they can log in with
     pub fn get_details_packet(&self) -> ServerPacket {
+        //the client shows characters waiting to be deleted differently when 32 is added to the type
+        let ctype = if self.delete_time.is_some() {
+            self.class as u8 + 32
+        } else {
+            self.class as u8
+        };
         ServerPacket::LoginCharacterDetails {
             name: self.name.clone(),
             pledge: self.pledge.clone(),
-            ctype: self.class as u8,
+            ctype,
             gender: self.gender,
             alignment: self.alignment,
             hp: self.hp_max,
@@ -1073,39 +1177,19 @@ impl Character {
         }
     }
 
-    /// Retrieve all items for the character
-    fn get_items(
-        &self,
-        mysql: &mut mysql::PooledConn,
-    ) -> Result<Vec<ItemInstanceWithoutDefinition>, crate::server::ClientError> {
-        let query = "SELECT * from character_items WHERE char_id=?";
-        let s = mysql.prep(query)?;
-        let params = Params::Positional(vec![self.id.into()]);
-        let details = mysql.exec_map(s, params, |a: ItemInstanceWithoutDefinition| a)?;
-        Ok(details)
-    }
-
     /// Retrieve all gameplay details of the character from the database, some of the elements need to be looked up to finish the character.
     pub fn get_partial_details(
         &self,
         new_id: super::world::WorldObjectId,
-        mysql: &mut mysql::PooledConn,
+        storage: &dyn Storage,
     ) -> Result<PartialCharacter, crate::server::ClientError> {
-        use mysql::prelude::Queryable;
-        let query = "SELECT Exp, CurHp, CurMp, 1, Food, 32, 1, 2, 3, 4 from characters WHERE account_name=? and char_name=?";
         log::info!(
             "Checking for account {} -  player {}",
             self.account_name,
             self.name
         );
-        let s = mysql.prep(query)?;
-        let details = mysql.exec_map(
-            s,
-            (&self.account_name, &self.name),
-            |a: ExtraCharacterDetails| a,
-        )?;
-        let details = details[0];
-        let items = self.get_items(mysql)?;
+        let details = storage.character_details(self)?;
+        let items = storage.character_items(self.id)?;
         let mut item_map = HashMap::new();
         for i in items {
             item_map.insert(i.id(), i);
@@ -1137,23 +1221,17 @@ impl Character {
     }
 
     /// Delete the character from the database
-    pub fn delete_char(&self, mysql: &mut mysql::PooledConn) -> Result<(), mysql::Error> {
-        let query = "DELETE FROM characters WHERE account_name=? AND char_name=?";
-        mysql.exec_drop(query, (self.account_name.clone(), self.name.clone()))?;
-        Ok(())
+    pub fn delete_char(&self, storage: &dyn Storage) -> Result<(), crate::storage::StorageError> {
+        storage.delete_character(&self.account_name, &self.name)
     }
 
     /// Retrieve characters for user account from database
     pub fn retrieve_chars(
-        account_name: &String,
-        mysql: &mut mysql::PooledConn,
+        account_name: &str,
+        storage: &dyn Storage,
     ) -> Result<Vec<crate::character::Character>, crate::server::ClientError> {
-        use mysql::prelude::Queryable;
-        let query = "SELECT * from characters WHERE account_name=?";
         log::info!("Checking for account {}", account_name);
-        let s = mysql.prep(query)?;
-        let asdf = mysql.exec_map(s, (account_name.clone(),), |a: Character| a)?;
-        Ok(asdf)
+        Ok(storage.characters(account_name)?)
     }
 
     /// Roll a new character
@@ -1194,33 +1272,18 @@ impl Character {
             charisma: cha,
             intelligence: int,
             location: class.starting_location(),
+            delete_time: None,
         })
     }
 
     /// Save a new character into the database, updating the id of the character to a new valid id
     pub fn save_new_to_db(
         &mut self,
-        mysql: &mut mysql::PooledConn,
+        storage: &dyn Storage,
     ) -> Result<(), crate::server::ClientError> {
-        use mysql::prelude::Queryable;
-        let mut t = mysql.start_transaction(mysql::TxOpts::default())?;
-        let p = mysql::Params::Positional(vec![self.name.as_str().into()]);
-        let row: Option<mysql::Row> =
-            t.exec_first("SELECT * FROM characters WHERE char_name=?", p)?;
-        let exists = row.is_some();
-        if !exists {
-            let id = crate::world::World::get_new_id(&mut t)?;
-            if let Some(id) = id {
-                self.id = id;
-            } else {
-                self.id = 2;
-            }
-            let query = "INSERT INTO characters SET account_name=?,objid=?,char_name=?,level=?,MaxHp=?,MaxMp=?,Class=?,Sex=?,Ac=?,Str=?,Dex=?,Con=?,Wis=?,Cha=?,Intel=?,LocX=?,LocY=?,MapID=?";
-            t.exec_drop(query, self)?;
-            t.commit()?;
+        if storage.insert_character(self)? {
             Ok(())
         } else {
-            t.rollback()?;
             Err(ClientError::CharacterExists)
         }
     }
@@ -1252,6 +1315,52 @@ impl From<&mut Character> for Params {
     }
 }
 
+impl From<&SavedCharacter> for Params {
+    fn from(value: &SavedCharacter) -> Self {
+        let c = &value.character;
+        let p = vec![
+            c.location.x.into(),
+            c.location.y.into(),
+            c.location.map.into(),
+            c.location.direction.into(),
+            value.details.curr_hp.into(),
+            value.details.curr_mp.into(),
+            value.details.exp.into(),
+            c.alignment.into(),
+            c.level.into(),
+            c.id.into(),
+        ];
+        Params::Positional(p)
+    }
+}
+
+impl From<&FullCharacter> for Character {
+    fn from(value: &FullCharacter) -> Self {
+        Self {
+            account_name: value.account_name.clone(),
+            access_level: value.access_level,
+            name: value.name.clone(),
+            id: value.id,
+            alignment: value.alignment,
+            level: value.level,
+            pledge: value.pledge.clone(),
+            class: value.class,
+            gender: value.gender,
+            hp_max: value.hp_max,
+            mp_max: value.mp_max,
+            ac: value.ac,
+            strength: value.strength,
+            dexterity: value.dexterity,
+            constitution: value.constitution,
+            wisdom: value.wisdom,
+            charisma: value.charisma,
+            intelligence: value.intelligence,
+            location: value.location,
+            delete_time: None,
+        }
+    }
+}
+
 impl mysql::prelude::FromRow for Character {
     fn from_row_opt(row: mysql::Row) -> Result<Self, mysql::FromRowError>
     where
@@ -1262,6 +1371,9 @@ impl mysql::prelude::FromRow for Character {
         let y: u16 = row.get("LocY").ok_or(mysql::FromRowError(row.clone()))?;
         let direction: u8 = row.get("Heading").ok_or(mysql::FromRowError(row.clone()))?;
         let map: u16 = row.get("MapID").ok_or(mysql::FromRowError(row.clone()))?;
+        let delete_time: mysql::Value = row
+            .get("DeleteTime")
+            .ok_or(mysql::FromRowError(row.clone()))?;
         Ok(Self {
             account_name: row
                 .get("account_name")
@@ -1295,6 +1407,11 @@ impl mysql::prelude::FromRow for Character {
                 map,
                 direction,
             },
+            delete_time: if delete_time == mysql::Value::NULL {
+                None
+            } else {
+                Some(crate::user::convert_date(delete_time))
+            },
         })
     }
 }
@@ -2,9 +2,23 @@
 
 use std::io::Read;
 
+/// Where the server stores its data
+#[derive(Copy, Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// A mysql database
+    #[default]
+    Mysql,
+    /// Memory only, nothing is saved when the server stops. There are no items or npcs.
+    Memory,
+}
+
 /// The configuration needed to make a connection to a mysql server
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct MysqlConfig {
+    /// Where the data is stored, the remaining settings are only used for mysql
+    #[serde(default)]
+    pub backend: StorageBackend,
     /// The password for the mysql login
     pub password: String,
     /// The username to login to the mysql server with
@@ -15,13 +29,301 @@ pub struct MysqlConfig {
     pub url: String,
 }
 
+/// The addresses and ports the server listens on
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// The address the game server listens on, use "::" to listen on all ipv4 and ipv6 addresses
+    pub game_address: std::net::IpAddr,
+    /// The port the game server listens on
+    pub game_port: u16,
+    /// Is the update server started?
+    pub update_enabled: bool,
+    /// The address the update server listens on
+    pub update_address: std::net::IpAddr,
+    /// The port the update server listens on
+    pub update_port: u16,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            game_address: std::net::Ipv4Addr::UNSPECIFIED.into(),
+            game_port: 2000,
+            update_enabled: true,
+            update_address: std::net::Ipv4Addr::UNSPECIFIED.into(),
+            update_port: 2003,
+        }
+    }
+}
+
+impl NetworkConfig {
+    /// The socket address for the game server
+    pub fn game_socket(&self) -> std::net::SocketAddr {
+        std::net::SocketAddr::new(self.game_address, self.game_port)
+    }
+
+    /// The socket address for the update server
+    pub fn update_socket(&self) -> std::net::SocketAddr {
+        std::net::SocketAddr::new(self.update_address, self.update_port)
+    }
+}
+
+/// What happens when a client sends packets faster than its rate limit allows
+#[derive(Copy, Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitAction {
+    /// Log a warning, but still process the packet
+    Warn,
+    /// Log a warning and drop the packet
+    Drop,
+    /// Disconnect the client
+    Disconnect,
+}
+
+/// The settings for a single token bucket
+#[derive(Copy, Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct BucketConfig {
+    /// The number of packets per second that are refilled into the bucket
+    pub rate: f64,
+    /// The maximum number of packets that can be sent in a burst
+    pub burst: f64,
+}
+
+/// The per-connection rate limits, one bucket for each category of packet
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Are packets rate limited at all?
+    pub enabled: bool,
+    /// What to do when a client exceeds a limit
+    pub action: RateLimitAction,
+    /// The limit for all types of chat
+    pub chat: BucketConfig,
+    /// The limit for moving and turning
+    pub movement: BucketConfig,
+    /// The limit for attacking an object
+    pub attack: BucketConfig,
+    /// The limit for using items
+    pub item: BucketConfig,
+    /// The limit for all other packets
+    pub other: BucketConfig,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            action: RateLimitAction::Drop,
+            chat: BucketConfig {
+                rate: 2.0,
+                burst: 5.0,
+            },
+            movement: BucketConfig {
+                rate: 10.0,
+                burst: 20.0,
+            },
+            attack: BucketConfig {
+                rate: 5.0,
+                burst: 10.0,
+            },
+            item: BucketConfig {
+                rate: 5.0,
+                burst: 10.0,
+            },
+            other: BucketConfig {
+                rate: 20.0,
+                burst: 40.0,
+            },
+        }
+    }
+}
+
+/// How long a connection may stay silent in each phase before it is disconnected, in seconds. Zero disables the timeout.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct IdleTimeoutConfig {
+    /// Before the client has sent its version
+    pub handshake: u64,
+    /// Before the client has logged in
+    pub login: u64,
+    /// While the client is at character select
+    pub character_select: u64,
+    /// While the client is playing a character
+    pub in_game: u64,
+}
+
+impl Default for IdleTimeoutConfig {
+    fn default() -> Self {
+        Self {
+            handshake: 30,
+            login: 120,
+            character_select: 600,
+            in_game: 300,
+        }
+    }
+}
+
+/// The limits on failed logins, counted separately for each account and each ip address
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct LoginThrottleConfig {
+    /// The number of failed logins before an account or ip address is locked out, 0 for no lockout
+    pub max_failures: u32,
+    /// How long without a failure before the failures are forgotten, in seconds
+    pub failure_window: u64,
+    /// How long a lockout lasts, in seconds
+    pub lockout: u64,
+    /// The wait after the first failure in milliseconds, doubled after every further failure. 0 disables the wait.
+    pub backoff: u64,
+    /// The longest wait between failures, in milliseconds
+    pub max_backoff: u64,
+}
+
+impl Default for LoginThrottleConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            failure_window: 900,
+            lockout: 900,
+            backoff: 1000,
+            max_backoff: 60000,
+        }
+    }
+}
+
+impl LoginThrottleConfig {
+    /// Construct a failure limiter with these settings
+    pub fn limiter<K: std::hash::Hash + Eq>(&self) -> crate::failure_limit::FailureLimiter<K> {
+        crate::failure_limit::FailureLimiter::new(
+            self.max_failures,
+            std::time::Duration::from_secs(self.failure_window),
+        )
+        .with_lockout(std::time::Duration::from_secs(self.lockout))
+        .with_backoff(
+            std::time::Duration::from_millis(self.backoff),
+            std::time::Duration::from_millis(self.max_backoff),
+        )
+    }
+}
+
 /// The main server configuration
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct ServerConfiguration {
     /// Should new accounts be automatically created if they don't exist? This is probably best for test servers only.
     pub automatic_account_creation: bool,
-    /// The salt for account creation
+    /// The global salt of the legacy password hashes. New hashes have their own salt, this is only used to check old passwords.
     pub account_creation_salt: String,
+    /// When set, the packets of every client connection are recorded to a capture file in this directory
+    #[serde(default)]
+    pub capture_directory: Option<String>,
+    /// The largest frame a client may send, in bytes including the two length bytes. Larger frames disconnect the client.
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+    /// The limits on how fast each client may send packets
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// How long silent connections are kept open
+    #[serde(default)]
+    pub idle_timeout: IdleTimeoutConfig,
+    /// The maximum number of simultaneous connections from a single ip address, 0 for no limit
+    #[serde(default = "default_max_connections_per_ip")]
+    pub max_connections_per_ip: usize,
+    /// The maximum number of simultaneous logins to a single account, 0 for no limit
+    #[serde(default = "default_max_sessions_per_account")]
+    pub max_sessions_per_account: usize,
+    /// The number of failed password changes from an address or for an account before further attempts are refused, 0 for no limit
+    #[serde(default = "default_password_change_max_failures")]
+    pub password_change_max_failures: u32,
+    /// How long failed password changes are remembered, in seconds
+    #[serde(default = "default_password_change_window")]
+    pub password_change_window: u64,
+    /// When an account that is already online logs in again, disconnect the older session instead of refusing the new login
+    #[serde(default = "default_kick_duplicate_logins")]
+    pub kick_duplicate_logins: bool,
+    /// The limits on failed logins
+    #[serde(default)]
+    pub login_throttle: LoginThrottleConfig,
+    /// How often every online character is saved, in seconds. 0 disables the autosave.
+    #[serde(default = "default_autosave_interval")]
+    pub autosave_interval: u64,
+    /// How long characters that need a waiting period stay marked for deletion before they are deleted.
+    /// Given in seconds, at most [MAX_CHARACTER_DELETE_WAIT].
+    #[serde(
+        default = "default_character_delete_wait",
+        deserialize_with = "deserialize_character_delete_wait",
+        serialize_with = "serialize_character_delete_wait"
+    )]
+    pub character_delete_wait: chrono::Duration,
+    /// A file listing banned ip addresses and ranges, reloaded whenever it changes
+    #[serde(default)]
+    pub ip_ban_file: Option<String>,
+}
+
+/// The default for the maximum frame size
+fn default_max_frame_size() -> usize {
+    common::packet::DEFAULT_MAX_FRAME_SIZE
+}
+
+/// The default for the maximum connections per ip address
+fn default_max_connections_per_ip() -> usize {
+    5
+}
+
+/// The default for the maximum sessions per account
+fn default_max_sessions_per_account() -> usize {
+    1
+}
+
+/// The default for kicking duplicate logins
+fn default_kick_duplicate_logins() -> bool {
+    true
+}
+
+/// The default for the autosave interval
+fn default_autosave_interval() -> u64 {
+    300
+}
+
+/// The default for the character delete wait, one week
+fn default_character_delete_wait() -> chrono::Duration {
+    chrono::Duration::days(7)
+}
+
+/// The longest allowed character delete wait, ten years
+pub const MAX_CHARACTER_DELETE_WAIT: u64 = 10 * 365 * 24 * 60 * 60;
+
+/// Read the character delete wait in seconds, refusing waits longer than [MAX_CHARACTER_DELETE_WAIT]
+fn deserialize_character_delete_wait<'de, D: serde::Deserializer<'de>>(
+    d: D,
+) -> Result<chrono::Duration, D::Error> {
+    let wait = <u64 as serde::Deserialize>::deserialize(d)?;
+    if wait > MAX_CHARACTER_DELETE_WAIT {
+        return Err(serde::de::Error::custom(format!(
+            "character_delete_wait of {} seconds is longer than the maximum of {}",
+            wait, MAX_CHARACTER_DELETE_WAIT
+        )));
+    }
+    //the maximum is far below i64::MAX seconds, so this does not wrap
+    Ok(chrono::Duration::seconds(wait as i64))
+}
+
+/// Write the character delete wait in seconds
+fn serialize_character_delete_wait<S: serde::Serializer>(
+    wait: &chrono::Duration,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    s.serialize_i64(wait.num_seconds())
+}
+
+/// The default for the maximum failed password changes
+fn default_password_change_max_failures() -> u32 {
+    5
+}
+
+/// The default for how long failed password changes are remembered
+fn default_password_change_window() -> u64 {
+    300
 }
 
 impl ServerConfiguration {
@@ -48,6 +350,9 @@ pub struct MainConfiguration {
     pub db: MysqlConfig,
     /// The main server configuration
     pub config: ServerConfiguration,
+    /// The network configuration
+    #[serde(default)]
+    pub network: NetworkConfig,
 }
 
 /// Load the configuration file from disk
@@ -70,3 +375,28 @@ pub fn open_mysql(settings: &MainConfiguration) -> Result<mysql::Pool, mysql::Er
     let mysql_opt = mysql::Opts::from_url(mysql_conn_s.as_str())?;
     mysql::Pool::new(mysql_opt)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that a character delete wait that is too long is refused when the configuration is read
+    #[test]
+    fn character_delete_wait() {
+        let config = |wait: u64| {
+            toml::from_str::<ServerConfiguration>(&format!(
+                "automatic_account_creation = true\naccount_creation_salt = \"\"\ncharacter_delete_wait = {}",
+                wait
+            ))
+        };
+        assert_eq!(
+            config(MAX_CHARACTER_DELETE_WAIT)
+                .unwrap()
+                .character_delete_wait
+                .num_seconds(),
+            MAX_CHARACTER_DELETE_WAIT as i64
+        );
+        assert!(config(MAX_CHARACTER_DELETE_WAIT + 1).is_err());
+        assert!(config(u64::MAX / 2).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+//! Limits how many times something can fail in a period of time, used to slow down password guessing
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// The failures recorded for a single key
+#[derive(Debug)]
+struct Failures {
+    /// The number of failures that have not been forgotten yet
+    count: u32,
+    /// When the last failure happened
+    last: Instant,
+    /// The key is locked out until this time
+    locked_until: Option<Instant>,
+}
+
+/// Counts failures for each key. After each failure the key must wait before trying again, the wait doubling with every failure.
+/// Once a key reaches the maximum number of failures it is locked out for a while.
+/// Failures are forgotten when a key has not failed for the length of the window.
+#[derive(Debug)]
+pub struct FailureLimiter<K: Hash + Eq> {
+    /// The failures of each key
+    failures: HashMap<K, Failures>,
+    /// The number of failures that lock out a key, 0 for no limit
+    max_failures: u32,
+    /// How long failures are remembered
+    window: Duration,
+    /// How long a key is locked out for
+    lockout: Duration,
+    /// The wait after the first failure, zero for no waiting
+    backoff: Duration,
+    /// The longest wait between failures
+    max_backoff: Duration,
+}
+
+impl<K: Hash + Eq> FailureLimiter<K> {
+    /// Construct a new limiter without backoff, that locks a key out for the length of the window
+    pub fn new(max_failures: u32, window: Duration) -> Self {
+        Self {
+            failures: HashMap::new(),
+            max_failures,
+            window,
+            lockout: window,
+            backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+
+    /// Set how long a key is locked out for
+    pub fn with_lockout(mut self, lockout: Duration) -> Self {
+        self.lockout = lockout;
+        self
+    }
+
+    /// Set the wait after the first failure, and the longest wait
+    pub fn with_backoff(mut self, backoff: Duration, max_backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// The wait required after the given number of failures
+    fn backoff_for(&self, count: u32) -> Duration {
+        if count == 0 {
+            return Duration::ZERO;
+        }
+        let factor = 1u32 << (count - 1).min(16);
+        self.backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+
+    /// How much longer the key is blocked for, None when it is not blocked
+    pub fn blocked_for(&mut self, key: &K, now: Instant) -> Option<Duration> {
+        let f = self.failures.get(key)?;
+        if let Some(l) = f.locked_until {
+            if now < l {
+                return Some(l - now);
+            }
+            self.failures.remove(key);
+            return None;
+        }
+        if now.saturating_duration_since(f.last) >= self.window {
+            self.failures.remove(key);
+            return None;
+        }
+        let ready = f.last + self.backoff_for(f.count);
+        if now < ready {
+            Some(ready - now)
+        } else {
+            None
+        }
+    }
+
+    /// Is the key currently blocked?
+    pub fn is_blocked(&mut self, key: &K, now: Instant) -> bool {
+        self.blocked_for(key, now).is_some()
+    }
+
+    /// Record a failure for the key. Returns true when this failure locked out the key.
+    pub fn record_failure(&mut self, key: K, now: Instant) -> bool {
+        let window = self.window;
+        let f = self.failures.entry(key).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        if now.saturating_duration_since(f.last) >= window {
+            f.count = 0;
+        }
+        f.count += 1;
+        f.last = now;
+        let locked =
+            self.max_failures != 0 && f.count >= self.max_failures && f.locked_until.is_none();
+        if locked {
+            f.locked_until = Some(now + self.lockout);
+        }
+        if self.failures.len() > 10000 {
+            let lockout = self.lockout;
+            self.failures
+                .retain(|_, f| now.saturating_duration_since(f.last) < window.max(lockout));
+        }
+        locked
+    }
+
+    /// Forget the failures of a key, after it succeeded
+    pub fn clear(&mut self, key: &K) {
+        self.failures.remove(key);
+    }
+
+    /// Take back the last failure of a key. Attempts are recorded as failures before their result is known,
+    /// this is for the attempts that turned out not to fail. A lockout caused by that failure is lifted.
+    pub fn forgive(&mut self, key: &K) {
+        if let Some(f) = self.failures.get_mut(key) {
+            f.count = f.count.saturating_sub(1);
+            if f.count == 0 {
+                self.failures.remove(key);
+            } else if self.max_failures == 0 || f.count < self.max_failures {
+                f.locked_until = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that a key is blocked after too many failures, until the window ends
+    #[test]
+    fn block_and_expire() {
+        let start = Instant::now();
+        let mut l = FailureLimiter::new(3, Duration::from_secs(60));
+        for _ in 0..2 {
+            assert!(!l.record_failure("a", start));
+        }
+        assert!(!l.is_blocked(&"a", start));
+        assert!(l.record_failure("a", start));
+        assert!(l.is_blocked(&"a", start));
+        assert!(!l.is_blocked(&"b", start));
+        assert!(l.is_blocked(&"a", start + Duration::from_secs(59)));
+        assert!(!l.is_blocked(&"a", start + Duration::from_secs(60)));
+        l.record_failure("b", start);
+        l.clear(&"b");
+        assert!(!l.is_blocked(&"b", start));
+    }
+
+    /// Checks that the wait doubles after each failure, up to the maximum
+    #[test]
+    fn backoff() {
+        let start = Instant::now();
+        let mut l = FailureLimiter::new(10, Duration::from_secs(600))
+            .with_lockout(Duration::from_secs(900))
+            .with_backoff(Duration::from_secs(1), Duration::from_secs(5));
+        let mut now = start;
+        for expected in [1, 2, 4, 5, 5] {
+            l.record_failure("a", now);
+            assert_eq!(
+                l.blocked_for(&"a", now),
+                Some(Duration::from_secs(expected))
+            );
+            now += Duration::from_secs(expected);
+            assert_eq!(l.blocked_for(&"a", now), None);
+        }
+        for _ in 0..4 {
+            l.record_failure("a", now);
+        }
+        assert!(l.record_failure("a", now));
+        assert_eq!(l.blocked_for(&"a", now), Some(Duration::from_secs(900)));
+        assert_eq!(l.blocked_for(&"a", now + Duration::from_secs(900)), None);
+    }
+
+    /// Checks that a forgiven failure does not count and lifts the lockout it caused
+    #[test]
+    fn forgive() {
+        let start = Instant::now();
+        let mut l = FailureLimiter::new(3, Duration::from_secs(60));
+        l.record_failure("a", start);
+        l.record_failure("a", start);
+        assert!(l.record_failure("a", start));
+        l.forgive(&"a");
+        assert!(!l.is_blocked(&"a", start));
+        assert!(l.record_failure("a", start));
+        assert!(l.is_blocked(&"a", start));
+        l.record_failure("b", start);
+        l.forgive(&"b");
+        assert!(l.failures.get(&"b").is_none());
+        l.forgive(&"c");
+        assert!(!l.is_blocked(&"c", start));
+    }
+}
@@ -3,6 +3,7 @@
 
 //! The server for the game
 
+mod replay;
 mod server;
 mod update;
 
@@ -11,18 +12,32 @@ mod server_message;
 mod character;
 mod clients;
 mod config;
+mod failure_limit;
+mod migrate;
+mod storage;
 use config::*;
 mod user;
 mod world;
 use crate::clients::ClientList;
 
 fn main() -> Result<(), String> {
-    tokio::runtime::Builder::new_current_thread()
+    let args: Vec<String> = std::env::args().collect();
+    let runtime = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .thread_stack_size(32 * 1024 * 1024)
         .build()
-        .unwrap()
-        .block_on(smain())
+        .unwrap();
+    match args.get(1).map(|a| a.as_str()) {
+        Some("replay") => {
+            let use_database = args.get(2).map(|a| a == "--use-database").unwrap_or(false);
+            let file = args.get(if use_database { 3 } else { 2 }).ok_or_else(|| {
+                "Usage: rustlin-server replay [--use-database] <capture file>".to_string()
+            })?;
+            runtime.block_on(replay::replay(file.into(), use_database))
+        }
+        Some("migrate") => migrate::run(&args[2..]),
+        _ => runtime.block_on(smain()),
+    }
 }
 
 /// The reasons to shutdown the server
@@ -46,11 +61,7 @@ async fn smain() -> Result<(), String> {
     log::info!("server: Game server is starting");
 
     let settings = load_config().unwrap();
-    let mysql_pool = open_mysql(&settings).unwrap();
-    log::info!("Trying to connect to database");
-    let _mysql_conn = mysql_pool
-        .get_conn()
-        .expect("Failed to connect to mysql server");
+    let storage = storage::open(&settings).expect("Failed to open the storage");
 
     let mut tasks: tokio::task::JoinSet<Result<(), u32>> = tokio::task::JoinSet::new();
 
@@ -69,7 +80,7 @@ async fn smain() -> Result<(), String> {
     let (main_s, main_r) = tokio::sync::mpsc::channel(2000);
 
     let mut world = world::World::new(
-        mysql_pool,
+        storage,
         iscs,
         main_r,
         main_s.clone(),
@@ -77,19 +88,27 @@ async fn smain() -> Result<(), String> {
     )
     .map_err(|e| format!("{:?}", e))?;
     world.spawn_monsters();
+    world.start_autosave();
+    world.start_character_purge();
 
-    std::thread::spawn(move || {
+    let world_s = main_s.clone();
+    let world_thread = std::thread::spawn(move || {
         world.run();
         world.end();
     });
 
-    let mut update_tx = Some(
-        update::setup_update_server(&mut tasks)
-            .await
-            .expect("Failed to setup update server"),
-    );
+    let mut update_tx = if settings.network.update_enabled {
+        Some(
+            update::setup_update_server(&mut tasks, &settings.network)
+                .await
+                .expect("Failed to setup update server"),
+        )
+    } else {
+        log::info!("update: The update server is disabled");
+        None
+    };
     let mut server_tx = Some(
-        server::setup_game_server(&mut tasks, &settings.config, main_s)
+        server::setup_game_server(&mut tasks, &settings.config, &settings.network, main_s)
             .await
             .expect("Failed to setup legacy server"),
     );
@@ -171,6 +190,22 @@ async fn smain() -> Result<(), String> {
     log::info!("Waiting for main tasks to finish");
     tasks.join_all().await;
 
+    log::info!("Saving characters");
+    let _ = world_s
+        .send(world::WorldMessage {
+            data: world::WorldMessageData::Shutdown,
+            sender: None,
+            peer: std::net::SocketAddr::from(([0, 0, 0, 0], 0)),
+        })
+        .await;
+    if tokio::task::spawn_blocking(move || world_thread.join())
+        .await
+        .map(|r| r.is_err())
+        .unwrap_or(true)
+    {
+        log::error!("The world thread did not end cleanly");
+    }
+
     log::info!(
         "server: Server will now close with status: {:?}",
         shutdown_mode
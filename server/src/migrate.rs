@@ -0,0 +1,301 @@
+//! Applies the update scripts in the database directory, recording the applied versions in the schema_version table.
+//! Scripts are named `update_<version>[-<description>].sql`, scripts with a `-pending` suffix are not finished and are skipped.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use mysql::prelude::Queryable;
+
+/// The schema version the server needs, the version of the newest update script that is not pending
+pub const SCHEMA_VERSION: u32 = 70;
+
+/// A single update script
+#[derive(Debug)]
+pub struct Migration {
+    /// The version the script updates the schema to
+    version: u32,
+    /// The file name of the script
+    name: String,
+    /// The full path of the script
+    path: PathBuf,
+}
+
+/// Get the version of an update script from its file name, and whether it is pending
+fn parse_name(file_name: &str) -> Option<(u32, bool)> {
+    let stem = file_name.strip_prefix("update_")?.strip_suffix(".sql")?;
+    let (version, suffix) = stem.split_once('-').unwrap_or((stem, ""));
+    Some((version.parse().ok()?, suffix == "pending"))
+}
+
+/// Find all update scripts in a directory that are not pending, ordered by version
+pub fn find_migrations(dir: &Path) -> Result<Vec<Migration>, String> {
+    let mut migrations = Vec::new();
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        match parse_name(&name) {
+            Some((_, true)) => log::info!("Skipping pending update {}", name),
+            Some((version, false)) => migrations.push(Migration {
+                version,
+                name,
+                path: entry.path(),
+            }),
+            None => {}
+        }
+    }
+    migrations.sort_by_key(|m| m.version);
+    for w in migrations.windows(2) {
+        if w[0].version == w[1].version {
+            return Err(format!(
+                "{} and {} have the same version",
+                w[0].name, w[1].name
+            ));
+        }
+    }
+    Ok(migrations)
+}
+
+/// The state of the splitter while it walks through a script
+#[derive(Clone, Copy, PartialEq)]
+enum SplitState {
+    /// Regular sql
+    Normal,
+    /// Inside a string or quoted name, started by the given quote character
+    Quoted(char),
+    /// Inside a comment that ends at the end of the line
+    LineComment,
+    /// Inside a comment that ends with */
+    BlockComment,
+}
+
+/// Split a script into its statements. Semicolons inside quotes and comments do not end a statement,
+/// and comments are removed except for the /*! ... */ comments that mysql executes.
+pub fn split_statements(sql: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut state = SplitState::Normal;
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match state {
+            SplitState::Normal => match c {
+                '\'' | '"' | '`' => {
+                    current.push(c);
+                    state = SplitState::Quoted(c);
+                }
+                '#' => state = SplitState::LineComment,
+                '-' if chars.peek() == Some(&'-') => {
+                    chars.next();
+                    match chars.peek() {
+                        None => {}
+                        Some(n) if n.is_whitespace() => state = SplitState::LineComment,
+                        Some(_) => current.push_str("--"),
+                    }
+                }
+                '/' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'!') {
+                        current.push_str("/*");
+                    } else {
+                        state = SplitState::BlockComment;
+                    }
+                }
+                ';' => {
+                    let s = current.trim();
+                    if !s.is_empty() {
+                        statements.push(s.to_string());
+                    }
+                    current.clear();
+                }
+                _ => current.push(c),
+            },
+            SplitState::Quoted(q) => {
+                current.push(c);
+                if c == '\\' {
+                    if let Some(n) = chars.next() {
+                        current.push(n);
+                    }
+                } else if c == q {
+                    if chars.peek() == Some(&q) {
+                        current.push(q);
+                        chars.next();
+                    } else {
+                        state = SplitState::Normal;
+                    }
+                }
+            }
+            SplitState::LineComment => {
+                if c == '\n' {
+                    current.push(c);
+                    state = SplitState::Normal;
+                }
+            }
+            SplitState::BlockComment => {
+                if c == '*' && chars.peek() == Some(&'/') {
+                    chars.next();
+                    current.push(' ');
+                    state = SplitState::Normal;
+                }
+            }
+        }
+    }
+    let s = current.trim();
+    if !s.is_empty() {
+        statements.push(s.to_string());
+    }
+    statements
+}
+
+/// Create the schema_version table if it does not exist
+fn create_version_table(conn: &mut mysql::PooledConn) -> Result<(), mysql::Error> {
+    conn.query_drop("CREATE TABLE IF NOT EXISTS schema_version (version int unsigned NOT NULL PRIMARY KEY, name varchar(255) NOT NULL, applied datetime NOT NULL)")
+}
+
+/// Get the versions that have been applied
+fn applied_versions(conn: &mut mysql::PooledConn) -> Result<HashSet<u32>, mysql::Error> {
+    let v: Vec<u32> = conn.query("SELECT version FROM schema_version")?;
+    Ok(v.into_iter().collect())
+}
+
+/// Get the newest schema version that has been applied, 0 when no updates have been recorded
+pub fn current_version(conn: &mut mysql::PooledConn) -> Result<u32, mysql::Error> {
+    let exists: Option<u32> = conn.query_first("SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = DATABASE() AND table_name = 'schema_version'")?;
+    if exists.unwrap_or(0) == 0 {
+        return Ok(0);
+    }
+    let v: Option<Option<u32>> = conn.query_first("SELECT MAX(version) FROM schema_version")?;
+    Ok(v.flatten().unwrap_or(0))
+}
+
+/// Apply a single update script and record it, in a transaction.
+/// Mysql commits schema changes such as ALTER TABLE immediately, so only the data changes of a failed script are rolled back.
+fn apply(conn: &mut mysql::PooledConn, m: &Migration) -> Result<(), String> {
+    let sql = std::fs::read_to_string(&m.path)
+        .map_err(|e| format!("Failed to read {}: {}", m.name, e))?;
+    let mut t = conn
+        .start_transaction(mysql::TxOpts::default())
+        .map_err(|e| format!("{:?}", e))?;
+    for s in split_statements(&sql) {
+        t.query_drop(&s)
+            .map_err(|e| format!("{} failed: {}\n{}", m.name, e, s))?;
+    }
+    t.exec_drop(
+        "INSERT INTO schema_version (version, name, applied) VALUES (?, ?, NOW())",
+        (m.version, &m.name),
+    )
+    .map_err(|e| format!("{:?}", e))?;
+    t.commit().map_err(|e| format!("{:?}", e))
+}
+
+/// Apply every update script that has not been applied yet, in order. Returns the number of scripts applied.
+pub fn migrate(conn: &mut mysql::PooledConn, dir: &Path) -> Result<usize, String> {
+    create_version_table(conn).map_err(|e| format!("{:?}", e))?;
+    let applied = applied_versions(conn).map_err(|e| format!("{:?}", e))?;
+    let mut count = 0;
+    for m in find_migrations(dir)? {
+        if applied.contains(&m.version) {
+            continue;
+        }
+        log::info!("Applying {}", m.name);
+        apply(conn, &m)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Record every update script up to a version as applied without running it, for databases that were updated by hand
+pub fn baseline(conn: &mut mysql::PooledConn, dir: &Path, version: u32) -> Result<(), String> {
+    create_version_table(conn).map_err(|e| format!("{:?}", e))?;
+    for m in find_migrations(dir)?
+        .iter()
+        .filter(|m| m.version <= version)
+    {
+        conn.exec_drop(
+            "INSERT IGNORE INTO schema_version (version, name, applied) VALUES (?, ?, NOW())",
+            (m.version, &m.name),
+        )
+        .map_err(|e| format!("{:?}", e))?;
+    }
+    Ok(())
+}
+
+/// Run the migrate command, the arguments are those after `migrate`
+pub fn run(args: &[String]) -> Result<(), String> {
+    simple_logger::init_with_level(log::Level::Info).unwrap();
+    let usage = "Usage: rustlin-server migrate [database directory]\n       rustlin-server migrate baseline <version> [database directory]";
+    let (baseline_version, dir) = match args.first().map(|a| a.as_str()) {
+        Some("baseline") => {
+            let v = args
+                .get(1)
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| usage.to_string())?;
+            (Some(v), args.get(2))
+        }
+        _ => (None, args.first()),
+    };
+    let dir = PathBuf::from(dir.map(|d| d.as_str()).unwrap_or("./database"));
+    let settings = crate::load_config().map_err(|e| format!("{:?}", e))?;
+    if settings.db.backend != crate::config::StorageBackend::Mysql {
+        return Err("Only mysql storage has a schema to update".to_string());
+    }
+    let pool = crate::open_mysql(&settings).map_err(|e| format!("{:?}", e))?;
+    let mut conn = pool.get_conn().map_err(|e| format!("{:?}", e))?;
+    if let Some(v) = baseline_version {
+        baseline(&mut conn, &dir, v)?;
+        log::info!("Recorded updates up to version {} as applied", v);
+    } else {
+        let count = migrate(&mut conn, &dir)?;
+        log::info!("Applied {} updates", count);
+    }
+    let version = current_version(&mut conn).map_err(|e| format!("{:?}", e))?;
+    log::info!(
+        "The schema is at version {}, the server needs version {}",
+        version,
+        SCHEMA_VERSION
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that quotes and comments are handled when splitting a script
+    #[test]
+    fn split() {
+        let sql = "-- update 1\n# a comment; with a semicolon\nINSERT INTO t VALUES ('a;b', 'it''s', \"x\\\";\");\n/* block; comment */UPDATE t SET `odd;name`='Gunter`s Cave';\n/*!40101 SET NAMES utf8 */;\nSELECT 1--not a comment\n;  \n";
+        assert_eq!(
+            split_statements(sql),
+            vec![
+                "INSERT INTO t VALUES ('a;b', 'it''s', \"x\\\";\")",
+                "UPDATE t SET `odd;name`='Gunter`s Cave'",
+                "/*!40101 SET NAMES utf8 */",
+                "SELECT 1--not a comment",
+            ]
+        );
+        assert!(split_statements("-- nothing\n  ;\n").is_empty());
+    }
+
+    /// Checks parsing the versions of script names
+    #[test]
+    fn names() {
+        assert_eq!(parse_name("update_064.sql"), Some((64, false)));
+        assert_eq!(parse_name("update_065-pending.sql"), Some((65, true)));
+        assert_eq!(parse_name("update_066-fixes.sql"), Some((66, false)));
+        assert_eq!(parse_name("update_abc.sql"), None);
+        assert_eq!(parse_name("l1jdb_m7.sql"), None);
+    }
+
+    /// Checks that the scripts shipped with the server match the version the server needs
+    #[test]
+    fn shipped_scripts() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../database");
+        let migrations = find_migrations(&dir).unwrap();
+        assert_eq!(migrations.last().map(|m| m.version), Some(SCHEMA_VERSION));
+        assert!(migrations.iter().all(|m| m.version != 65));
+        for m in &migrations {
+            let sql = std::fs::read_to_string(&m.path).unwrap();
+            assert!(!split_statements(&sql).is_empty(), "{}", m.name);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Replays a packet capture against the game world
+
+use crate::load_config;
+use crate::world::{WorldMessage, WorldMessageData, WorldResponse};
+use common::capture::{CaptureReader, Direction};
+use common::packet::{ClientPacket, Packet};
+
+/// Feed the client packets from a capture file into a freshly started world, keeping the original timing.
+/// Everything the world sends back is logged.
+/// The world uses in-memory storage, unless use_database is set to use the configured storage instead.
+pub async fn replay(path: std::path::PathBuf, use_database: bool) -> Result<(), String> {
+    simple_logger::init_with_level(log::Level::Info).unwrap();
+    log::info!("replay: Replaying {}", path.display());
+
+    let f = std::fs::File::open(&path).map_err(|e| format!("{:?}", e))?;
+    let capture = CaptureReader::new(std::io::BufReader::new(f)).map_err(|e| format!("{:?}", e))?;
+
+    let settings = load_config().map_err(|e| format!("{:?}", e))?;
+    let storage: std::sync::Arc<dyn crate::storage::Storage> = if use_database {
+        log::warn!("replay: Using the configured storage, the replay changes it");
+        crate::storage::open(&settings).map_err(|e| format!("{:?}", e))?
+    } else {
+        std::sync::Arc::new(crate::storage::memory::MemoryStorage::new())
+    };
+
+    let (iscs, _iscr) = tokio::sync::mpsc::channel(5);
+    let (main_s, main_r) = tokio::sync::mpsc::channel(2000);
+    let mut world = crate::world::World::new(
+        storage,
+        iscs,
+        main_r,
+        main_s.clone(),
+        settings.config.clone(),
+    )
+    .map_err(|e| format!("{:?}", e))?;
+    world.spawn_monsters();
+
+    std::thread::spawn(move || {
+        world.run();
+        world.end();
+    });
+
+    let peer: std::net::SocketAddr = ([127, 0, 0, 1], 0).into();
+    let (t_s, mut t_r) = tokio::sync::mpsc::channel(100);
+    main_s
+        .send(WorldMessage {
+            data: WorldMessageData::RegisterSender(t_s),
+            sender: None,
+            peer,
+        })
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+    let id = loop {
+        match t_r.recv().await {
+            Some(WorldResponse::NewClientId(id)) => break id,
+            Some(r) => log::info!("replay: world sent {:?}", r),
+            None => return Err("The world closed the connection".to_string()),
+        }
+    };
+
+    let mut opcodes = &common::opcode::CLASSIC;
+    let start = tokio::time::Instant::now();
+    for entry in capture {
+        let entry = entry.map_err(|e| format!("{:?}", e))?;
+        if entry.direction != Direction::ClientToServer {
+            continue;
+        }
+        let deadline = start + entry.timestamp;
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => break,
+                Some(r) = t_r.recv() => log::info!("replay: world sent {:?}", r),
+            }
+        }
+        match Packet::raw_packet(entry.data).convert_with(opcodes) {
+            Ok(p) => {
+                if let ClientPacket::Version(_, _, _, version) = &p {
+                    opcodes = common::opcode::OpcodeTable::for_version(*version);
+                }
+                log::info!("replay: sending {:?}", p);
+                main_s
+                    .send(WorldMessage {
+                        data: WorldMessageData::ClientPacket(p),
+                        sender: Some(id),
+                        peer,
+                    })
+                    .await
+                    .map_err(|e| format!("{:?}", e))?;
+            }
+            Err(e) => log::warn!("replay: skipping undecodable packet {:?}", e),
+        }
+    }
+
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(1);
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => break,
+            Some(r) = t_r.recv() => log::info!("replay: world sent {:?}", r),
+        }
+    }
+    let _ = main_s
+        .send(WorldMessage {
+            data: WorldMessageData::UnregisterClient(id),
+            sender: Some(id),
+            peer,
+        })
+        .await;
+    log::info!("replay: finished");
+    Ok(())
+}
@@ -7,6 +7,8 @@ use std::sync::Arc;
 use tokio::net::TcpListener;
 
 pub mod client;
+pub mod ip_ban;
+pub mod rate_limit;
 use crate::server::client::*;
 use crate::world::WorldMessage;
 
@@ -21,10 +23,14 @@ pub enum ClientError {
     IoError(std::io::Error),
     /// A mysql error occurred
     MysqlError(mysql::Error),
+    /// A storage error occurred
+    StorageError(crate::storage::StorageError),
     /// The user selected an invalid character
     InvalidCharSelection,
     /// The character already exists when trying to create a new character
     CharacterExists,
+    /// The client sent packets faster than allowed
+    RateLimited(rate_limit::PacketCategory),
 }
 
 impl From<PacketError> for ClientError {
@@ -33,6 +39,12 @@ impl From<PacketError> for ClientError {
     }
 }
 
+impl From<PacketDecodeError> for ClientError {
+    fn from(p: PacketDecodeError) -> ClientError {
+        ClientError::PacketError(p.into())
+    }
+}
+
 impl From<std::io::Error> for ClientError {
     fn from(a: std::io::Error) -> ClientError {
         ClientError::IoError(a)
@@ -45,19 +57,33 @@ impl From<mysql::Error> for ClientError {
     }
 }
 
+impl From<crate::storage::StorageError> for ClientError {
+    fn from(a: crate::storage::StorageError) -> ClientError {
+        ClientError::StorageError(a)
+    }
+}
+
 /// Process a single client for the world in the server
 async fn process_client(
     socket: tokio::net::TcpStream,
     world_sender: tokio::sync::mpsc::Sender<crate::world::WorldMessage>,
     end_rx: tokio::sync::mpsc::Receiver<u32>,
+    config: Arc<crate::ServerConfiguration>,
 ) -> Result<u8, ClientError> {
     log::info!("Processing a client");
     let (reader, writer) = socket.into_split();
-    let packet_writer = ServerPacketSender::new(writer);
+    let mut packet_writer = ServerPacketSender::new(writer);
 
     let (t_s, t_r) = tokio::sync::mpsc::channel(100);
     let peer = reader.peer_addr()?;
-    let mut c = Client::new(packet_writer, world_sender, peer);
+    let recorder = config
+        .capture_directory
+        .as_ref()
+        .and_then(|dir| start_capture(dir, peer));
+    if let Some(r) = &recorder {
+        packet_writer.set_recorder(r.clone());
+    }
+    let mut c = Client::new(packet_writer, world_sender, peer, recorder, config);
     match c.event_loop(reader, t_r, t_s, end_rx).await {
         Ok(_) => {
             c.end().await;
@@ -72,6 +98,70 @@ async fn process_client(
     Ok(0)
 }
 
+/// Create a capture file for a client connection in the given directory
+fn start_capture(dir: &str, peer: SocketAddr) -> Option<common::capture::Recorder> {
+    let name = format!(
+        "{}-{}.cap",
+        chrono::Local::now().format("%Y%m%d-%H%M%S"),
+        peer.to_string().replace([':', '[', ']'], "_")
+    );
+    let path = std::path::Path::new(dir).join(name);
+    if let Err(e) = std::fs::create_dir_all(dir) {
+        log::error!("Failed to create capture directory {}: {:?}", dir, e);
+        return None;
+    }
+    match common::capture::Recorder::create(&path) {
+        Ok(r) => {
+            log::info!("Recording client {} to {}", peer, path.display());
+            Some(r)
+        }
+        Err(e) => {
+            log::error!("Failed to create capture {}: {:?}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Counts the connections from a single ip address, decrementing the count when dropped
+struct ConnectionGuard {
+    /// The connection counts of all ip addresses
+    counts: Arc<std::sync::Mutex<HashMap<std::net::IpAddr, usize>>>,
+    /// The address of the connection
+    ip: std::net::IpAddr,
+}
+
+impl ConnectionGuard {
+    /// Count a new connection, returns None when the address already has the maximum number of connections
+    fn new(
+        counts: &Arc<std::sync::Mutex<HashMap<std::net::IpAddr, usize>>>,
+        ip: std::net::IpAddr,
+        max: usize,
+    ) -> Option<Self> {
+        let mut c = counts.lock().unwrap();
+        let count = c.entry(ip).or_insert(0);
+        if max != 0 && *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(Self {
+            counts: counts.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut c = self.counts.lock().unwrap();
+        if let Some(count) = c.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                c.remove(&self.ip);
+            }
+        }
+    }
+}
+
 /// The main struct for the game server
 struct GameServer {
     /// Used to accept new connections from game clients
@@ -84,6 +174,10 @@ struct GameServer {
     kill: Arc<tokio::sync::Mutex<HashMap<SocketAddr, tokio::sync::mpsc::Sender<u32>>>>,
     /// task list of all clients
     clients: Option<Vec<tokio::task::JoinHandle<()>>>,
+    /// The banned ip addresses, kept up to date by a background task
+    bans: tokio::sync::watch::Receiver<ip_ban::IpBanList>,
+    /// The number of connections from each ip address
+    connections: Arc<std::sync::Mutex<HashMap<std::net::IpAddr, usize>>>,
 }
 
 impl Drop for GameServer {
@@ -114,23 +208,37 @@ impl GameServer {
         loop {
             tokio::select! {
                 Ok((socket, addr)) = self.listener.accept() => {
+                    let ip = addr.ip().to_canonical();
+                    if self.bans.borrow().is_banned(&ip) {
+                        log::warn!("Refusing a client from banned address {}", addr);
+                        continue;
+                    }
+                    let guard = match ConnectionGuard::new(&self.connections, ip, self.config.max_connections_per_ip) {
+                        Some(g) => g,
+                        None => {
+                            log::warn!("Refusing a client from {}, too many connections", addr);
+                            continue;
+                        }
+                    };
                     log::info!("Received a client from {}", addr);
                     let sender2 = sender.clone();
                     let (kill_s, kill_r) = tokio::sync::mpsc::channel(100);
                     let kills2 = self.kill.clone();
+                    let config = self.config.clone();
                     if let Some(c) = &mut self.clients {
                         let d = tokio::task::spawn(async move {
                             {
                                 let mut k = kills2.lock().await;
                                 k.insert(addr, kill_s);
                             }
-                            if let Err(e) = process_client(socket, sender2, kill_r).await {
+                            if let Err(e) = process_client(socket, sender2, kill_r, config).await {
                                 log::warn!("Client {} errored {:?}", addr, e);
                             }
                             {
                                 let mut k = kills2.lock().await;
                                 k.remove(&addr);
                             }
+                            drop(guard);
                             log::info!("Exiting client task");
                         });
                         c.push(d);
@@ -150,13 +258,16 @@ impl GameServer {
 pub async fn setup_game_server(
     tasks: &mut tokio::task::JoinSet<Result<(), u32>>,
     config: &crate::ServerConfiguration,
+    network: &crate::NetworkConfig,
     sender: tokio::sync::mpsc::Sender<WorldMessage>,
 ) -> Result<tokio::sync::oneshot::Sender<u32>, Box<dyn Error>> {
-    log::info!("server: Starting the game server");
+    let addr = network.game_socket();
+    log::info!("server: Starting the game server on {}", addr);
     let (update_tx, update_rx) = tokio::sync::oneshot::channel::<u32>();
-    let update_listener = TcpListener::bind("0.0.0.0:2000").await?;
+    let update_listener = TcpListener::bind(addr).await?;
 
     let config = std::sync::Arc::new(config.clone());
+    let bans = ip_ban::watch(config.ip_ban_file.as_ref().map(|f| f.into()));
 
     let mut server = GameServer {
         listener: update_listener,
@@ -164,6 +275,8 @@ pub async fn setup_game_server(
         update_rx,
         clients: Some(Vec::new()),
         kill: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+        bans,
+        connections: Arc::new(std::sync::Mutex::new(HashMap::new())),
     };
     tasks.spawn(async move {
         let e = server.run(sender).await;
@@ -1,5 +1,7 @@
 //! Holds code for game clients
 
+use crate::config::RateLimitAction;
+use crate::server::rate_limit::{PacketCategory, RateLimiter};
 use crate::server::ClientError;
 use crate::user::*;
 use crate::world::{ObjectRef, WorldMessage, WorldResponse};
@@ -8,6 +10,36 @@ use common::packet::*;
 use futures::FutureExt;
 use rand::Rng;
 
+/// The phases of a game connection, each with its own idle timeout
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConnectionPhase {
+    /// The client has not sent its version yet
+    Handshake,
+    /// The client has not logged in yet
+    Login,
+    /// The client is at character select
+    CharacterSelect,
+    /// The client is playing a character
+    InGame,
+}
+
+impl ConnectionPhase {
+    /// The idle timeout for the phase, None when there is no timeout
+    fn timeout(&self, config: &crate::config::IdleTimeoutConfig) -> Option<std::time::Duration> {
+        let secs = match self {
+            Self::Handshake => config.handshake,
+            Self::Login => config.login,
+            Self::CharacterSelect => config.character_select,
+            Self::InGame => config.in_game,
+        };
+        if secs == 0 {
+            None
+        } else {
+            Some(std::time::Duration::from_secs(secs))
+        }
+    }
+}
+
 /// A game client for a game server
 pub struct Client {
     /// Used to send packets to the server
@@ -18,6 +50,16 @@ pub struct Client {
     world_sender: tokio::sync::mpsc::Sender<WorldMessage>,
     /// The remote peer
     peer: std::net::SocketAddr,
+    /// Records the packets of the connection, when capturing is enabled
+    recorder: Option<common::capture::Recorder>,
+    /// The opcodes used by the game client, determined by the version it reports
+    opcodes: &'static common::opcode::OpcodeTable,
+    /// The server configuration
+    config: std::sync::Arc<crate::ServerConfiguration>,
+    /// Limits how fast the client can send packets
+    rate_limiter: RateLimiter,
+    /// The current phase of the connection
+    phase: ConnectionPhase,
 }
 
 impl Drop for Client {
@@ -32,19 +74,39 @@ impl Client {
         packet_writer: ServerPacketSender,
         world_sender: tokio::sync::mpsc::Sender<WorldMessage>,
         peer: std::net::SocketAddr,
+        recorder: Option<common::capture::Recorder>,
+        config: std::sync::Arc<crate::ServerConfiguration>,
     ) -> Self {
         Self {
             packet_writer,
             world_sender,
             id: None,
             peer,
+            recorder,
+            opcodes: &common::opcode::CLASSIC,
+            rate_limiter: RateLimiter::new(&config.rate_limit, std::time::Instant::now()),
+            phase: ConnectionPhase::Handshake,
+            config,
         }
     }
 
     pub async fn end(&mut self) {
         log::info!("Running async end on client");
+        let violations = self.rate_limiter.total_violations();
+        if violations > 0 {
+            log::warn!(
+                "Client {} exceeded its rate limits {} times",
+                self.peer,
+                violations
+            );
+        }
         let _ = self.packet_writer.queue_packet(ServerPacket::Disconnect);
-        self.packet_writer.send_all_current_packets(None).await;
+        //a half-open connection may never accept the packet
+        let _ = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            self.packet_writer.send_all_current_packets(None),
+        )
+        .await;
         if let Some(id) = self.id {
             self.world_sender
                 .send(WorldMessage {
@@ -63,9 +125,66 @@ impl Client {
         }
     }
 
+    /// Track the phase of the connection from the packets the world sends to the client
+    fn update_phase(&mut self, p: &ServerPacket) {
+        match p {
+            ServerPacket::LoginResult {
+                code: LoginResult::Ok,
+            } => self.phase = ConnectionPhase::CharacterSelect,
+            ServerPacket::BackToCharacterSelect => self.phase = ConnectionPhase::CharacterSelect,
+            ServerPacket::StartGame(_) => self.phase = ConnectionPhase::InGame,
+            _ => {}
+        }
+    }
+
     /// Process a single packet from the game client
     pub async fn process_packet(&mut self, p: Packet) -> Result<(), ClientError> {
-        let c = p.convert();
+        let c = match p.convert_with(self.opcodes) {
+            Ok(c) => c,
+            Err(e @ PacketDecodeError::UnknownSubtype { .. }) => {
+                log::warn!(
+                    "Client {} sent an unsupported packet, dropping it: {:?}",
+                    self.peer,
+                    e
+                );
+                return Ok(());
+            }
+            Err(e) => {
+                log::error!("Client {} sent a malformed packet: {:?}", self.peer, e);
+                return Err(e.into());
+            }
+        };
+        let category = PacketCategory::of(&c);
+        if let Some(action) = self.rate_limiter.check(category, std::time::Instant::now()) {
+            let count = self.rate_limiter.violations(category);
+            if count == 1 || count % 100 == 0 || action == RateLimitAction::Disconnect {
+                log::warn!(
+                    "Client {} exceeded the {:?} rate limit ({} violations), action {:?}",
+                    self.peer,
+                    category,
+                    count,
+                    action
+                );
+            }
+            match action {
+                RateLimitAction::Warn => {}
+                RateLimitAction::Drop => return Ok(()),
+                RateLimitAction::Disconnect => return Err(ClientError::RateLimited(category)),
+            }
+        }
+        if let ClientPacket::Version(_, _, _, version) = &c {
+            if self.phase == ConnectionPhase::Handshake {
+                self.phase = ConnectionPhase::Login;
+            }
+            self.opcodes = common::opcode::OpcodeTable::for_version(*version);
+            self.packet_writer.set_opcode_table(self.opcodes);
+            log::info!(
+                "Client {} reports version {}, using {} opcodes",
+                self.peer,
+                version,
+                self.opcodes.name
+            );
+        }
         self.world_sender
             .send(WorldMessage {
                 data: crate::world::WorldMessageData::ClientPacket(c),
@@ -87,6 +206,10 @@ impl Client {
         let encryption_key: u32 = rand::thread_rng().gen();
         self.packet_writer.set_future_encryption_key(encryption_key);
         let mut packet_reader = ServerPacketReceiver::new(reader, encryption_key);
+        packet_reader.set_max_frame_size(self.config.max_frame_size);
+        if let Some(r) = self.recorder.take() {
+            packet_reader.set_recorder(r);
+        }
 
         self.world_sender
             .send(WorldMessage {
@@ -95,20 +218,39 @@ impl Client {
                 peer: self.peer,
             })
             .await;
+        let mut last_activity = tokio::time::Instant::now();
         loop {
+            let idle_deadline = self
+                .phase
+                .timeout(&self.config.idle_timeout)
+                .map(|t| last_activity + t);
+            let idle = async {
+                match idle_deadline {
+                    Some(d) => tokio::time::sleep_until(d).await,
+                    None => futures::future::pending().await,
+                }
+            };
             futures::select! {
                 packet = packet_reader.read_packet().fuse() => {
                     let p = packet?;
+                    last_activity = tokio::time::Instant::now();
                     log::info!("Process packet {:?}", p);
                     self.process_packet(p).await?;
+                    packet_reader.set_opcode_table(self.opcodes);
                     self.packet_writer.send_all_current_packets(Some(&mut packet_reader)).await?;
                 }
                 msg = receiver.recv().fuse() => {
                     let p = msg.unwrap();
                     match p {
                         WorldResponse::ServerPacket(p) => {
+                            self.update_phase(&p);
+                            let disconnect = p == ServerPacket::Disconnect;
                             self.packet_writer.queue_packet(p);
                             self.packet_writer.send_all_current_packets(Some(&mut packet_reader)).await?;
+                            if disconnect {
+                                log::info!("Client {} was disconnected by the world", self.peer);
+                                break;
+                            }
                         }
                         WorldResponse::NewClientId(id) => {
                             self.id = Some(id);
@@ -120,6 +262,10 @@ impl Client {
                 _ = end_rx.recv().fuse() => {
                     break;
                 }
+                _ = idle.fuse() => {
+                    log::warn!("Client {} was idle too long during {:?}, disconnecting", self.peer, self.phase);
+                    break;
+                }
             }
         }
         Ok(0)
@@ -0,0 +1,260 @@
+//! The list of banned ip addresses, loaded from a file and reloaded in the background when the file changes.
+//! Each line of the file is a single address or a CIDR range, anything after a # is a comment.
+
+use std::net::IpAddr;
+
+/// A single address or a range of addresses
+#[derive(Clone, Debug, PartialEq)]
+pub struct IpRange {
+    /// The first address of the range
+    addr: IpAddr,
+    /// The number of leading bits that must match
+    prefix: u8,
+}
+
+impl std::str::FromStr for IpRange {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|e| format!("Invalid address {}: {}", s, e))?;
+        let addr = addr.to_canonical();
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(p) => p
+                .trim()
+                .parse()
+                .map_err(|e| format!("Invalid prefix {}: {}", s, e))?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(format!("Invalid prefix {}", s));
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl IpRange {
+    /// Does the range contain an address?
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => {
+                Self::prefix_matches(u32::from(a) as u128, u32::from(b) as u128, 32, self.prefix)
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) => {
+                Self::prefix_matches(u128::from(a), u128::from(b), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+
+    /// Do the first prefix bits of two addresses of the given width match?
+    fn prefix_matches(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+        if prefix == 0 {
+            return true;
+        }
+        let shift = bits - prefix;
+        (a >> shift) == (b >> shift)
+    }
+}
+
+/// The ban list
+#[derive(Clone, Debug, Default)]
+pub struct IpBanList {
+    /// The banned ranges
+    ranges: Vec<IpRange>,
+}
+
+impl IpBanList {
+    /// Parse the contents of a ban file, invalid lines are logged and skipped
+    fn parse(contents: &str) -> Self {
+        let mut ranges = Vec::new();
+        for (num, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            match line.parse() {
+                Ok(r) => ranges.push(r),
+                Err(e) => log::error!("ip ban line {}: {}", num + 1, e),
+            }
+        }
+        Self { ranges }
+    }
+
+    /// Is an address banned?
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.ranges.iter().any(|r| r.contains(ip))
+    }
+}
+
+/// How often the ban file is checked for changes
+const RELOAD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// The file a ban list is loaded from
+#[derive(Debug)]
+struct IpBanFile {
+    /// The path of the file
+    path: std::path::PathBuf,
+    /// When the file was last modified, when it was loaded
+    modified: Option<std::time::SystemTime>,
+    /// Has the file been loaded at least once
+    loaded: bool,
+    /// Has the last attempt to read the file failed, so the failure is only logged once
+    failing: bool,
+}
+
+impl IpBanFile {
+    /// Load the list again if the file has been modified since it was last loaded.
+    /// A file that can not be read keeps the previous list.
+    fn reload_if_changed(&mut self) -> Option<IpBanList> {
+        let contents = std::fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .and_then(|modified| {
+                if Some(modified) == self.modified {
+                    Ok(None)
+                } else {
+                    std::fs::read_to_string(&self.path).map(|c| Some((modified, c)))
+                }
+            });
+        match contents {
+            Ok(None) => None,
+            Ok(Some((modified, c))) => {
+                self.modified = Some(modified);
+                self.loaded = true;
+                self.failing = false;
+                let list = IpBanList::parse(&c);
+                log::info!(
+                    "Loaded {} ip bans from {}",
+                    list.ranges.len(),
+                    self.path.display()
+                );
+                Some(list)
+            }
+            Err(e) => {
+                if self.loaded && !self.failing {
+                    log::error!(
+                        "Failed to reload ip bans from {}, keeping the previous bans: {}",
+                        self.path.display(),
+                        e
+                    );
+                }
+                self.failing = true;
+                None
+            }
+        }
+    }
+}
+
+/// Load the ban list from the given file and reload it in the background whenever the file changes.
+/// A missing file is an empty list. The reloading stops when every receiver is dropped.
+pub fn watch(path: Option<std::path::PathBuf>) -> tokio::sync::watch::Receiver<IpBanList> {
+    let mut file = match path {
+        Some(path) => IpBanFile {
+            path,
+            modified: None,
+            loaded: false,
+            failing: false,
+        },
+        None => return tokio::sync::watch::channel(IpBanList::default()).1,
+    };
+    let list = file.reload_if_changed().unwrap_or_default();
+    let (sender, receiver) = tokio::sync::watch::channel(list);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let reloaded = tokio::task::spawn_blocking(move || {
+                let list = file.reload_if_changed();
+                (file, list)
+            })
+            .await;
+            let list = match reloaded {
+                Ok((f, list)) => {
+                    file = f;
+                    list
+                }
+                Err(e) => {
+                    log::error!("The ip ban reload failed: {:?}", e);
+                    return;
+                }
+            };
+            if let Some(list) = list {
+                if sender.send(list).is_err() {
+                    return;
+                }
+            } else if sender.is_closed() {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks single addresses and ranges of both address families
+    #[test]
+    fn ranges() {
+        let r: IpRange = "10.1.0.0/16".parse().unwrap();
+        assert!(r.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(r.contains(&"::ffff:10.1.200.3".parse().unwrap()));
+        assert!(!r.contains(&"10.2.0.1".parse().unwrap()));
+        assert!(!r.contains(&"::1".parse().unwrap()));
+        let r: IpRange = "192.168.1.5".parse().unwrap();
+        assert!(r.contains(&"192.168.1.5".parse().unwrap()));
+        assert!(!r.contains(&"192.168.1.6".parse().unwrap()));
+        let r: IpRange = "2001:db8::/32".parse().unwrap();
+        assert!(r.contains(&"2001:db8:1::7".parse().unwrap()));
+        assert!(!r.contains(&"2001:db9::1".parse().unwrap()));
+        let r: IpRange = "0.0.0.0/0".parse().unwrap();
+        assert!(r.contains(&"8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("nonsense".parse::<IpRange>().is_err());
+    }
+
+    /// Checks that the file format skips comments and invalid lines
+    #[test]
+    fn parse_file() {
+        let l = IpBanList::parse("# flooders\n1.2.3.4\n\n5.6.0.0/16 # a whole range\nbad line\n");
+        assert_eq!(l.ranges.len(), 2);
+        assert!(l.is_banned(&"1.2.3.4".parse().unwrap()));
+        assert!(l.is_banned(&"5.6.7.8".parse().unwrap()));
+        assert!(!l.is_banned(&"1.2.3.5".parse().unwrap()));
+    }
+
+    /// Checks that the list is reloaded when the file changes and kept when it can not be read
+    #[test]
+    fn reload() {
+        let path = std::env::temp_dir().join(format!("ip-bans-{}.txt", std::process::id()));
+        std::fs::write(&path, "1.2.3.4\n").unwrap();
+        let mut f = IpBanFile {
+            path: path.clone(),
+            modified: None,
+            loaded: false,
+            failing: false,
+        };
+        let l = f.reload_if_changed().unwrap();
+        assert!(l.is_banned(&"1.2.3.4".parse().unwrap()));
+        assert!(f.reload_if_changed().is_none());
+        f.modified = Some(std::time::SystemTime::UNIX_EPOCH);
+        std::fs::write(&path, "5.6.7.8\n").unwrap();
+        let l = f.reload_if_changed().unwrap();
+        assert!(l.is_banned(&"5.6.7.8".parse().unwrap()));
+        assert!(!l.is_banned(&"1.2.3.4".parse().unwrap()));
+        std::fs::remove_file(&path).unwrap();
+        assert!(f.reload_if_changed().is_none());
+        assert!(f.failing);
+    }
+}
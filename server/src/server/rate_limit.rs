@@ -0,0 +1,220 @@
+//! Per-connection flood protection, using a token bucket for each category of packet
+
+use std::time::Instant;
+
+use crate::config::{BucketConfig, RateLimitAction, RateLimitConfig};
+use common::packet::ClientPacket;
+
+/// The categories of client packets that are limited separately
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PacketCategory {
+    /// All types of chat
+    Chat,
+    /// Moving and turning
+    Movement,
+    /// Attacking an object
+    Attack,
+    /// Using an item
+    Item,
+    /// Everything else
+    Other,
+}
+
+impl PacketCategory {
+    /// The number of categories
+    const COUNT: usize = 5;
+
+    /// Get the category of a packet
+    pub fn of(p: &ClientPacket) -> Self {
+        match p {
+            ClientPacket::Chat(_)
+            | ClientPacket::YellChat(_)
+            | ClientPacket::PartyChat(_)
+            | ClientPacket::PledgeChat(_)
+            | ClientPacket::WhisperChat(_, _)
+            | ClientPacket::GlobalChat(_)
+            | ClientPacket::CommandChat(_)
+            | ClientPacket::SpecialCommandChat(_) => Self::Chat,
+            ClientPacket::MoveFrom { .. } | ClientPacket::ChangeDirection(_) => Self::Movement,
+            ClientPacket::AttackObject { .. } => Self::Attack,
+            ClientPacket::UseItem { .. } => Self::Item,
+            _ => Self::Other,
+        }
+    }
+
+    /// The index of the category, used to look up its bucket
+    fn index(&self) -> usize {
+        match self {
+            Self::Chat => 0,
+            Self::Movement => 1,
+            Self::Attack => 2,
+            Self::Item => 3,
+            Self::Other => 4,
+        }
+    }
+}
+
+/// A token bucket, refilled at a constant rate up to a maximum
+#[derive(Debug)]
+struct TokenBucket {
+    /// The settings for the bucket
+    config: BucketConfig,
+    /// The number of tokens currently in the bucket
+    tokens: f64,
+    /// When the bucket was last refilled
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Construct a full bucket
+    fn new(config: BucketConfig, now: Instant) -> Self {
+        Self {
+            config,
+            tokens: config.burst,
+            last: now,
+        }
+    }
+
+    /// Try to take a single token from the bucket, returns false when the bucket is empty
+    fn take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.config.rate).min(self.config.burst);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The rate limiter for a single client connection
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// The buckets, indexed by the packet category
+    buckets: Vec<TokenBucket>,
+    /// Are packets limited at all?
+    enabled: bool,
+    /// What to do when a limit is exceeded
+    action: RateLimitAction,
+    /// The number of violations of each category
+    violations: [u32; PacketCategory::COUNT],
+}
+
+impl RateLimiter {
+    /// Construct a new rate limiter, with all buckets full
+    pub fn new(config: &RateLimitConfig, now: Instant) -> Self {
+        let buckets = [
+            config.chat,
+            config.movement,
+            config.attack,
+            config.item,
+            config.other,
+        ]
+        .iter()
+        .map(|c| TokenBucket::new(*c, now))
+        .collect();
+        Self {
+            buckets,
+            enabled: config.enabled,
+            action: config.action,
+            violations: [0; PacketCategory::COUNT],
+        }
+    }
+
+    /// Check a packet against its limit. Returns the action to take when the limit is exceeded.
+    pub fn check(&mut self, category: PacketCategory, now: Instant) -> Option<RateLimitAction> {
+        if !self.enabled || self.buckets[category.index()].take(now) {
+            return None;
+        }
+        self.violations[category.index()] += 1;
+        Some(self.action)
+    }
+
+    /// The number of times a category has exceeded its limit
+    pub fn violations(&self, category: PacketCategory) -> u32 {
+        self.violations[category.index()]
+    }
+
+    /// The total number of violations over all categories
+    pub fn total_violations(&self) -> u32 {
+        self.violations.iter().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Builds a config with the same limit for every category
+    fn config(rate: f64, burst: f64) -> RateLimitConfig {
+        let b = BucketConfig { rate, burst };
+        RateLimitConfig {
+            enabled: true,
+            action: RateLimitAction::Drop,
+            chat: b,
+            movement: b,
+            attack: b,
+            item: b,
+            other: b,
+        }
+    }
+
+    /// Checks that a burst is allowed, then the bucket refills at the configured rate
+    #[test]
+    fn burst_and_refill() {
+        let start = Instant::now();
+        let mut r = RateLimiter::new(&config(2.0, 3.0), start);
+        for _ in 0..3 {
+            assert_eq!(r.check(PacketCategory::Chat, start), None);
+        }
+        assert_eq!(
+            r.check(PacketCategory::Chat, start),
+            Some(RateLimitAction::Drop)
+        );
+        assert_eq!(r.check(PacketCategory::Movement, start), None);
+        let later = start + Duration::from_millis(500);
+        assert_eq!(r.check(PacketCategory::Chat, later), None);
+        assert_eq!(
+            r.check(PacketCategory::Chat, later),
+            Some(RateLimitAction::Drop)
+        );
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(r.check(PacketCategory::Chat, much_later), None);
+        }
+        assert_eq!(r.violations(PacketCategory::Chat), 2);
+        assert_eq!(r.violations(PacketCategory::Movement), 0);
+        assert_eq!(r.total_violations(), 2);
+    }
+
+    /// Checks that nothing is limited when rate limiting is disabled
+    #[test]
+    fn disabled() {
+        let start = Instant::now();
+        let mut c = config(0.0, 0.0);
+        c.enabled = false;
+        let mut r = RateLimiter::new(&c, start);
+        assert_eq!(r.check(PacketCategory::Attack, start), None);
+        assert_eq!(r.total_violations(), 0);
+    }
+
+    /// Checks the category of a few packets
+    #[test]
+    fn categories() {
+        assert_eq!(
+            PacketCategory::of(&ClientPacket::Chat("hi".to_string())),
+            PacketCategory::Chat
+        );
+        assert_eq!(
+            PacketCategory::of(&ClientPacket::ChangeDirection(1)),
+            PacketCategory::Movement
+        );
+        assert_eq!(
+            PacketCategory::of(&ClientPacket::KeepAlive),
+            PacketCategory::Other
+        );
+    }
+}
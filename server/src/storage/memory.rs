@@ -0,0 +1,421 @@
+//! A storage that keeps everything in memory, for running the server and its tests without a database.
+//! Nothing is kept when the server stops. There are no item definitions or npcs, only the maps that new characters start on.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use super::{Storage, StorageError};
+use crate::{
+    character::{Character, ExtraCharacterDetails, SavedCharacter},
+    user::{PasswordChange, UserAccount},
+    world::{
+        item::{Armor, EtcItem, ItemInstanceWithoutDefinition, SavedItem, Weapon},
+        monster::MonsterSpawn,
+        npc::{NpcDefinition, NpcSpawn},
+        Map,
+    },
+};
+
+/// The data that changes while the server runs
+#[derive(Debug)]
+struct MemoryData {
+    /// The accounts, by name
+    accounts: HashMap<String, UserAccount>,
+    /// The characters and their details, by id
+    characters: HashMap<u32, (Character, ExtraCharacterDetails)>,
+    /// The items held by characters, by item id
+    items: HashMap<u32, SavedItem>,
+    /// The id for the next new character or item
+    next_id: u32,
+    /// The number of character saves that fail on purpose before saves work again
+    failing_saves: u32,
+}
+
+impl MemoryData {
+    /// Remove the characters that match, along with their items. Returns the names of the removed characters.
+    fn remove_characters(&mut self, matches: impl Fn(&Character) -> bool) -> Vec<String> {
+        let ids: Vec<u32> = self
+            .characters
+            .iter()
+            .filter(|(_, (c, _))| matches(c))
+            .map(|(id, _)| *id)
+            .collect();
+        let mut names = Vec::new();
+        for id in ids {
+            if let Some((c, _)) = self.characters.remove(&id) {
+                names.push(c.name().to_string());
+            }
+        }
+        let characters = &self.characters;
+        self.items
+            .retain(|_, i| characters.contains_key(&i.char_id));
+        names
+    }
+
+    /// Hand out the next id for a character or item
+    fn reserve_id(&mut self) -> Result<u32, StorageError> {
+        if self.next_id >= crate::world::FIRST_WORLD_ID {
+            return Err(StorageError::OutOfIds);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        Ok(id)
+    }
+}
+
+/// Stores everything in memory
+#[derive(Debug)]
+pub struct MemoryStorage {
+    /// The data that changes while the server runs
+    data: Mutex<MemoryData>,
+    /// The maps of the world
+    maps: Vec<Map>,
+}
+
+impl MemoryStorage {
+    /// Construct an empty storage, with only the starting maps
+    pub fn new() -> Self {
+        Self {
+            data: Mutex::new(MemoryData {
+                accounts: HashMap::new(),
+                characters: HashMap::new(),
+                items: HashMap::new(),
+                next_id: 2,
+                failing_saves: 0,
+            }),
+            maps: vec![
+                Map::new(68, "Singing Island", 0, u16::MAX, 0, u16::MAX),
+                Map::new(69, "Hidden Valley", 0, u16::MAX, 0, u16::MAX),
+            ],
+        }
+    }
+
+    /// Make the next character saves fail, to test what happens when the storage is unavailable
+    #[cfg(test)]
+    pub fn fail_saves(&self, count: u32) {
+        self.data().failing_saves = count;
+    }
+
+    /// Lock the data. A panic while the lock was held does not leave the data in a broken state, so poisoning is ignored.
+    fn data(&self) -> MutexGuard<'_, MemoryData> {
+        self.data.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn account(&self, name: &str) -> Result<Option<UserAccount>, StorageError> {
+        Ok(self.data().accounts.get(name).cloned())
+    }
+
+    fn insert_account(&self, account: &UserAccount) -> Result<(), StorageError> {
+        self.data()
+            .accounts
+            .entry(account.account_name().to_string())
+            .or_insert_with(|| account.clone());
+        Ok(())
+    }
+
+    fn set_password(&self, name: &str, hash: &str) -> Result<(), StorageError> {
+        if let Some(a) = self.data().accounts.get_mut(name) {
+            a.set_password_hash(hash.to_string());
+        }
+        Ok(())
+    }
+
+    fn change_password(
+        &self,
+        name: &str,
+        change: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<PasswordChange, StorageError> {
+        let mut data = self.data();
+        let a = match data.accounts.get_mut(name) {
+            Some(a) => a,
+            None => return Ok(PasswordChange::NoSuchAccount),
+        };
+        match change(a.password_hash()) {
+            Some(hash) => {
+                a.set_password_hash(hash);
+                Ok(PasswordChange::Changed)
+            }
+            None => Ok(PasswordChange::WrongPassword),
+        }
+    }
+
+    fn ban_account(
+        &self,
+        name: &str,
+        expires: Option<chrono::DateTime<chrono::Utc>>,
+        reason: &str,
+    ) -> Result<(), StorageError> {
+        if let Some(a) = self.data().accounts.get_mut(name) {
+            a.set_ban(expires, reason);
+        }
+        Ok(())
+    }
+
+    fn unban_account(&self, name: &str) -> Result<(), StorageError> {
+        if let Some(a) = self.data().accounts.get_mut(name) {
+            a.clear_ban();
+        }
+        Ok(())
+    }
+
+    fn record_login(
+        &self,
+        name: &str,
+        when: chrono::DateTime<chrono::Utc>,
+        ip: &str,
+    ) -> Result<(), StorageError> {
+        if let Some(a) = self.data().accounts.get_mut(name) {
+            a.set_login(when, ip);
+        }
+        Ok(())
+    }
+
+    fn characters(&self, account: &str) -> Result<Vec<Character>, StorageError> {
+        let mut chars: Vec<Character> = self
+            .data()
+            .characters
+            .values()
+            .filter(|(c, _)| c.account_name() == account)
+            .map(|(c, _)| c.clone())
+            .collect();
+        chars.sort_by_key(|c| c.id());
+        Ok(chars)
+    }
+
+    fn character_details(&self, c: &Character) -> Result<ExtraCharacterDetails, StorageError> {
+        self.data()
+            .characters
+            .get(&c.id())
+            .map(|(_, d)| *d)
+            .ok_or_else(|| StorageError::NotFound(c.name().to_string()))
+    }
+
+    fn character_items(
+        &self,
+        char_id: u32,
+    ) -> Result<Vec<ItemInstanceWithoutDefinition>, StorageError> {
+        Ok(self
+            .data()
+            .items
+            .values()
+            .filter(|i| i.char_id == char_id)
+            .map(|i| i.item.clone())
+            .collect())
+    }
+
+    fn insert_character(&self, c: &mut Character) -> Result<bool, StorageError> {
+        let mut data = self.data();
+        if data.characters.values().any(|(o, _)| o.name() == c.name()) {
+            return Ok(false);
+        }
+        c.set_id(data.reserve_id()?);
+        data.characters
+            .insert(c.id(), (c.clone(), c.initial_details()));
+        Ok(true)
+    }
+
+    fn delete_character(&self, account: &str, name: &str) -> Result<(), StorageError> {
+        self.data()
+            .remove_characters(|c| c.account_name() == account && c.name() == name);
+        Ok(())
+    }
+
+    fn set_delete_time(
+        &self,
+        char_id: u32,
+        when: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<(), StorageError> {
+        if let Some((c, _)) = self.data().characters.get_mut(&char_id) {
+            c.set_delete_time(when);
+        }
+        Ok(())
+    }
+
+    fn purge_deleted_characters(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<String>, StorageError> {
+        Ok(self
+            .data()
+            .remove_characters(|c| c.delete_time().map(|t| t <= now).unwrap_or(false)))
+    }
+
+    fn save_character(&self, c: &SavedCharacter) -> Result<(), StorageError> {
+        let mut data = self.data();
+        if data.failing_saves > 0 {
+            data.failing_saves -= 1;
+            return Err(StorageError::NotFound(
+                "The storage is failing saves".to_string(),
+            ));
+        }
+        if let Some(e) = data.characters.get_mut(&c.character.id()) {
+            *e = (c.character.clone(), c.details);
+        }
+        for id in &c.items.deleted {
+            data.items.remove(id);
+        }
+        for (id, i) in c.items.inserted.iter().chain(c.items.updated.iter()) {
+            data.items.insert(*id, i.clone());
+        }
+        Ok(())
+    }
+
+    fn reserve_id(&self) -> Result<u32, StorageError> {
+        self.data().reserve_id()
+    }
+
+    fn weapons(&self) -> Result<Vec<Weapon>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    fn etc_items(&self) -> Result<Vec<EtcItem>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    fn armor(&self) -> Result<Vec<Armor>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    fn maps(&self) -> Result<Vec<Map>, StorageError> {
+        Ok(self.maps.clone())
+    }
+
+    fn npc_definitions(&self) -> Result<Vec<NpcDefinition>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    fn npc_spawns(&self) -> Result<Vec<NpcSpawn>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    fn monster_spawns(&self) -> Result<Vec<MonsterSpawn>, StorageError> {
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Roll a character for an account
+    fn roll(account: &str, name: &str) -> Character {
+        Character::new(
+            account.to_string(),
+            0,
+            name.to_string(),
+            1,
+            0,
+            16,
+            12,
+            16,
+            10,
+            10,
+            10,
+        )
+        .unwrap()
+    }
+
+    /// Checks creating, banning and changing the password of an account
+    #[test]
+    fn accounts() {
+        let s = MemoryStorage::new();
+        assert!(s.account("bob").unwrap().is_none());
+        let a =
+            UserAccount::new("bob".to_string(), "pw".to_string(), "1.2.3.4".to_string()).unwrap();
+        a.insert_into_db(&s);
+        let mut a = s.account("bob").unwrap().unwrap();
+        assert!(a.check_login("", "pw"));
+        let now = chrono::Utc::now();
+        a.ban(None, "spam", &s).unwrap();
+        assert!(s.account("bob").unwrap().unwrap().is_banned(now));
+        a.unban(&s).unwrap();
+        assert!(!s.account("bob").unwrap().unwrap().is_banned(now));
+        let change = |current: &str| (current == "old").then(|| "new".to_string());
+        assert_eq!(
+            s.change_password("alice", &change).unwrap(),
+            PasswordChange::NoSuchAccount
+        );
+        assert_eq!(
+            s.change_password("bob", &change).unwrap(),
+            PasswordChange::WrongPassword
+        );
+        s.set_password("bob", "old").unwrap();
+        assert_eq!(
+            s.change_password("bob", &change).unwrap(),
+            PasswordChange::Changed
+        );
+        assert_eq!(s.account("bob").unwrap().unwrap().password_hash(), "new");
+    }
+
+    /// Checks creating, playing, saving and deleting characters
+    #[test]
+    fn characters() {
+        let s = MemoryStorage::new();
+        let mut c = roll("bob", "knight");
+        c.save_new_to_db(&s).unwrap();
+        assert!(roll("alice", "knight").save_new_to_db(&s).is_err());
+        roll("bob", "second").save_new_to_db(&s).unwrap();
+        roll("alice", "third").save_new_to_db(&s).unwrap();
+        let chars = Character::retrieve_chars("bob", &s).unwrap();
+        assert_eq!(chars.len(), 2);
+        assert_eq!(chars[0].name(), "knight");
+        assert_eq!(chars[0].id(), c.id());
+        let item_id = s.reserve_id().unwrap();
+        assert!(chars.iter().all(|c| c.id() != item_id));
+        assert!(item_id < crate::world::FIRST_WORLD_ID);
+
+        let mut ids = crate::world::WorldIdGenerator::new();
+        let mut fc = chars[0]
+            .get_partial_details(ids.new_id(), &s)
+            .unwrap()
+            .into_full(&HashMap::new());
+        fc.location_mut().x += 5;
+        let moved = *fc.location_ref();
+        s.save_character(&fc.saved()).unwrap();
+        let fc = Character::retrieve_chars("bob", &s).unwrap()[0]
+            .get_partial_details(ids.new_id(), &s)
+            .unwrap()
+            .into_full(&HashMap::new());
+        assert_eq!(*fc.location_ref(), moved);
+
+        s.delete_character("bob", "knight").unwrap();
+        let chars = Character::retrieve_chars("bob", &s).unwrap();
+        assert_eq!(chars.len(), 1);
+        assert_eq!(chars[0].name(), "second");
+        assert_eq!(s.maps().unwrap().len(), 2);
+    }
+
+    /// Checks that characters marked for deletion are deleted once their time has passed
+    #[test]
+    fn purge() {
+        let s = MemoryStorage::new();
+        let mut c = roll("bob", "knight");
+        c.save_new_to_db(&s).unwrap();
+        roll("bob", "second").save_new_to_db(&s).unwrap();
+        let now = chrono::Utc::now();
+        s.set_delete_time(c.id(), Some(now)).unwrap();
+        let chars = Character::retrieve_chars("bob", &s).unwrap();
+        assert_eq!(chars[0].delete_time(), Some(now));
+        let ctype = |c: &Character| match c.get_details_packet() {
+            common::packet::ServerPacket::LoginCharacterDetails { ctype, .. } => ctype,
+            p => panic!("Unexpected packet {:?}", p),
+        };
+        assert_eq!(ctype(&chars[0]), ctype(&c) + 32);
+        assert!(s
+            .purge_deleted_characters(now - chrono::Duration::seconds(1))
+            .unwrap()
+            .is_empty());
+        assert_eq!(s.purge_deleted_characters(now).unwrap(), vec!["knight"]);
+        let chars = Character::retrieve_chars("bob", &s).unwrap();
+        assert_eq!(chars.len(), 1);
+        assert_eq!(chars[0].delete_time(), None);
+    }
+}
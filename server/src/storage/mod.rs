@@ -0,0 +1,161 @@
+//! Persistent storage for the server. Everything the server keeps in a database goes through the [Storage] trait,
+//! so the server can run against mysql in production or an in-memory store for development and tests.
+
+use std::sync::Arc;
+
+use crate::{
+    character::{Character, ExtraCharacterDetails, SavedCharacter},
+    user::{PasswordChange, UserAccount},
+    world::{
+        item::{Armor, EtcItem, ItemInstanceWithoutDefinition, Weapon},
+        monster::MonsterSpawn,
+        npc::{NpcDefinition, NpcSpawn},
+        Map,
+    },
+};
+
+pub mod memory;
+pub mod mysql;
+pub mod passwords;
+pub mod persistence;
+
+/// The errors that can occur when accessing storage
+#[derive(Debug)]
+pub enum StorageError {
+    /// A mysql error occurred
+    Mysql(::mysql::Error),
+    /// The data asked for does not exist
+    NotFound(String),
+    /// Every id below [crate::world::FIRST_WORLD_ID] has been handed out
+    OutOfIds,
+    /// A password could not be hashed
+    PasswordHash(scrypt::password_hash::Error),
+    /// The database schema is older than the server needs
+    SchemaOutdated {
+        /// The version of the database schema
+        found: u32,
+        /// The version the server needs
+        expected: u32,
+    },
+}
+
+impl From<::mysql::Error> for StorageError {
+    fn from(e: ::mysql::Error) -> Self {
+        StorageError::Mysql(e)
+    }
+}
+
+/// The storage used by the server for accounts, characters, items and the static game tables
+pub trait Storage: Send + Sync {
+    /// Get an account by name, if it exists
+    fn account(&self, name: &str) -> Result<Option<UserAccount>, StorageError>;
+
+    /// Insert a new account
+    fn insert_account(&self, account: &UserAccount) -> Result<(), StorageError>;
+
+    /// Replace the password hash of an account
+    fn set_password(&self, name: &str, hash: &str) -> Result<(), StorageError>;
+
+    /// Change the password of an account atomically. The change function is given the current hash,
+    /// and returns the new hash or None when the old password was wrong.
+    fn change_password(
+        &self,
+        name: &str,
+        change: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<PasswordChange, StorageError>;
+
+    /// Ban an account until the given time, or forever when there is no expiry
+    fn ban_account(
+        &self,
+        name: &str,
+        expires: Option<chrono::DateTime<chrono::Utc>>,
+        reason: &str,
+    ) -> Result<(), StorageError>;
+
+    /// Remove the ban from an account
+    fn unban_account(&self, name: &str) -> Result<(), StorageError>;
+
+    /// Record a login to an account at the given time from the given ip address
+    fn record_login(
+        &self,
+        name: &str,
+        when: chrono::DateTime<chrono::Utc>,
+        ip: &str,
+    ) -> Result<(), StorageError>;
+
+    /// Get all characters of an account
+    fn characters(&self, account: &str) -> Result<Vec<Character>, StorageError>;
+
+    /// Get the extra details of a character, needed to play the character
+    fn character_details(&self, c: &Character) -> Result<ExtraCharacterDetails, StorageError>;
+
+    /// Get the items held by a character
+    fn character_items(
+        &self,
+        char_id: u32,
+    ) -> Result<Vec<ItemInstanceWithoutDefinition>, StorageError>;
+
+    /// Insert a new character, giving it a new id. Returns false when a character with the same name already exists.
+    fn insert_character(&self, c: &mut Character) -> Result<bool, StorageError>;
+
+    /// Delete a character of an account, along with its items and bookmarks.
+    /// Warehouses belong to the account and are shared by its characters, they are deleted with the last character of the account.
+    fn delete_character(&self, account: &str, name: &str) -> Result<(), StorageError>;
+
+    /// Mark a character to be deleted at the given time, or keep it when there is no time
+    fn set_delete_time(
+        &self,
+        char_id: u32,
+        when: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<(), StorageError>;
+
+    /// Delete every character whose delete time has passed, in the same way as [Storage::delete_character].
+    /// Returns the names of the deleted characters.
+    fn purge_deleted_characters(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<String>, StorageError>;
+
+    /// Save the state of a character that is being played, along with the changes to its items
+    fn save_character(&self, c: &SavedCharacter) -> Result<(), StorageError>;
+
+    /// Reserve an id for a new character, item, warehouse entry, clan or pet, it is never handed out again.
+    /// The ids are all below [crate::world::FIRST_WORLD_ID].
+    fn reserve_id(&self) -> Result<u32, StorageError>;
+
+    /// Get the weapon table
+    fn weapons(&self) -> Result<Vec<Weapon>, StorageError>;
+
+    /// Get the etc item table
+    fn etc_items(&self) -> Result<Vec<EtcItem>, StorageError>;
+
+    /// Get the armor table
+    fn armor(&self) -> Result<Vec<Armor>, StorageError>;
+
+    /// Get the map table
+    fn maps(&self) -> Result<Vec<Map>, StorageError>;
+
+    /// Get the npc definition table
+    fn npc_definitions(&self) -> Result<Vec<NpcDefinition>, StorageError>;
+
+    /// Get the npc spawn table
+    fn npc_spawns(&self) -> Result<Vec<NpcSpawn>, StorageError>;
+
+    /// Get the monster spawn table
+    fn monster_spawns(&self) -> Result<Vec<MonsterSpawn>, StorageError>;
+}
+
+/// Open the storage selected in the configuration
+pub fn open(settings: &crate::MainConfiguration) -> Result<Arc<dyn Storage>, StorageError> {
+    match settings.db.backend {
+        crate::config::StorageBackend::Mysql => {
+            log::info!("Trying to connect to database");
+            let s = self::mysql::MysqlStorage::new(crate::open_mysql(settings)?)?;
+            Ok(Arc::new(s))
+        }
+        crate::config::StorageBackend::Memory => {
+            log::warn!("Using in-memory storage, nothing will be saved when the server stops");
+            Ok(Arc::new(memory::MemoryStorage::new()))
+        }
+    }
+}
@@ -0,0 +1,362 @@
+//! The mysql storage, used in production
+
+use mysql::prelude::Queryable;
+
+use super::{Storage, StorageError};
+use crate::{
+    character::{Character, ExtraCharacterDetails, SavedCharacter},
+    user::{PasswordChange, UserAccount},
+    world::{
+        item::{Armor, EtcItem, ItemInstanceWithoutDefinition, Weapon},
+        monster::MonsterSpawn,
+        npc::{NpcDefinition, NpcSpawn},
+        Map,
+    },
+};
+
+/// The columns selected for an account
+const ACCOUNT_COLUMNS: &str = "login, password, access_level, ip, host, banned, character_slot, lastactive, ban_expires, ban_reason";
+
+/// How many ids are reserved from the id_sequence table at a time
+const ID_BLOCK_SIZE: u32 = 100;
+
+/// A block of ids reserved from the id_sequence table
+#[derive(Debug, Default)]
+struct IdBlock {
+    /// The next id to hand out
+    next: u32,
+    /// The first id after the block
+    end: u32,
+}
+
+/// Stores everything in a mysql database
+pub struct MysqlStorage {
+    /// The connection pool for the database
+    pool: mysql::Pool,
+    /// The ids reserved by this server that have not been handed out yet
+    ids: std::sync::Mutex<IdBlock>,
+}
+
+impl MysqlStorage {
+    /// Construct the storage, checking that a connection to the database can be made and that the schema is up to date
+    pub fn new(pool: mysql::Pool) -> Result<Self, StorageError> {
+        let found = crate::migrate::current_version(&mut pool.get_conn()?)?;
+        let expected = crate::migrate::SCHEMA_VERSION;
+        if found < expected {
+            log::error!(
+                "The database schema is version {}, the server needs version {}. Run `rustlin-server migrate` to update it.",
+                found,
+                expected
+            );
+            return Err(StorageError::SchemaOutdated { found, expected });
+        }
+        Ok(Self {
+            pool,
+            ids: std::sync::Mutex::new(IdBlock::default()),
+        })
+    }
+
+    /// Get a connection to the database
+    fn conn(&self) -> Result<mysql::PooledConn, StorageError> {
+        Ok(self.pool.get_conn()?)
+    }
+
+    /// Reserve the next block of ids from the id_sequence table.
+    /// Other servers using the same database get different blocks, the ids left in a block when the server stops are never used.
+    fn reserve_block(&self) -> Result<IdBlock, StorageError> {
+        let mut conn = self.conn()?;
+        let mut t = conn.start_transaction(mysql::TxOpts::default())?;
+        let next: Option<u32> = t.exec_first(
+            "SELECT next_id FROM id_sequence WHERE name='object' FOR UPDATE",
+            (),
+        )?;
+        let next = next.ok_or_else(|| StorageError::NotFound("id_sequence".to_string()))?;
+        let end = next
+            .checked_add(ID_BLOCK_SIZE)
+            .filter(|e| *e <= crate::world::FIRST_WORLD_ID)
+            .ok_or(StorageError::OutOfIds)?;
+        t.exec_drop(
+            "UPDATE id_sequence SET next_id=? WHERE name='object'",
+            (end,),
+        )?;
+        t.commit()?;
+        Ok(IdBlock { next, end })
+    }
+
+    /// Run a query that loads an entire table
+    fn load_table<T: mysql::prelude::FromRow>(&self, query: &str) -> Result<Vec<T>, StorageError> {
+        let mut conn = self.conn()?;
+        Ok(conn.exec_map(query, (), |a: T| a)?)
+    }
+}
+
+/// Delete a character and the rows that belong to it as part of a transaction.
+/// The warehouses of the account are deleted when it was the last character of the account.
+fn delete_character_rows(t: &mut mysql::Transaction<'_>, id: u32) -> Result<(), mysql::Error> {
+    let account: Option<String> =
+        t.exec_first("SELECT account_name FROM characters WHERE objid=?", (id,))?;
+    t.exec_drop("DELETE FROM character_items WHERE char_id=?", (id,))?;
+    t.exec_drop("DELETE FROM character_teleport WHERE char_id=?", (id,))?;
+    t.exec_drop("DELETE FROM characters WHERE objid=?", (id,))?;
+    if let Some(account) = account {
+        let left: Option<u64> = t.exec_first(
+            "SELECT COUNT(*) FROM characters WHERE account_name=?",
+            (&account,),
+        )?;
+        if left == Some(0) {
+            t.exec_drop(
+                "DELETE FROM character_warehouse WHERE account_name=?",
+                (&account,),
+            )?;
+            t.exec_drop(
+                "DELETE FROM character_elf_warehouse WHERE account_name=?",
+                (&account,),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Convert a chrono date time to a mysql value
+fn to_mysql_date(d: chrono::DateTime<chrono::Utc>) -> mysql::Value {
+    use chrono::{Datelike, Timelike};
+    mysql::Value::Date(
+        d.year() as u16,
+        d.month() as u8,
+        d.day() as u8,
+        d.hour() as u8,
+        d.minute() as u8,
+        d.second() as u8,
+        0,
+    )
+}
+
+impl Storage for MysqlStorage {
+    fn account(&self, name: &str) -> Result<Option<UserAccount>, StorageError> {
+        let query = format!(
+            "SELECT {} from accounts WHERE login=? LIMIT 1",
+            ACCOUNT_COLUMNS
+        );
+        Ok(self.conn()?.exec_first(query, (name,))?)
+    }
+
+    fn insert_account(&self, account: &UserAccount) -> Result<(), StorageError> {
+        let query = "INSERT INTO accounts SET login=?,password=?,lastactive=?,access_level=?,ip=?,host=?,banned=?,character_slot=?";
+        self.conn()?.exec_drop(query, account)?;
+        Ok(())
+    }
+
+    fn set_password(&self, name: &str, hash: &str) -> Result<(), StorageError> {
+        self.conn()?
+            .exec_drop("UPDATE accounts SET password=? WHERE login=?", (hash, name))?;
+        Ok(())
+    }
+
+    fn change_password(
+        &self,
+        name: &str,
+        change: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<PasswordChange, StorageError> {
+        let mut conn = self.conn()?;
+        let mut t = conn.start_transaction(mysql::TxOpts::default())?;
+        let current: Option<String> = t.exec_first(
+            "SELECT password FROM accounts WHERE login=? FOR UPDATE",
+            (name,),
+        )?;
+        let result = match current.map(|p| change(&p)) {
+            None => PasswordChange::NoSuchAccount,
+            Some(None) => PasswordChange::WrongPassword,
+            Some(Some(hash)) => {
+                t.exec_drop("UPDATE accounts SET password=? WHERE login=?", (hash, name))?;
+                PasswordChange::Changed
+            }
+        };
+        if result == PasswordChange::Changed {
+            t.commit()?;
+        } else {
+            t.rollback()?;
+        }
+        Ok(result)
+    }
+
+    fn ban_account(
+        &self,
+        name: &str,
+        expires: Option<chrono::DateTime<chrono::Utc>>,
+        reason: &str,
+    ) -> Result<(), StorageError> {
+        let query = "UPDATE accounts SET banned=1, ban_expires=?, ban_reason=? WHERE login=?";
+        self.conn()?.exec_drop(
+            query,
+            (
+                expires.map(to_mysql_date).unwrap_or(mysql::Value::NULL),
+                reason,
+                name,
+            ),
+        )?;
+        Ok(())
+    }
+
+    fn unban_account(&self, name: &str) -> Result<(), StorageError> {
+        let query = "UPDATE accounts SET banned=0, ban_expires=NULL, ban_reason=NULL WHERE login=?";
+        self.conn()?.exec_drop(query, (name,))?;
+        Ok(())
+    }
+
+    fn record_login(
+        &self,
+        name: &str,
+        when: chrono::DateTime<chrono::Utc>,
+        ip: &str,
+    ) -> Result<(), StorageError> {
+        let query = "UPDATE accounts SET lastactive=?, ip=?, host=? WHERE login=?";
+        self.conn()?
+            .exec_drop(query, (to_mysql_date(when), ip, ip, name))?;
+        Ok(())
+    }
+
+    fn characters(&self, account: &str) -> Result<Vec<Character>, StorageError> {
+        let query = "SELECT * from characters WHERE account_name=?";
+        let mut conn = self.conn()?;
+        let s = conn.prep(query)?;
+        Ok(conn.exec_map(s, (account,), |a: Character| a)?)
+    }
+
+    fn character_details(&self, c: &Character) -> Result<ExtraCharacterDetails, StorageError> {
+        let query = "SELECT Exp, CurHp, CurMp, 1, Food, 32, 1, 2, 3, 4 from characters WHERE account_name=? and char_name=?";
+        let mut conn = self.conn()?;
+        let s = conn.prep(query)?;
+        let details: Option<ExtraCharacterDetails> =
+            conn.exec_first(s, (c.account_name(), c.name()))?;
+        details.ok_or_else(|| StorageError::NotFound(c.name().to_string()))
+    }
+
+    fn character_items(
+        &self,
+        char_id: u32,
+    ) -> Result<Vec<ItemInstanceWithoutDefinition>, StorageError> {
+        let query = "SELECT * from character_items WHERE char_id=?";
+        let mut conn = self.conn()?;
+        let s = conn.prep(query)?;
+        Ok(conn.exec_map(s, (char_id,), |a: ItemInstanceWithoutDefinition| a)?)
+    }
+
+    fn insert_character(&self, c: &mut Character) -> Result<bool, StorageError> {
+        let mut conn = self.conn()?;
+        let mut t = conn.start_transaction(mysql::TxOpts::default())?;
+        let row: Option<mysql::Row> =
+            t.exec_first("SELECT * FROM characters WHERE char_name=?", (c.name(),))?;
+        if row.is_some() {
+            t.rollback()?;
+            return Ok(false);
+        }
+        c.set_id(self.reserve_id()?);
+        let query = "INSERT INTO characters SET account_name=?,objid=?,char_name=?,level=?,MaxHp=?,MaxMp=?,Class=?,Sex=?,Ac=?,Str=?,Dex=?,Con=?,Wis=?,Cha=?,Intel=?,LocX=?,LocY=?,MapID=?";
+        t.exec_drop(query, c)?;
+        t.commit()?;
+        Ok(true)
+    }
+
+    fn delete_character(&self, account: &str, name: &str) -> Result<(), StorageError> {
+        let mut conn = self.conn()?;
+        let mut t = conn.start_transaction(mysql::TxOpts::default())?;
+        let query = "SELECT objid FROM characters WHERE account_name=? AND char_name=? FOR UPDATE";
+        let id: Option<u32> = t.exec_first(query, (account, name))?;
+        if let Some(id) = id {
+            delete_character_rows(&mut t, id)?;
+        }
+        t.commit()?;
+        Ok(())
+    }
+
+    fn set_delete_time(
+        &self,
+        char_id: u32,
+        when: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<(), StorageError> {
+        let query = "UPDATE characters SET DeleteTime=? WHERE objid=?";
+        self.conn()?.exec_drop(
+            query,
+            (
+                when.map(to_mysql_date).unwrap_or(mysql::Value::NULL),
+                char_id,
+            ),
+        )?;
+        Ok(())
+    }
+
+    fn purge_deleted_characters(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<String>, StorageError> {
+        let mut conn = self.conn()?;
+        let mut t = conn.start_transaction(mysql::TxOpts::default())?;
+        let query = "SELECT objid, char_name FROM characters WHERE DeleteTime IS NOT NULL AND DeleteTime<=? FOR UPDATE";
+        let due: Vec<(u32, String)> = t.exec(query, (to_mysql_date(now),))?;
+        for (id, _) in &due {
+            delete_character_rows(&mut t, *id)?;
+        }
+        t.commit()?;
+        Ok(due.into_iter().map(|(_, name)| name).collect())
+    }
+
+    fn save_character(&self, c: &SavedCharacter) -> Result<(), StorageError> {
+        let mut conn = self.conn()?;
+        let mut t = conn.start_transaction(mysql::TxOpts::default())?;
+        let query = "UPDATE characters SET LocX=?,LocY=?,MapID=?,Heading=?,CurHp=?,CurMp=?,Exp=?,Lawful=?,level=? WHERE objid=?";
+        t.exec_drop(query, c)?;
+        if !c.items.deleted.is_empty() {
+            let query = "DELETE FROM character_items WHERE id=? AND char_id=?";
+            let char_id = c.character.id();
+            t.exec_batch(query, c.items.deleted.iter().map(|id| (*id, char_id)))?;
+        }
+        if !c.items.inserted.is_empty() || !c.items.updated.is_empty() {
+            //an insert that was written by a save that failed later on, or an update of a missing row, still ends up right
+            let query = "INSERT INTO character_items SET item_id=?,char_id=?,item_name=?,count=?,is_equipped=?,enchantlvl=?,is_id=?,durability=?,bless=?,attr_enchant_kind=?,attr_enchant_level=?,charge_count=?,remaining_time=?,id=? ON DUPLICATE KEY UPDATE item_id=VALUES(item_id),char_id=VALUES(char_id),item_name=VALUES(item_name),count=VALUES(count),is_equipped=VALUES(is_equipped),enchantlvl=VALUES(enchantlvl),is_id=VALUES(is_id),durability=VALUES(durability),bless=VALUES(bless),attr_enchant_kind=VALUES(attr_enchant_kind),attr_enchant_level=VALUES(attr_enchant_level),charge_count=VALUES(charge_count),remaining_time=VALUES(remaining_time)";
+            t.exec_batch(
+                query,
+                c.items.inserted.values().chain(c.items.updated.values()),
+            )?;
+        }
+        t.commit()?;
+        Ok(())
+    }
+
+    fn reserve_id(&self) -> Result<u32, StorageError> {
+        let mut ids = self.ids.lock().unwrap_or_else(|e| e.into_inner());
+        if ids.next == ids.end {
+            *ids = self.reserve_block()?;
+        }
+        let id = ids.next;
+        ids.next += 1;
+        Ok(id)
+    }
+
+    fn weapons(&self) -> Result<Vec<Weapon>, StorageError> {
+        self.load_table("SELECT * from weapon")
+    }
+
+    fn etc_items(&self) -> Result<Vec<EtcItem>, StorageError> {
+        self.load_table("SELECT * from etcitem")
+    }
+
+    fn armor(&self) -> Result<Vec<Armor>, StorageError> {
+        self.load_table("SELECT * from armor")
+    }
+
+    fn maps(&self) -> Result<Vec<Map>, StorageError> {
+        self.load_table("SELECT mapid, locationname, startX, endX, startY, endY, monster_amount, drop_rate, underwater, markable, teleportable, escapable, resurrection, painwand, penalty, take_pets, recall_pets, usable_item, usable_skill from mapids")
+    }
+
+    fn npc_definitions(&self) -> Result<Vec<NpcDefinition>, StorageError> {
+        self.load_table("SELECT * from npc")
+    }
+
+    fn npc_spawns(&self) -> Result<Vec<NpcSpawn>, StorageError> {
+        self.load_table("SELECT * from spawnlist_npc")
+    }
+
+    fn monster_spawns(&self) -> Result<Vec<MonsterSpawn>, StorageError> {
+        self.load_table("SELECT * from spawnlist")
+    }
+}
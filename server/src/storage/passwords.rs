@@ -0,0 +1,227 @@
+//! Checks and hashes passwords on a few threads of their own. Hashing a password is slow on purpose,
+//! so it is kept away from the world and from the [super::persistence::PersistenceWorker] that saves the characters.
+//! The queue is bounded, work that does not fit is refused right away instead of piling up.
+
+use std::sync::{Arc, Mutex};
+
+use super::{
+    persistence::{LoginCheck, StorageResponse},
+    Storage,
+};
+use crate::{
+    user::UserAccount,
+    world::{WorldMessage, WorldMessageData},
+};
+
+/// The number of threads checking passwords
+const THREADS: usize = 2;
+
+/// The number of requests that can wait for a thread, more are refused
+const QUEUE_SIZE: usize = 16;
+
+/// The password work the world can ask for
+#[derive(Debug)]
+pub enum PasswordRequest {
+    /// Check the password for a login, creating the account when it does not exist and creation is allowed
+    Login {
+        /// The account name
+        username: String,
+        /// The password given by the client
+        password: String,
+        /// The global salt of legacy password hashes
+        salt: String,
+        /// Create the account when it does not exist
+        create: bool,
+        /// Identifies the login attempt, it is sent back with the answer
+        attempt: u32,
+    },
+    /// Change the password of an account
+    ChangePassword {
+        /// The account name
+        account: String,
+        /// The global salt of legacy password hashes
+        salt: String,
+        /// The current password
+        oldpass: String,
+        /// The new password
+        newpass: String,
+    },
+}
+
+/// Work queued for the threads
+struct Job {
+    /// The client the request is for
+    client: u32,
+    /// The address of the client
+    peer: std::net::SocketAddr,
+    /// The request
+    request: PasswordRequest,
+}
+
+/// Checks and hashes passwords in the background
+pub struct PasswordWorkers {
+    /// Sends work to the threads, None once the workers have finished
+    sender: Option<std::sync::mpsc::SyncSender<Job>>,
+    /// The threads doing the work
+    threads: Vec<std::thread::JoinHandle<()>>,
+}
+
+impl PasswordWorkers {
+    /// Start the worker threads
+    /// # Arguments:
+    /// * storage - The storage with the accounts
+    /// * world - Where the answers are sent, as [WorldMessageData::Storage] messages
+    pub fn new(storage: Arc<dyn Storage>, world: tokio::sync::mpsc::Sender<WorldMessage>) -> Self {
+        let (sender, recv) = std::sync::mpsc::sync_channel(QUEUE_SIZE);
+        let recv = Arc::new(Mutex::new(recv));
+        let threads = (0..THREADS)
+            .map(|i| {
+                let storage = storage.clone();
+                let world = world.clone();
+                let recv = recv.clone();
+                std::thread::Builder::new()
+                    .name(format!("password worker {}", i))
+                    .spawn(move || Self::run(storage, world, recv))
+                    .expect("Failed to start a password worker")
+            })
+            .collect();
+        Self {
+            sender: Some(sender),
+            threads,
+        }
+    }
+
+    /// Do work until the sender is dropped
+    fn run(
+        storage: Arc<dyn Storage>,
+        world: tokio::sync::mpsc::Sender<WorldMessage>,
+        recv: Arc<Mutex<std::sync::mpsc::Receiver<Job>>>,
+    ) {
+        loop {
+            let job = match recv.lock().unwrap_or_else(|e| e.into_inner()).recv() {
+                Ok(j) => j,
+                Err(_) => break,
+            };
+            let r = Self::handle(storage.as_ref(), job.peer, job.request);
+            let m = WorldMessage {
+                data: WorldMessageData::Storage(r),
+                sender: Some(job.client),
+                peer: job.peer,
+            };
+            if world.blocking_send(m).is_err() {
+                log::warn!("The world has stopped, a password answer was dropped");
+            }
+        }
+    }
+
+    /// Do the work for a request from the client at peer
+    fn handle(
+        storage: &dyn Storage,
+        peer: std::net::SocketAddr,
+        request: PasswordRequest,
+    ) -> StorageResponse {
+        match request {
+            PasswordRequest::Login {
+                username,
+                password,
+                salt,
+                create,
+                attempt,
+            } => {
+                let result = match crate::user::get_user_details(&username, storage) {
+                    None if !create => LoginCheck::NoAccount,
+                    None => {
+                        log::info!("Creating account {}", username);
+                        match UserAccount::new(username.clone(), password, peer.to_string()) {
+                            Ok(a) => {
+                                a.insert_into_db(storage);
+                                LoginCheck::Ok(a)
+                            }
+                            Err(e) => LoginCheck::Failed(e),
+                        }
+                    }
+                    Some(mut a) => {
+                        if !a.check_login(&salt, &password) {
+                            LoginCheck::WrongPassword
+                        } else {
+                            if a.has_legacy_hash() {
+                                log::info!("Upgrading the password hash of {}", username);
+                                if let Err(e) = a.upgrade_password(&password, storage) {
+                                    log::error!(
+                                        "Failed to upgrade the password of {}: {:?}",
+                                        username,
+                                        e
+                                    );
+                                }
+                            }
+                            LoginCheck::Ok(a)
+                        }
+                    }
+                };
+                StorageResponse::Login {
+                    username,
+                    attempt,
+                    result,
+                }
+            }
+            PasswordRequest::ChangePassword {
+                account,
+                salt,
+                oldpass,
+                newpass,
+            } => {
+                let result =
+                    crate::user::change_password(&account, &salt, &oldpass, &newpass, storage);
+                StorageResponse::PasswordChange { account, result }
+            }
+        }
+    }
+
+    /// Queue a request from a client, the answer is sent to the world as coming from the client.
+    /// Returns false when the request was refused, because the queue is full or the workers have finished.
+    pub fn request(
+        &self,
+        client: u32,
+        peer: std::net::SocketAddr,
+        request: PasswordRequest,
+    ) -> bool {
+        let s = match &self.sender {
+            Some(s) => s,
+            None => {
+                log::error!("Password work queued after the workers finished");
+                return false;
+            }
+        };
+        match s.try_send(Job {
+            client,
+            peer,
+            request,
+        }) {
+            Ok(()) => true,
+            Err(std::sync::mpsc::TrySendError::Full(_)) => {
+                log::warn!("Too many passwords are waiting to be checked, refusing one");
+                false
+            }
+            Err(std::sync::mpsc::TrySendError::Disconnected(_)) => {
+                log::error!("The password workers have stopped, work was dropped");
+                false
+            }
+        }
+    }
+
+    /// Do everything that is queued and stop the workers
+    pub fn finish(&mut self) {
+        self.sender.take();
+        for t in self.threads.drain(..) {
+            if t.join().is_err() {
+                log::error!("A password worker panicked");
+            }
+        }
+    }
+}
+
+impl Drop for PasswordWorkers {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
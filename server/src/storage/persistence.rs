@@ -0,0 +1,589 @@
+//! Does the storage work of the world on a thread of its own, so the world never waits for the database.
+//! Saves are written without an answer, requests are answered with a [WorldMessageData::Storage] message to the world.
+//! Everything is done in the order it was queued in.
+
+use std::collections::{hash_map::Entry, HashMap};
+use std::sync::Arc;
+
+use super::{Storage, StorageError};
+use crate::{
+    character::{Character, PartialCharacter, SavedCharacter},
+    server::ClientError,
+    user::{PasswordChange, UserAccount},
+    world::{WorldMessage, WorldMessageData, WorldObjectId},
+};
+
+/// The storage work the world can ask for
+#[derive(Debug)]
+pub enum StorageRequest {
+    /// Record a successful login to an account
+    RecordLogin {
+        /// The account name
+        name: String,
+        /// When the login happened
+        when: chrono::DateTime<chrono::Utc>,
+        /// The ip address of the client
+        ip: String,
+    },
+    /// Remove a ban that has expired, there is no answer
+    ExpireBan(String),
+    /// Get the characters of an account
+    CharacterList(String),
+    /// Insert a new character
+    CreateCharacter(Character),
+    /// Delete a character, or mark it for deletion when it needs a waiting period
+    DeleteCharacter {
+        /// The account name
+        account: String,
+        /// The name of the character
+        name: String,
+        /// How long a character that needs a waiting period is kept
+        wait: chrono::Duration,
+    },
+    /// Load a character to be played, cancelling its deletion
+    LoadCharacter {
+        /// The account name
+        account: String,
+        /// The name of the character
+        name: String,
+        /// The world id the character gets
+        world_id: WorldObjectId,
+    },
+    /// Ban an account
+    BanAccount {
+        /// The account name
+        name: String,
+        /// When the ban ends, None for a permanent ban
+        expires: Option<chrono::DateTime<chrono::Utc>>,
+        /// Why the account is banned
+        reason: String,
+    },
+    /// Remove the ban from an account
+    UnbanAccount(String),
+    /// Reserve the id for a new item
+    ReserveItemId {
+        /// The id of the item definition
+        item: u32,
+        /// How many of the item there are
+        count: u32,
+    },
+}
+
+/// The result of checking the password for a login
+#[derive(Debug)]
+pub enum LoginCheck {
+    /// The account does not exist
+    NoAccount,
+    /// The password is wrong
+    WrongPassword,
+    /// The password is correct
+    Ok(UserAccount),
+    /// The account could not be checked or created
+    Failed(StorageError),
+}
+
+/// The answers to storage requests
+#[derive(Debug)]
+pub enum StorageResponse {
+    /// The result of a login check
+    Login {
+        /// The account name
+        username: String,
+        /// The login attempt that was checked
+        attempt: u32,
+        /// The result
+        result: LoginCheck,
+    },
+    /// The result of a password change
+    PasswordChange {
+        /// The account name
+        account: String,
+        /// The result
+        result: Result<PasswordChange, StorageError>,
+    },
+    /// The characters of an account
+    CharacterList(Result<Vec<Character>, ClientError>),
+    /// The result of creating a character, the character has its new id
+    CharacterCreated(Result<Character, ClientError>),
+    /// A character was deleted, or marked for deletion when waiting is true
+    CharacterDeleted {
+        /// The name of the character
+        name: String,
+        /// Was the character marked for deletion instead of deleted
+        waiting: bool,
+    },
+    /// A character was loaded to be played, None when it could not be loaded
+    CharacterLoaded {
+        /// The name of the character
+        name: String,
+        /// The character
+        character: Option<PartialCharacter>,
+    },
+    /// The result of banning an account
+    AccountBanned {
+        /// The account name
+        name: String,
+        /// When the ban ends, None for a permanent ban
+        expires: Option<chrono::DateTime<chrono::Utc>>,
+        /// Why the account is banned
+        reason: String,
+        /// Whether the account exists
+        result: Result<bool, StorageError>,
+    },
+    /// The result of removing the ban from an account
+    AccountUnbanned {
+        /// The account name
+        name: String,
+        /// Whether the account exists
+        result: Result<bool, StorageError>,
+    },
+    /// The id for a new item
+    ItemIdReserved {
+        /// The id of the item definition
+        item: u32,
+        /// How many of the item there are
+        count: u32,
+        /// The id of the new item
+        result: Result<u32, StorageError>,
+    },
+}
+
+/// Work queued for the worker
+enum Job {
+    /// Save a character
+    Save(SavedCharacter),
+    /// Answer a request from a client
+    Request {
+        /// The client the request is for
+        client: u32,
+        /// The address of the client
+        peer: std::net::SocketAddr,
+        /// The request
+        request: StorageRequest,
+    },
+}
+
+/// Does the storage work of the world in the background
+pub struct PersistenceWorker {
+    /// Sends work to the thread, None once the worker has finished
+    sender: Option<std::sync::mpsc::Sender<Job>>,
+    /// The thread doing the work
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl PersistenceWorker {
+    /// Start the worker thread
+    /// # Arguments:
+    /// * storage - The storage to work with
+    /// * world - Where the answers to requests are sent
+    pub fn new(storage: Arc<dyn Storage>, world: tokio::sync::mpsc::Sender<WorldMessage>) -> Self {
+        let (sender, recv) = std::sync::mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("persistence worker".to_string())
+            .spawn(move || Self::run(storage, world, recv))
+            .expect("Failed to start the persistence worker");
+        Self {
+            sender: Some(sender),
+            thread: Some(thread),
+        }
+    }
+
+    /// Do work until the sender is dropped.
+    /// Saves of the same character that queued up while the last batch was worked on are merged into one,
+    /// saves are always written before the requests queued after them.
+    /// A save that fails is kept and merged with the next save of the character, so no changes are lost.
+    fn run(
+        storage: Arc<dyn Storage>,
+        world: tokio::sync::mpsc::Sender<WorldMessage>,
+        recv: std::sync::mpsc::Receiver<Job>,
+    ) {
+        let mut saves: HashMap<u32, SavedCharacter> = HashMap::new();
+        while let Ok(first) = recv.recv() {
+            for job in std::iter::once(first).chain(recv.try_iter()) {
+                match job {
+                    Job::Save(c) => match saves.entry(c.character.id()) {
+                        Entry::Occupied(mut e) => e.get_mut().merge(c),
+                        Entry::Vacant(e) => {
+                            e.insert(c);
+                        }
+                    },
+                    Job::Request {
+                        client,
+                        peer,
+                        request,
+                    } => {
+                        Self::write_saves(storage.as_ref(), &mut saves);
+                        if let Some(r) = Self::handle(storage.as_ref(), request) {
+                            let m = WorldMessage {
+                                data: WorldMessageData::Storage(r),
+                                sender: Some(client),
+                                peer,
+                            };
+                            if world.blocking_send(m).is_err() {
+                                log::warn!("The world has stopped, a storage answer was dropped");
+                            }
+                        }
+                    }
+                }
+            }
+            Self::write_saves(storage.as_ref(), &mut saves);
+        }
+        for c in saves.values() {
+            log::error!(
+                "Character {} could not be saved before stopping",
+                c.character.name()
+            );
+        }
+    }
+
+    /// Write the queued saves, keeping the ones that failed
+    fn write_saves(storage: &dyn Storage, saves: &mut HashMap<u32, SavedCharacter>) {
+        let queued: Vec<(u32, SavedCharacter)> = saves.drain().collect();
+        for (id, c) in queued {
+            if let Err(e) = storage.save_character(&c) {
+                log::error!("Failed to save character {}: {:?}", c.character.name(), e);
+                saves.insert(id, c);
+            }
+        }
+    }
+
+    /// Do the work for a request, returning the answer if there is one
+    fn handle(storage: &dyn Storage, request: StorageRequest) -> Option<StorageResponse> {
+        match request {
+            StorageRequest::RecordLogin { name, when, ip } => {
+                if let Err(e) = storage.record_login(&name, when, &ip) {
+                    log::error!("Failed to record login for {}: {:?}", name, e);
+                }
+                None
+            }
+            StorageRequest::ExpireBan(name) => {
+                if let Err(e) = storage.unban_account(&name) {
+                    log::error!("Failed to remove the expired ban on {}: {:?}", name, e);
+                }
+                None
+            }
+            StorageRequest::CharacterList(account) => Some(StorageResponse::CharacterList(
+                Character::retrieve_chars(&account, storage),
+            )),
+            StorageRequest::CreateCharacter(mut c) => Some(StorageResponse::CharacterCreated(
+                c.save_new_to_db(storage).map(|()| c),
+            )),
+            StorageRequest::DeleteCharacter {
+                account,
+                name,
+                wait,
+            } => Self::delete_character(storage, &account, name, wait),
+            StorageRequest::LoadCharacter {
+                account,
+                name,
+                world_id,
+            } => {
+                let character = Self::load_character(storage, &account, &name, world_id);
+                Some(StorageResponse::CharacterLoaded { name, character })
+            }
+            StorageRequest::BanAccount {
+                name,
+                expires,
+                reason,
+            } => {
+                let result = match crate::user::get_user_details(&name, storage) {
+                    Some(mut a) => a.ban(expires, &reason, storage).map(|()| true),
+                    None => Ok(false),
+                };
+                Some(StorageResponse::AccountBanned {
+                    name,
+                    expires,
+                    reason,
+                    result,
+                })
+            }
+            StorageRequest::UnbanAccount(name) => {
+                let result = match crate::user::get_user_details(&name, storage) {
+                    Some(mut a) => a.unban(storage).map(|()| true),
+                    None => Ok(false),
+                };
+                Some(StorageResponse::AccountUnbanned { name, result })
+            }
+            StorageRequest::ReserveItemId { item, count } => {
+                Some(StorageResponse::ItemIdReserved {
+                    item,
+                    count,
+                    result: storage.reserve_id(),
+                })
+            }
+        }
+    }
+
+    /// Delete a character, or mark it for deletion when it needs a waiting period. There is no answer when the character does not exist.
+    fn delete_character(
+        storage: &dyn Storage,
+        account: &str,
+        name: String,
+        wait: chrono::Duration,
+    ) -> Option<StorageResponse> {
+        let chars = match Character::retrieve_chars(account, storage) {
+            Ok(c) => c,
+            Err(e) => {
+                log::error!("Failed to get the characters of {}: {:?}", account, e);
+                return None;
+            }
+        };
+        let c = chars.iter().find(|c| c.name() == name)?;
+        if !c.needs_delete_waiting() {
+            if let Err(e) = c.delete_char(storage) {
+                log::error!("Failed to delete character {}: {:?}", name, e);
+            }
+            return Some(StorageResponse::CharacterDeleted {
+                name,
+                waiting: false,
+            });
+        }
+        if c.delete_time().is_none() {
+            let when = match chrono::Utc::now().checked_add_signed(wait) {
+                Some(w) => w,
+                None => {
+                    log::error!("The delete wait for {} is too long", name);
+                    return None;
+                }
+            };
+            match storage.set_delete_time(c.id(), Some(when)) {
+                Ok(()) => log::info!("Character {} will be deleted at {}", name, when),
+                Err(e) => log::error!("Failed to mark {} for deletion: {:?}", name, e),
+            }
+        }
+        Some(StorageResponse::CharacterDeleted {
+            name,
+            waiting: true,
+        })
+    }
+
+    /// Load a character to be played, cancelling its deletion
+    fn load_character(
+        storage: &dyn Storage,
+        account: &str,
+        name: &str,
+        world_id: WorldObjectId,
+    ) -> Option<PartialCharacter> {
+        let chars = match Character::retrieve_chars(account, storage) {
+            Ok(c) => c,
+            Err(e) => {
+                log::error!("Failed to get the characters of {}: {:?}", account, e);
+                return None;
+            }
+        };
+        let c = chars.iter().find(|c| c.name() == name)?;
+        if c.delete_time().is_some() {
+            log::info!("Deletion of {} is cancelled", name);
+            if let Err(e) = storage.set_delete_time(c.id(), None) {
+                log::error!("Failed to cancel deletion of {}: {:?}", name, e);
+            }
+        }
+        match c.get_partial_details(world_id, storage) {
+            Ok(pc) => Some(pc),
+            Err(e) => {
+                log::error!("Failed to load character {}: {:?}", name, e);
+                None
+            }
+        }
+    }
+
+    /// Queue a character to be saved
+    pub fn save(&self, c: SavedCharacter) {
+        self.queue(Job::Save(c));
+    }
+
+    /// Queue a request from a client, the answer is sent to the world as coming from the client
+    pub fn request(&self, client: u32, peer: std::net::SocketAddr, request: StorageRequest) {
+        self.queue(Job::Request {
+            client,
+            peer,
+            request,
+        });
+    }
+
+    /// Queue work for the thread
+    fn queue(&self, job: Job) {
+        match &self.sender {
+            Some(s) => {
+                if s.send(job).is_err() {
+                    log::error!("The persistence worker has stopped, work was dropped");
+                }
+            }
+            None => log::error!("Work queued after the persistence worker finished"),
+        }
+    }
+
+    /// Do everything that is queued and stop the worker
+    pub fn finish(&mut self) {
+        self.sender.take();
+        if let Some(t) = self.thread.take() {
+            if t.join().is_err() {
+                log::error!("The persistence worker panicked");
+            }
+        }
+    }
+}
+
+impl Drop for PersistenceWorker {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    /// Roll a character for bob
+    fn roll(name: &str) -> Character {
+        Character::new(
+            "bob".to_string(),
+            0,
+            name.to_string(),
+            1,
+            0,
+            16,
+            12,
+            16,
+            10,
+            10,
+            10,
+        )
+        .unwrap()
+    }
+
+    /// The address used for requests in the tests
+    fn peer() -> std::net::SocketAddr {
+        std::net::SocketAddr::from(([127, 0, 0, 1], 0))
+    }
+
+    /// Checks that queued saves are written by the time the worker finishes
+    #[test]
+    fn saves_are_flushed() {
+        let storage = Arc::new(MemoryStorage::new());
+        let mut c = roll("knight");
+        c.save_new_to_db(storage.as_ref()).unwrap();
+        let mut ids = crate::world::WorldIdGenerator::new();
+        let mut fc = c
+            .get_partial_details(ids.new_id(), storage.as_ref())
+            .unwrap()
+            .into_full(&HashMap::new());
+        let (world, _world_r) = tokio::sync::mpsc::channel(10);
+        let mut w = PersistenceWorker::new(storage.clone(), world);
+        for _ in 0..10 {
+            fc.location_mut().y += 1;
+            w.save(fc.saved());
+        }
+        w.finish();
+        let fc2 = storage.characters("bob").unwrap()[0]
+            .get_partial_details(ids.new_id(), storage.as_ref())
+            .unwrap()
+            .into_full(&HashMap::new());
+        assert_eq!(fc2.location_ref(), fc.location_ref());
+    }
+
+    /// Checks that the item changes of a save that failed are written with the next save of the character
+    #[test]
+    fn failed_saves_are_kept() {
+        let storage = Arc::new(MemoryStorage::new());
+        let mut c = roll("knight");
+        c.save_new_to_db(storage.as_ref()).unwrap();
+        let mut ids = crate::world::WorldIdGenerator::new();
+        let definition = crate::world::item::Item::Etc(
+            crate::world::item::EtcItem::plain(40001).get_instance(ids.new_id()),
+        );
+        let table: HashMap<u32, crate::world::item::Item> =
+            std::iter::once((40001, definition.clone())).collect();
+        let mut fc = c
+            .get_partial_details(ids.new_id(), storage.as_ref())
+            .unwrap()
+            .into_full(&table);
+        let (world, mut world_r) = tokio::sync::mpsc::channel(10);
+        let mut w = PersistenceWorker::new(storage.clone(), world);
+
+        storage.fail_saves(1);
+        let item_id = storage.reserve_id().unwrap();
+        fc.add_item(crate::world::item::ItemInstance::new(
+            definition, item_id, 3,
+        ));
+        w.save(fc.saved());
+        w.request(5, peer(), StorageRequest::CharacterList("bob".to_string()));
+        //the first save has been tried by the time the request is answered
+        world_r.blocking_recv().unwrap();
+
+        fc.location_mut().x += 1;
+        let save = fc.saved();
+        assert!(save.items.is_empty());
+        w.save(save);
+        w.finish();
+        let items = storage.character_items(c.id()).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].id(), item_id);
+        let fc2 = storage.characters("bob").unwrap()[0]
+            .get_partial_details(ids.new_id(), storage.as_ref())
+            .unwrap()
+            .into_full(&table);
+        assert_eq!(fc2.location_ref(), fc.location_ref());
+    }
+
+    /// Checks that requests are answered in order with messages to the world, after the saves queued before them
+    #[test]
+    fn requests_are_answered() {
+        let storage = Arc::new(MemoryStorage::new());
+        let (world, mut world_r) = tokio::sync::mpsc::channel(10);
+        let mut w = PersistenceWorker::new(storage.clone(), world);
+        w.request(5, peer(), StorageRequest::CreateCharacter(roll("knight")));
+        w.request(5, peer(), StorageRequest::CreateCharacter(roll("knight")));
+        w.request(5, peer(), StorageRequest::CharacterList("bob".to_string()));
+        let mut ids = crate::world::WorldIdGenerator::new();
+        let mut answers = Vec::new();
+        for _ in 0..3 {
+            let m = world_r.blocking_recv().unwrap();
+            assert_eq!(m.sender, Some(5));
+            match m.data {
+                WorldMessageData::Storage(r) => answers.push(r),
+                d => panic!("Unexpected message {:?}", d),
+            }
+        }
+        assert!(matches!(
+            &answers[0],
+            StorageResponse::CharacterCreated(Ok(c)) if c.id() != 0
+        ));
+        assert!(matches!(
+            &answers[1],
+            StorageResponse::CharacterCreated(Err(ClientError::CharacterExists))
+        ));
+        assert!(matches!(
+            &answers[2],
+            StorageResponse::CharacterList(Ok(c)) if c.len() == 1
+        ));
+
+        let mut fc = storage.characters("bob").unwrap()[0]
+            .get_partial_details(ids.new_id(), storage.as_ref())
+            .unwrap()
+            .into_full(&HashMap::new());
+        fc.location_mut().x += 3;
+        w.save(fc.saved());
+        w.request(
+            5,
+            peer(),
+            StorageRequest::LoadCharacter {
+                account: "bob".to_string(),
+                name: "knight".to_string(),
+                world_id: ids.new_id(),
+            },
+        );
+        match world_r.blocking_recv().unwrap().data {
+            WorldMessageData::Storage(StorageResponse::CharacterLoaded {
+                character: Some(pc),
+                ..
+            }) => {
+                let loaded = pc.into_full(&HashMap::new());
+                assert_eq!(loaded.location_ref(), fc.location_ref());
+            }
+            d => panic!("Unexpected message {:?}", d),
+        }
+        w.finish();
+    }
+}
@@ -146,10 +146,12 @@ impl UpdateServer {
 /// Setup and start the update portion of the server
 pub async fn setup_update_server(
     tasks: &mut tokio::task::JoinSet<Result<(), u32>>,
+    network: &crate::NetworkConfig,
 ) -> Result<tokio::sync::oneshot::Sender<u32>, Box<dyn Error>> {
-    log::info!("update: Starting the update server");
+    let addr = network.update_socket();
+    log::info!("update: Starting the update server on {}", addr);
     let (update_tx, update_rx) = tokio::sync::oneshot::channel::<u32>();
-    let update_listener = TcpListener::bind("0.0.0.0:2003").await?;
+    let update_listener = TcpListener::bind(addr).await?;
 
     let mut updates = UpdateFiles {
         versions: HashMap::new(),
@@ -1,12 +1,13 @@
 //! Code for managing user accounts on the server
 
-use mysql::prelude::Queryable;
-
 use chrono::{TimeZone, Utc};
 use crypto::digest::Digest;
+use scrypt::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+
+use crate::storage::{Storage, StorageError};
 
 /// A user account on the server
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct UserAccount {
     /// The name for the account in the database
     name: String,
@@ -22,12 +23,61 @@ pub struct UserAccount {
     host: String,
     /// Is the account banned?
     banned: bool,
+    /// When the ban ends, None for a permanent ban
+    ban_expires: Option<chrono::DateTime<chrono::Utc>>,
+    /// Why the account was banned
+    ban_reason: Option<String>,
     /// The number of characters slots the account has?
     slot: u32,
 }
 
-/// Hash the password for the database
-pub fn hash_password(name: &str, salt: &str, pw: &str) -> String {
+/// The prefix of passwords hashed with scrypt, in the PHC string format
+const SCRYPT_PREFIX: &str = "$scrypt$";
+
+/// The prefix of the legacy scrypt hashes made with rust-crypto, these are upgraded on login
+const LEGACY_SCRYPT_PREFIX: &str = "$rscrypt$";
+
+/// The base 2 logarithm of the scrypt cost of new password hashes
+const SCRYPT_LOG_N: u8 = 14;
+
+/// Hash a password for the database with scrypt and a random salt.
+/// The result is a PHC string that records the parameters and the salt, in the form `$scrypt$ln=14,r=8,p=1$<salt>$<hash>`.
+pub fn hash_password(pw: &str) -> Result<String, StorageError> {
+    hash_password_with(pw, SCRYPT_LOG_N)
+}
+
+/// Hash a password with the given scrypt cost
+fn hash_password_with(pw: &str, log_n: u8) -> Result<String, StorageError> {
+    let params =
+        scrypt::Params::new(log_n, 8, 1, scrypt::Params::RECOMMENDED_LEN).map_err(|_| {
+            StorageError::PasswordHash(
+                scrypt::password_hash::errors::InvalidValue::Malformed.param_error(),
+            )
+        })?;
+    let salt = SaltString::generate(&mut rand::rngs::OsRng);
+    scrypt::Scrypt
+        .hash_password_customized(pw.as_bytes(), None, None, params, &salt)
+        .map(|h| h.to_string())
+        .map_err(StorageError::PasswordHash)
+}
+
+/// Check a password against a stored hash.
+/// This is either an scrypt hash, a legacy scrypt hash made with rust-crypto or a legacy hash made with the global salt.
+pub fn verify_password(name: &str, legacy_salt: &str, pw: &str, stored: &str) -> bool {
+    if stored.starts_with(SCRYPT_PREFIX) {
+        PasswordHash::new(stored)
+            .map(|h| scrypt::Scrypt.verify_password(pw.as_bytes(), &h).is_ok())
+            .unwrap_or(false)
+    } else if stored.starts_with(LEGACY_SCRYPT_PREFIX) {
+        crypto::scrypt::scrypt_check(pw, stored).unwrap_or(false)
+    } else {
+        legacy_hash_password(name, legacy_salt, pw) == stored
+    }
+}
+
+/// Hash a password with the legacy scheme, a global salt and the md5 of the account name fed into sha256.
+/// This is only used to check passwords that have not been upgraded yet.
+fn legacy_hash_password(name: &str, salt: &str, pw: &str) -> String {
     let mut md5 = crypto::md5::Md5::new();
     md5.input_str(name);
     let m = md5.result_str();
@@ -37,8 +87,37 @@ pub fn hash_password(name: &str, salt: &str, pw: &str) -> String {
     sha.result_str()
 }
 
+/// The outcome of an attempt to change the password of an account
+#[derive(Debug, PartialEq)]
+pub enum PasswordChange {
+    /// The password was changed
+    Changed,
+    /// The account does not exist
+    NoSuchAccount,
+    /// The old password was wrong
+    WrongPassword,
+}
+
+/// Change the password of an account, checking the old password first. The storage does both atomically.
+pub fn change_password(
+    account: &str,
+    salt: &str,
+    oldpass: &str,
+    newpass: &str,
+    storage: &dyn Storage,
+) -> Result<PasswordChange, StorageError> {
+    let hash = hash_password(newpass)?;
+    storage.change_password(account, &|current| {
+        if verify_password(account, salt, oldpass, current) {
+            Some(hash.clone())
+        } else {
+            None
+        }
+    })
+}
+
 /// Convert the mysql value to a usable date time
-fn convert_date(d: mysql::Value) -> chrono::DateTime<chrono::Utc> {
+pub fn convert_date(d: mysql::Value) -> chrono::DateTime<chrono::Utc> {
     let dt = match d {
         mysql::Value::Date(y, m, d, h, min, s, _micro) => {
             Utc.with_ymd_and_hms(y as i32, m as u32, d as u32, h as u32, min as u32, s as u32)
@@ -48,54 +127,115 @@ fn convert_date(d: mysql::Value) -> chrono::DateTime<chrono::Utc> {
     dt.single().or(dt.latest()).unwrap()
 }
 
+impl mysql::prelude::FromRow for UserAccount {
+    fn from_row_opt(row: mysql::Row) -> Result<Self, mysql::FromRowError>
+    where
+        Self: Sized,
+    {
+        let banned: u32 = row.get("banned").ok_or(mysql::FromRowError(row.clone()))?;
+        let active: mysql::Value = row
+            .get("lastactive")
+            .ok_or(mysql::FromRowError(row.clone()))?;
+        let ban_expires: mysql::Value = row
+            .get("ban_expires")
+            .ok_or(mysql::FromRowError(row.clone()))?;
+        Ok(Self {
+            name: row.get("login").ok_or(mysql::FromRowError(row.clone()))?,
+            password: row
+                .get("password")
+                .ok_or(mysql::FromRowError(row.clone()))?,
+            active: convert_date(active),
+            access: row
+                .get("access_level")
+                .ok_or(mysql::FromRowError(row.clone()))?,
+            ip: row.get("ip").ok_or(mysql::FromRowError(row.clone()))?,
+            host: row.get("host").ok_or(mysql::FromRowError(row.clone()))?,
+            banned: banned != 0,
+            ban_expires: if ban_expires == mysql::Value::NULL {
+                None
+            } else {
+                Some(convert_date(ban_expires))
+            },
+            ban_reason: row
+                .get("ban_reason")
+                .ok_or(mysql::FromRowError(row.clone()))?,
+            slot: row
+                .get("character_slot")
+                .ok_or(mysql::FromRowError(row.clone()))?,
+        })
+    }
+}
+
+impl From<&UserAccount> for mysql::Params {
+    fn from(value: &UserAccount) -> Self {
+        mysql::Params::Positional(vec![
+            value.name.clone().into(),
+            value.password.clone().into(),
+            mysql::Value::Date(2010, 3, 5, 4, 5, 6, 100),
+            value.access.into(),
+            value.ip.clone().into(),
+            value.host.clone().into(),
+            (if value.banned { 1 } else { 0 }).into(),
+            value.slot.into(),
+        ])
+    }
+}
+
 /// Get a user account from the db, if it exists
-pub fn get_user_details(user: String, mysql: &mut mysql::PooledConn) -> Option<UserAccount> {
-    let query = "SELECT login, password, access_level, ip, host, banned, character_slot, lastactive from accounts WHERE login=? LIMIT 1";
-    let usertest = mysql.exec_map(
-        query,
-        (user,),
-        |(a, pw, acc, ipa, h, b, slot, d): (
-            String,
-            String,
-            u32,
-            String,
-            String,
-            u32,
-            u32,
-            mysql::Value,
-        )| {
-            UserAccount {
-                name: a,
-                password: pw,
-                active: convert_date(d),
-                access: acc,
-                ip: ipa,
-                host: h,
-                banned: b != 0,
-                slot: slot,
-            }
-        },
-    );
-    usertest.unwrap().pop()
+pub fn get_user_details(user: &str, storage: &dyn Storage) -> Option<UserAccount> {
+    match storage.account(user) {
+        Ok(a) => a,
+        Err(e) => {
+            log::error!("Failed to get account {}: {:?}", user, e);
+            None
+        }
+    }
 }
 
 impl UserAccount {
-    /// Check login to see if the password was correct
+    /// Check login to see if the password was correct. The salt is only used for legacy password hashes.
     pub fn check_login(&self, salt: &str, pw: &str) -> bool {
-        let hash = hash_password(&self.name, salt, pw);
-        hash == self.password
+        verify_password(&self.name, salt, pw, &self.password)
+    }
+
+    /// Is the password stored with one of the legacy hashes?
+    pub fn has_legacy_hash(&self) -> bool {
+        !self.password.starts_with(SCRYPT_PREFIX)
+    }
+
+    /// Hash the password again with the current scheme and store it. Used after a successful login with a legacy hash.
+    pub fn upgrade_password(
+        &mut self,
+        pw: &str,
+        storage: &dyn Storage,
+    ) -> Result<(), StorageError> {
+        let hash = hash_password(pw)?;
+        storage.set_password(&self.name, &hash)?;
+        self.set_password_hash(hash);
+        Ok(())
+    }
+
+    /// Replace the stored password hash
+    pub fn set_password_hash(&mut self, hash: String) {
+        self.password = hash;
+    }
+
+    /// Get the stored password hash
+    pub fn password_hash(&self) -> &str {
+        &self.password
     }
 
     /// Delete the specified character from the account
-    pub fn delete_char(&mut self, name: &str, mysql: &mut mysql::PooledConn) {
-        let query = "DELETE FROM characters WHERE account_name=? AND char_name=?";
-        let _ = mysql.exec_drop(query, (&self.name, name));
+    pub fn delete_char(&mut self, name: &str, storage: &dyn Storage) {
+        if let Err(e) = storage.delete_character(&self.name, name) {
+            log::error!("Failed to delete character {}: {:?}", name, e);
+        }
     }
 
-    /// Construct a new user account
-    pub fn new(name: String, pass: String, ip: String, salt: String) -> Self {
-        let hashpass = hash_password(&name, &salt, &pass);
-        Self {
+    /// Construct a new user account, failing when the password can not be hashed
+    pub fn new(name: String, pass: String, ip: String) -> Result<Self, StorageError> {
+        let hashpass = hash_password(&pass)?;
+        Ok(Self {
             name: name.clone(),
             password: hashpass,
             active: Utc.with_ymd_and_hms(1970, 1, 1, 0, 0, 1).single().unwrap(),
@@ -103,8 +243,10 @@ impl UserAccount {
             ip: ip.clone(),
             host: ip.clone(),
             banned: false,
+            ban_expires: None,
+            ban_reason: None,
             slot: 0,
-        }
+        })
     }
 
     /// Get the account name
@@ -112,33 +254,75 @@ impl UserAccount {
         &self.name
     }
 
+    /// Is the account banned at the given time? A ban that has expired does not count.
+    pub fn is_banned(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.banned && self.ban_expires.map(|e| e > now).unwrap_or(true)
+    }
+
+    /// Is the account still marked as banned, even though the ban has expired?
+    pub fn ban_expired(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.banned && !self.is_banned(now)
+    }
+
+    /// Why the account was banned, if a reason was given
+    pub fn ban_reason(&self) -> Option<&str> {
+        self.ban_reason.as_deref()
+    }
+
+    /// Ban the account until the given time, or forever when there is no expiry
+    pub fn ban(
+        &mut self,
+        expires: Option<chrono::DateTime<chrono::Utc>>,
+        reason: &str,
+        storage: &dyn Storage,
+    ) -> Result<(), StorageError> {
+        storage.ban_account(&self.name, expires, reason)?;
+        self.set_ban(expires, reason);
+        Ok(())
+    }
+
+    /// Mark the account as banned, without storing it
+    pub fn set_ban(&mut self, expires: Option<chrono::DateTime<chrono::Utc>>, reason: &str) {
+        self.banned = true;
+        self.ban_expires = expires;
+        self.ban_reason = Some(reason.to_string());
+    }
+
+    /// Remove the ban from the account
+    pub fn unban(&mut self, storage: &dyn Storage) -> Result<(), StorageError> {
+        storage.unban_account(&self.name)?;
+        self.clear_ban();
+        Ok(())
+    }
+
+    /// Mark the account as not banned, without storing it
+    pub fn clear_ban(&mut self) {
+        self.banned = false;
+        self.ban_expires = None;
+        self.ban_reason = None;
+    }
+
+    /// Set the time and address of the last login, without storing it
+    pub fn set_login(&mut self, when: chrono::DateTime<chrono::Utc>, ip: &str) {
+        self.active = when;
+        self.host = ip.to_string();
+        self.ip = ip.to_string();
+    }
+
     /// Retrieve characters for user account from database
     pub fn retrieve_chars(
         &self,
-        mysql: &mut mysql::PooledConn,
+        storage: &dyn Storage,
     ) -> Result<Vec<crate::character::Character>, crate::server::ClientError> {
-        crate::character::Character::retrieve_chars(&self.name, mysql)
+        crate::character::Character::retrieve_chars(&self.name, storage)
     }
 
     /// Insert a new account into the database
-    pub fn insert_into_db(&self, mysql: &mut mysql::PooledConn) {
-        let query = "INSERT INTO accounts SET login=?,password=?,lastactive=?,access_level=?,ip=?,host=?,banned=?,character_slot=?";
-        let tq = mysql.exec_drop(
-            query,
-            (
-                self.name.clone(),
-                self.password.clone(),
-                mysql::Value::Date(2010, 3, 5, 4, 5, 6, 100),
-                self.access,
-                self.ip.clone(),
-                self.host.clone(),
-                if self.banned { 1 } else { 0 },
-                self.slot,
-            ),
-        );
+    pub fn insert_into_db(&self, storage: &dyn Storage) {
+        let tq = storage.insert_account(self);
         match tq {
             Err(e) => {
-                log::info!("error inserting account {}", e);
+                log::info!("error inserting account {:?}", e);
             }
             _ => {
                 log::info!("account insertion is fine");
@@ -146,3 +330,38 @@ impl UserAccount {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that both scrypt and legacy hashes are verified
+    #[test]
+    fn verify() {
+        let h = hash_password_with("secret", 4).unwrap();
+        assert!(h.starts_with("$scrypt$ln=4,r=8,p=1$"));
+        assert_ne!(h, hash_password_with("secret", 4).unwrap());
+        assert!(verify_password("user", "salt", "secret", &h));
+        assert!(!verify_password("user", "salt", "wrong", &h));
+        assert!(!verify_password(
+            "user",
+            "salt",
+            "secret",
+            "$scrypt$garbage"
+        ));
+        let old =
+            crypto::scrypt::scrypt_simple("secret", &crypto::scrypt::ScryptParams::new(4, 8, 1))
+                .unwrap();
+        assert!(verify_password("user", "salt", "secret", &old));
+        assert!(!verify_password("user", "salt", "wrong", &old));
+        let legacy = legacy_hash_password("user", "salt", "secret");
+        assert!(verify_password("user", "salt", "secret", &legacy));
+        assert!(!verify_password("user", "pepper", "secret", &legacy));
+        assert!(!verify_password(
+            "user",
+            "salt",
+            "secret",
+            "$rscrypt$garbage"
+        ));
+    }
+}
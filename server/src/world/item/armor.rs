@@ -2,6 +2,7 @@
 
 use super::{ItemStuff, ItemTrait, ItemUsage};
 use crate::world::WorldObjectId;
+use common::item::{ItemDescriptor, Material};
 
 /// The type of armor
 #[derive(Copy, Clone, Debug)]
@@ -75,6 +76,10 @@ pub struct Armor {
     max_use_time: u32,
     /// The armor type
     atype: ArmorType,
+    /// The material the armor is made of
+    material: Material,
+    /// The armor class of the armor
+    ac: i32,
 }
 
 impl Armor {
@@ -93,6 +98,9 @@ impl mysql::prelude::FromRow for Armor {
         Self: Sized,
     {
         let at: String = row.get("type").ok_or(mysql::FromRowError(row.clone()))?;
+        let material: String = row
+            .get("material")
+            .ok_or(mysql::FromRowError(row.clone()))?;
         Ok(Self {
             id: row.get(0).ok_or(mysql::FromRowError(row.clone()))?,
             weight: row.get(6).ok_or(mysql::FromRowError(row.clone()))?,
@@ -102,6 +110,8 @@ impl mysql::prelude::FromRow for Armor {
             identified: row.get(3).ok_or(mysql::FromRowError(row.clone()))?,
             max_use_time: row.get(43).ok_or(mysql::FromRowError(row.clone()))?,
             atype: at.into(),
+            material: super::material_from_name(&material),
+            ac: row.get("ac").ok_or(mysql::FromRowError(row.clone()))?,
         })
     }
 }
@@ -115,6 +125,26 @@ pub struct ArmorInstance {
     world_id: WorldObjectId,
 }
 
+impl ArmorInstance {
+    /// Build the extended description of the armor
+    fn extended_description(&self, stuff: &ItemStuff) -> Vec<u8> {
+        if !stuff.identified {
+            return Vec::new();
+        }
+        let mut d = vec![ItemDescriptor::ArmorClass {
+            ac: self.definition.ac.unsigned_abs() as u8,
+            //TODO the grade is not in the database
+            grade: 0,
+            material: self.definition.material,
+            weight: self.definition.weight,
+        }];
+        if stuff.enchanted_level > 0 {
+            d.push(ItemDescriptor::Enchant(stuff.enchanted_level as u8));
+        }
+        ItemDescriptor::encode(&d)
+    }
+}
+
 impl ItemTrait for ArmorInstance {
     fn world_id(&self) -> WorldObjectId {
         self.world_id
@@ -146,7 +176,7 @@ impl ItemTrait for ArmorInstance {
             id: stuff.item_id,
             description: self.name(stuff),
             count: stuff.count,
-            ed: Vec::new(),
+            ed: self.extended_description(stuff),
         }
     }
 
@@ -190,7 +220,7 @@ impl ItemTrait for ArmorInstance {
             count: stuff.count,
             identified: if stuff.identified { 1 } else { 0 },
             description,
-            ed: Vec::new(),
+            ed: self.extended_description(stuff),
         }
     }
 }
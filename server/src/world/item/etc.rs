@@ -2,6 +2,7 @@
 
 use super::{ItemStuff, ItemTrait, ItemUsage};
 use crate::world::WorldObjectId;
+use common::item::{ItemDescriptor, Material};
 
 /// The item type for etc items
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -116,6 +117,8 @@ pub struct EtcItem {
     identified: String,
     /// Item usage
     usage: ItemUsage,
+    /// The material the item is made of
+    material: Material,
 }
 
 impl EtcItem {
@@ -139,6 +142,26 @@ impl EtcItem {
     }
 }
 
+#[cfg(test)]
+impl EtcItem {
+    /// A plain item with the given definition id, for tests
+    pub fn plain(id: u32) -> Self {
+        Self {
+            id,
+            world_id: None,
+            itype: EtcItemType::Other,
+            weight: 0,
+            inventory_graphic: 0,
+            ground_graphic: 0,
+            max_charge_count: 0,
+            unidentified: "item".to_string(),
+            identified: "item".to_string(),
+            usage: ItemUsage::None,
+            material: Material::Liquid,
+        }
+    }
+}
+
 impl mysql::prelude::FromRow for EtcItem {
     fn from_row_opt(row: mysql::Row) -> Result<Self, mysql::FromRowError>
     where
@@ -146,6 +169,9 @@ impl mysql::prelude::FromRow for EtcItem {
     {
         let itype: String = row.get(4).ok_or(mysql::FromRowError(row.clone()))?;
         let usage: String = row.get(5).ok_or(mysql::FromRowError(row.clone()))?;
+        let material: String = row
+            .get("material")
+            .ok_or(mysql::FromRowError(row.clone()))?;
         Ok(Self {
             id: row.get(0).ok_or(mysql::FromRowError(row.clone()))?,
             world_id: None,
@@ -157,6 +183,7 @@ impl mysql::prelude::FromRow for EtcItem {
             identified: row.get(3).ok_or(mysql::FromRowError(row.clone()))?,
             itype: itype.as_str().into(),
             usage: usage.as_str().into(),
+            material: super::material_from_name(&material),
         })
     }
 }
@@ -170,6 +197,19 @@ pub struct EtcItemInstance {
     world_id: WorldObjectId,
 }
 
+impl EtcItemInstance {
+    /// Build the extended description of the item
+    fn extended_description(&self, stuff: &ItemStuff) -> Vec<u8> {
+        if !stuff.identified {
+            return Vec::new();
+        }
+        ItemDescriptor::encode(&[ItemDescriptor::Weight {
+            material: self.definition.material,
+            weight: self.definition.weight,
+        }])
+    }
+}
+
 impl ItemTrait for EtcItemInstance {
     fn world_id(&self) -> WorldObjectId {
         self.world_id
@@ -200,7 +240,7 @@ impl ItemTrait for EtcItemInstance {
             id: stuff.item_id,
             description: self.name(stuff),
             count: stuff.count,
-            ed: Vec::new(),
+            ed: self.extended_description(stuff),
         }
     }
 
@@ -264,7 +304,7 @@ impl ItemTrait for EtcItemInstance {
             count: stuff.count,
             identified: if stuff.identified { 1 } else { 0 },
             description,
-            ed: Vec::new(),
+            ed: self.extended_description(stuff),
         }
     }
 }
@@ -46,8 +46,39 @@ pub trait ItemTrait {
     fn get_type(&self) -> ItemType;
 }
 
+/// Convert the name of a material from the database into a material
+pub fn material_from_name(name: &str) -> common::item::Material {
+    use common::item::Material;
+    let name = name.split('(').next().unwrap_or("").trim();
+    match name {
+        "liquid" => Material::Liquid,
+        "web" => Material::Web,
+        "vegetation" => Material::Vegetation,
+        "animalmatter" => Material::AnimalMatter,
+        "paper" => Material::Paper,
+        "cloth" => Material::Cloth,
+        "leather" => Material::Leather,
+        "wood" => Material::Wood,
+        "bone" => Material::Bone,
+        "dragonscale" => Material::DragonScale,
+        "iron" => Material::Iron,
+        "metal" => Material::Metal,
+        "copper" => Material::Copper,
+        "silver" => Material::Silver,
+        "gold" => Material::Gold,
+        "platinum" => Material::Platinum,
+        "mithril" => Material::Mithril,
+        "blackmithril" => Material::BlackMithril,
+        "glass" => Material::Glass,
+        "gemstone" => Material::Gemstone,
+        "mineral" => Material::Mineral,
+        "oriharukon" => Material::Oriharukon,
+        _ => Material::Other(0),
+    }
+}
+
 /// The elemental types that an item can be enchanted with
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug)]
 #[repr(u8)]
 pub enum ElementalEnchantType {
     /// Earth elemental
@@ -229,14 +260,113 @@ pub struct ItemInstance {
     id: u32,
     /// The item configuration details
     stuff: ItemStuff,
+    /// Has the item changed since it was last saved
+    dirty: bool,
+}
+
+/// An item instance as it is written to storage
+#[derive(Clone, Debug)]
+pub struct SavedItem {
+    /// The id of the character holding the item
+    pub char_id: u32,
+    /// The plain name of the item, for people reading the database
+    pub name: String,
+    /// The item
+    pub item: ItemInstanceWithoutDefinition,
+}
+
+/// The changes to the items of a character that still need to be written to storage
+#[derive(Clone, Debug, Default)]
+pub struct ItemChanges {
+    /// Items that are not in storage yet, by item id
+    pub inserted: HashMap<u32, SavedItem>,
+    /// Items in storage that have changed, by item id
+    pub updated: HashMap<u32, SavedItem>,
+    /// Items that need to be removed from storage
+    pub deleted: HashSet<u32>,
+}
+
+impl ItemChanges {
+    /// Are there no changes
+    pub fn is_empty(&self) -> bool {
+        self.inserted.is_empty() && self.updated.is_empty() && self.deleted.is_empty()
+    }
+
+    /// Combine with changes that were made after these changes, so writing the result is the same as writing both in order
+    pub fn merge(&mut self, newer: ItemChanges) {
+        for id in newer.deleted {
+            self.updated.remove(&id);
+            if self.inserted.remove(&id).is_none() {
+                self.deleted.insert(id);
+            }
+        }
+        self.inserted.extend(newer.inserted);
+        for (id, i) in newer.updated {
+            if let Some(e) = self.inserted.get_mut(&id) {
+                *e = i;
+            } else {
+                self.updated.insert(id, i);
+            }
+        }
+    }
 }
 
 impl ItemInstance {
+    /// Create a new instance of an item, it has not been saved yet
+    /// # Arguments:
+    /// * definition - The definition of the item
+    /// * item_id - The id of the new item, from [crate::storage::Storage::reserve_id]
+    /// * count - How many of the item there are
+    pub fn new(definition: Item, item_id: u32, count: u32) -> Self {
+        Self {
+            id: definition.db_id(),
+            definition,
+            stuff: ItemStuff {
+                item_id,
+                count,
+                equipped: false,
+                enchanted_level: 0,
+                identified: false,
+                durability: 0,
+                blessed: 1,
+                charges: 0,
+                time_remaining: 0,
+                elemental_enchant: None,
+            },
+            dirty: true,
+        }
+    }
+
     /// Get the item id
     pub fn id(&self) -> u32 {
         self.stuff.item_id
     }
 
+    /// Has the item changed since it was last saved
+    pub fn dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Take a copy of the item to be saved, the item is no longer dirty afterwards
+    pub fn take_saved(&mut self, char_id: u32) -> SavedItem {
+        self.dirty = false;
+        let plain = ItemStuff {
+            count: 1,
+            equipped: false,
+            enchanted_level: 0,
+            identified: true,
+            ..self.stuff.clone()
+        };
+        SavedItem {
+            char_id,
+            name: self.definition.name(&plain),
+            item: ItemInstanceWithoutDefinition {
+                id: self.id,
+                stuff: self.stuff.clone(),
+            },
+        }
+    }
+
     /// Is the item equipped
     pub fn equipped(&self) -> bool {
         self.stuff.equipped
@@ -250,6 +380,7 @@ impl ItemInstance {
     /// Toggle if the item is equipped or not
     pub fn toggle_equip(&mut self) {
         self.stuff.equipped = !self.stuff.equipped;
+        self.dirty = true;
     }
 
     /// Get the item definition
@@ -298,6 +429,7 @@ impl ItemInstanceWithoutDefinition {
                 definition: item.to_owned(),
                 id: self.id,
                 stuff: self.stuff,
+                dirty: false,
             })
         } else {
             log::error!("Item {} not found in table", self.id);
@@ -350,6 +482,31 @@ impl mysql::prelude::FromRow for ItemStuff {
     }
 }
 
+/// The parameters for inserting or updating an item, in the order item_id, char_id, item_name, count, is_equipped, enchantlvl,
+/// is_id, durability, bless, attr_enchant_kind, attr_enchant_level, charge_count, remaining_time, id
+impl From<&SavedItem> for mysql::Params {
+    fn from(value: &SavedItem) -> Self {
+        let s = &value.item.stuff;
+        let p = vec![
+            value.item.id.into(),
+            value.char_id.into(),
+            value.name.clone().into(),
+            s.count.into(),
+            s.equipped.into(),
+            s.enchanted_level.into(),
+            s.identified.into(),
+            s.durability.into(),
+            s.blessed.into(),
+            s.elemental_enchant.map(|(t, _)| t as u8).into(),
+            s.elemental_enchant.map(|(_, l)| l).into(),
+            s.charges.into(),
+            s.time_remaining.into(),
+            s.item_id.into(),
+        ];
+        mysql::Params::Positional(p)
+    }
+}
+
 impl mysql::prelude::FromRow for ItemInstanceWithoutDefinition {
     fn from_row_opt(row: mysql::Row) -> Result<Self, mysql::FromRowError>
     where
@@ -509,3 +666,58 @@ impl ObjectTrait for ItemWithLocation {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a saved item with a count
+    fn saved(item_id: u32, count: u32) -> SavedItem {
+        SavedItem {
+            char_id: 2,
+            name: "dagger".to_string(),
+            item: ItemInstanceWithoutDefinition {
+                id: 1,
+                stuff: ItemStuff {
+                    item_id,
+                    count,
+                    equipped: false,
+                    enchanted_level: 0,
+                    identified: true,
+                    durability: 0,
+                    blessed: 1,
+                    charges: 0,
+                    time_remaining: 0,
+                    elemental_enchant: None,
+                },
+            },
+        }
+    }
+
+    /// Checks that merging item changes gives the same result as writing them in order
+    #[test]
+    fn merge_changes() {
+        let mut a = ItemChanges::default();
+        a.inserted.insert(10, saved(10, 1));
+        a.updated.insert(11, saved(11, 1));
+        a.updated.insert(12, saved(12, 1));
+        let mut b = ItemChanges::default();
+        b.updated.insert(10, saved(10, 5));
+        b.updated.insert(11, saved(11, 5));
+        b.deleted.insert(12);
+        b.deleted.insert(13);
+        a.merge(b);
+        assert_eq!(a.inserted.len(), 1);
+        assert_eq!(a.inserted[&10].item.stuff.count, 5);
+        assert_eq!(a.updated.len(), 1);
+        assert_eq!(a.updated[&11].item.stuff.count, 5);
+        assert_eq!(a.deleted, vec![12, 13].into_iter().collect());
+
+        let mut c = ItemChanges::default();
+        c.deleted.insert(10);
+        a.merge(c);
+        assert!(a.inserted.is_empty());
+        assert!(!a.deleted.contains(&10));
+        assert!(!a.is_empty());
+    }
+}
@@ -2,6 +2,7 @@
 
 use super::super::{ItemTrait, WorldObjectId};
 use super::{ElementalEnchantType, ItemStuff, ItemUsage};
+use common::item::{ItemDescriptor, Material};
 
 /// The types of weapons
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -110,6 +111,12 @@ pub struct Weapon {
     hit_rate_bonus: i16,
     /// The weapon type
     wtype: WeaponType,
+    /// The material the weapon is made of
+    material: Material,
+    /// Damage against small targets
+    dmg_small: u8,
+    /// Damage against large targets
+    dmg_large: u8,
 }
 
 impl Weapon {
@@ -131,6 +138,9 @@ impl mysql::prelude::FromRow for Weapon {
         Self: Sized,
     {
         let wt: String = row.get("type").ok_or(mysql::FromRowError(row.clone()))?;
+        let material: String = row
+            .get("material")
+            .ok_or(mysql::FromRowError(row.clone()))?;
         Ok(Self {
             id: row.get(0).ok_or(mysql::FromRowError(row.clone()))?,
             weight: row.get(6).ok_or(mysql::FromRowError(row.clone()))?,
@@ -143,6 +153,13 @@ impl mysql::prelude::FromRow for Weapon {
                 .get("hitmodifier")
                 .ok_or(mysql::FromRowError(row.clone()))?,
             wtype: wt.into(),
+            material: super::material_from_name(&material),
+            dmg_small: row
+                .get("dmg_small")
+                .ok_or(mysql::FromRowError(row.clone()))?,
+            dmg_large: row
+                .get("dmg_large")
+                .ok_or(mysql::FromRowError(row.clone()))?,
         })
     }
 }
@@ -226,6 +243,48 @@ impl WeaponInstance {
         false
     }
 
+    /// Is the weapon held with both hands
+    pub fn is_two_handed(&self) -> bool {
+        matches!(
+            self.definition.wtype,
+            WeaponType::TwoHandSword
+                | WeaponType::Bow
+                | WeaponType::Spear
+                | WeaponType::Staff
+                | WeaponType::Claw
+                | WeaponType::Edoryu
+                | WeaponType::TwoHandBlunt
+                | WeaponType::TwoHandStaff
+                | WeaponType::Kiringku
+                | WeaponType::ChainSword
+        )
+    }
+
+    /// Build the extended description of the weapon
+    fn extended_description(&self, stuff: &ItemStuff) -> Vec<u8> {
+        if !stuff.identified {
+            return Vec::new();
+        }
+        let mut d = vec![ItemDescriptor::Damage {
+            small: self.definition.dmg_small,
+            large: self.definition.dmg_large,
+            material: self.definition.material,
+            weight: self.definition.weight,
+        }];
+        if stuff.enchanted_level > 0 {
+            d.push(ItemDescriptor::Enchant(stuff.enchanted_level as u8));
+        }
+        if self.is_two_handed() {
+            d.push(ItemDescriptor::TwoHanded);
+        }
+        if self.definition.hit_rate_bonus > 0 {
+            d.push(ItemDescriptor::HitBonus(
+                self.definition.hit_rate_bonus as u8,
+            ));
+        }
+        ItemDescriptor::encode(&d)
+    }
+
     /// What is the range of this weapon in map units?
     pub fn range(&self) -> u8 {
         ///TODO
@@ -263,7 +322,7 @@ impl ItemTrait for WeaponInstance {
             id: stuff.item_id,
             description: self.name(stuff),
             count: stuff.count,
-            ed: Vec::new(),
+            ed: self.extended_description(stuff),
         }
     }
 
@@ -337,7 +396,7 @@ impl ItemTrait for WeaponInstance {
             count: stuff.count,
             identified: if stuff.identified { 1 } else { 0 },
             description,
-            ed: Vec::new(),
+            ed: self.extended_description(stuff),
         }
     }
 }
@@ -8,12 +8,20 @@ pub mod monster;
 pub mod npc;
 pub mod object;
 
-use common::packet::{ClientPacket, ServerPacket, ServerPacketSender};
+use common::packet::{
+    CharacterCreationStatus, ClientPacket, LoginResult, MessageId, ServerPacket,
+    ServerPacketSender, Weather,
+};
 
 use crate::{
-    character::{Character, FullCharacter, Location},
+    character::Location,
     server::ClientError,
-    user::UserAccount,
+    storage::{
+        passwords::{PasswordRequest, PasswordWorkers},
+        persistence::{LoginCheck, PersistenceWorker, StorageRequest, StorageResponse},
+        Storage,
+    },
+    user::{PasswordChange, UserAccount},
     world::{
         item::ItemTrait,
         object::{Damage, ObjectList, ObjectTrait},
@@ -75,6 +83,31 @@ pub struct Map {
 }
 
 impl Map {
+    /// Construct a map where everything is allowed, used when there is no map table
+    pub fn new(id: u16, name: &str, min_x: u16, max_x: u16, min_y: u16, max_y: u16) -> Self {
+        Self {
+            id,
+            name: name.to_string(),
+            min_x,
+            max_x,
+            min_y,
+            max_y,
+            monster_rate: 1.0,
+            drop_rate: 1.0,
+            underwater: false,
+            bookmarkable: true,
+            random_teleport: true,
+            escapable: true,
+            resurrection: true,
+            spawn_monster: true,
+            death_exp_penalty: true,
+            pets: true,
+            summon_monster: true,
+            item_usage: true,
+            skill_usage: true,
+        }
+    }
+
     /// Can players use items on this map?
     pub fn can_use_items(&self) -> bool {
         self.item_usage
@@ -132,15 +165,21 @@ impl mysql::prelude::FromRow for Map {
     }
 }
 
+/// The first id handed out to objects that only exist in the world.
+/// Ids below this are for objects in the database, so the ids the client sees for items and world objects never collide.
+pub const FIRST_WORLD_ID: u32 = 0x4000_0000;
+
+/// Hands out the ids of objects in the world
 pub struct WorldIdGenerator {
+    /// The next id to hand out
     next: WorldObjectId,
 }
 
 impl WorldIdGenerator {
-    /// Build a new generator
-    pub fn new(start: u32) -> Self {
+    /// Build a new generator, starting at [FIRST_WORLD_ID]
+    pub fn new() -> Self {
         Self {
-            next: WorldObjectId(start),
+            next: WorldObjectId(FIRST_WORLD_ID),
         }
     }
 
@@ -152,6 +191,12 @@ impl WorldIdGenerator {
     }
 }
 
+impl Default for WorldIdGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug)]
 /// A message to the world
 pub enum WorldMessageData {
@@ -163,6 +208,12 @@ pub enum WorldMessageData {
     RegisterSender(tokio::sync::mpsc::Sender<WorldResponse>),
     /// Register a monster
     RegisterMonster(monster::Monster),
+    /// Save all characters that are online
+    Autosave,
+    /// Save all characters and stop the world
+    Shutdown,
+    /// The answer to a storage request made for the sender
+    Storage(StorageResponse),
 }
 
 #[derive(Debug)]
@@ -186,6 +237,17 @@ pub struct ItemUseData {
     pub packets: Vec<ServerPacket>,
 }
 
+/// Sent when a password can not be checked because too many are waiting already
+const BUSY_MESSAGE: &str = "The server is busy, please try again in a moment";
+
+/// A login that is waiting for its password to be checked
+struct PendingLogin {
+    /// The account name
+    account: String,
+    /// Matches the answer to the attempt, client ids are handed out again after a client leaves
+    attempt: u32,
+}
+
 /// Represents the world for a server
 pub struct World {
     /// The users logged into the world
@@ -198,10 +260,18 @@ pub struct World {
     object_ref_table: HashMap<WorldObjectId, ObjectRef>,
     /// A lookup table to convert client ids to user accounts
     account_table: HashMap<u32, UserAccount>,
+    /// When each logged in client logged in, to find the oldest sessions of an account
+    login_times: HashMap<u32, std::time::Instant>,
     /// The object id for each character (covers monsters and players)
     characters: HashMap<u32, WorldObjectId>,
-    /// The connection to the database
-    mysql: mysql::Pool,
+    /// The storage for accounts, characters and game data
+    storage: Arc<dyn Storage>,
+    /// Does the storage work of the world without blocking it
+    persistence: PersistenceWorker,
+    /// Checks and hashes passwords without blocking the world
+    passwords: PasswordWorkers,
+    /// The tasks that run on a timer, such as the autosave
+    timer_tasks: Vec<tokio::task::JoinHandle<()>>,
     /// maps of the world
     maps: HashMap<u16, Map>,
     /// dynamic information for all maps
@@ -226,6 +296,18 @@ pub struct World {
     sender: tokio::sync::mpsc::Sender<WorldMessage>,
     /// Configuration from the server config
     config: crate::ServerConfiguration,
+    /// Failed password changes from each ip address
+    password_ip_failures: crate::failure_limit::FailureLimiter<std::net::IpAddr>,
+    /// Failed password changes for each account
+    password_account_failures: crate::failure_limit::FailureLimiter<String>,
+    /// Failed logins from each ip address
+    login_ip_failures: crate::failure_limit::FailureLimiter<std::net::IpAddr>,
+    /// Failed logins for each account
+    login_account_failures: crate::failure_limit::FailureLimiter<String>,
+    /// The logins waiting for their password to be checked, by client id
+    pending_logins: HashMap<u32, PendingLogin>,
+    /// The number for the next login attempt
+    next_login_attempt: u32,
 }
 
 impl Drop for World {
@@ -240,28 +322,34 @@ impl Drop for World {
 impl World {
     /// Construct a new server world
     pub fn new(
-        mysql: mysql::Pool,
+        storage: Arc<dyn Storage>,
         server_s: tokio::sync::mpsc::Sender<crate::server_message::ServerShutdownMessage>,
         recv: tokio::sync::mpsc::Receiver<WorldMessage>,
         sender: tokio::sync::mpsc::Sender<WorldMessage>,
         config: crate::ServerConfiguration,
     ) -> Result<Self, String> {
-        let mut conn = mysql.get_conn().map_err(|e| format!("{:?}", e))?;
-        let npc_spawn_table =
-            npc::NpcSpawn::load_table(&mut conn).map_err(|e| format!("{:?}", e))?;
-        let monster_spawn_table =
-            monster::MonsterSpawn::load_table(&mut conn).map_err(|e| format!("{:?}", e))?;
-        let (mapd, mapi) = Self::load_maps_data(&mut conn)?;
-        let mut id_generator = WorldIdGenerator::new(1);
-        let items = Self::load_item_data(&mut conn, &mut id_generator)?;
-        let npc = npc::NpcDefinition::load_table(&mut conn)?;
+        let npc_spawn_table = storage.npc_spawns().map_err(|e| format!("{:?}", e))?;
+        let monster_spawn_table = storage.monster_spawns().map_err(|e| format!("{:?}", e))?;
+        let (mapd, mapi) = Self::load_maps_data(storage.as_ref())?;
+        let mut id_generator = WorldIdGenerator::new();
+        let items = Self::load_item_data(storage.as_ref(), &mut id_generator)?;
+        let npc = storage
+            .npc_definitions()
+            .map_err(|e| format!("{:?}", e))?
+            .into_iter()
+            .map(|n| (n.id, n))
+            .collect();
         let mut w = Self {
             users: HashMap::new(),
             client_ids: crate::ClientList::new(),
             object_ref_table: HashMap::new(),
             account_table: HashMap::new(),
+            login_times: HashMap::new(),
             characters: HashMap::new(),
-            mysql,
+            persistence: PersistenceWorker::new(storage.clone(), sender.clone()),
+            passwords: PasswordWorkers::new(storage.clone(), sender.clone()),
+            timer_tasks: Vec::new(),
+            storage,
             maps: mapd,
             map_info: mapi,
             item_table: items,
@@ -274,6 +362,18 @@ impl World {
             object_senders: HashMap::new(),
             recv,
             sender,
+            password_ip_failures: crate::failure_limit::FailureLimiter::new(
+                config.password_change_max_failures,
+                std::time::Duration::from_secs(config.password_change_window),
+            ),
+            password_account_failures: crate::failure_limit::FailureLimiter::new(
+                config.password_change_max_failures,
+                std::time::Duration::from_secs(config.password_change_window),
+            ),
+            login_ip_failures: config.login_throttle.limiter(),
+            login_account_failures: config.login_throttle.limiter(),
+            pending_logins: HashMap::new(),
+            next_login_attempt: 0,
             config,
         };
         {
@@ -319,49 +419,607 @@ impl World {
     fn login_with_news(
         &mut self,
         id: u32,
+        peer: std::net::SocketAddr,
         username: String,
         s: &mut tokio::sync::mpsc::Sender<WorldResponse>,
     ) -> Result<(), ClientError> {
         s.blocking_send(WorldResponse::ServerPacket(ServerPacket::LoginResult {
-            code: 0,
+            code: LoginResult::Ok,
         }));
         let news = self.config.get_news();
         if news.is_empty() {
-            self.after_news(id, s)?;
+            self.after_news(id, peer);
         } else {
             s.blocking_send(WorldResponse::ServerPacket(ServerPacket::News(news)));
         }
         Ok(())
     }
 
-    /// Send the client details that happens after the news (if there was any news at all)
-    /// This still should be called even if there was no news.
-    fn after_news(
+    /// Send a login result to a client
+    fn send_login_result(s: &tokio::sync::mpsc::Sender<WorldResponse>, code: LoginResult) {
+        let _ = s.blocking_send(WorldResponse::ServerPacket(ServerPacket::LoginResult {
+            code,
+        }));
+    }
+
+    /// Send a system message to a client
+    fn send_system_message(s: &tokio::sync::mpsc::Sender<WorldResponse>, message: String) {
+        let _ = s.blocking_send(WorldResponse::ServerPacket(ServerPacket::SystemMessage(
+            message,
+        )));
+    }
+
+    /// Process a login attempt from a client
+    fn login(
         &mut self,
-        id: u32,
-        s: &mut tokio::sync::mpsc::Sender<WorldResponse>,
+        sender: u32,
+        peer: std::net::SocketAddr,
+        username: String,
+        password: String,
     ) -> Result<(), ClientError> {
-        if let Some(account) = self.account_table.get(&id) {
-            let mut conn = self.get_mysql_conn()?;
-            let chars = account.retrieve_chars(&mut conn)?;
-            log::info!("Characters are {:?}", chars);
-            let response = ServerPacket::NumberCharacters(chars.len() as u8, 8);
-            s.blocking_send(WorldResponse::ServerPacket(response));
-
-            for c in &chars {
-                let response = c.get_details_packet();
-                s.blocking_send(WorldResponse::ServerPacket(response));
+        let s = match self.object_senders.get(&sender) {
+            Some(s) => s.clone(),
+            None => return Ok(()),
+        };
+        let now = std::time::Instant::now();
+        let ip = peer.ip().to_canonical();
+        let blocked = self
+            .login_ip_failures
+            .blocked_for(&ip, now)
+            .or_else(|| self.login_account_failures.blocked_for(&username, now));
+        if let Some(wait) = blocked {
+            log::info!(
+                "Throttling login to {} from {} for another {:?}",
+                username,
+                peer,
+                wait
+            );
+            let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            Self::send_system_message(
+                &s,
+                format!("Too many failed logins, try again in {} seconds", seconds),
+            );
+            return Ok(());
+        }
+        if self.pending_logins.contains_key(&sender) {
+            log::info!("Client {} already has a login in progress", sender);
+            return Ok(());
+        }
+        if self.pending_logins.values().any(|p| p.account == username) {
+            log::info!(
+                "Refusing login to {} from {}, another login to the account is in progress",
+                username,
+                peer
+            );
+            Self::send_login_result(&s, LoginResult::AccountInUse);
+            return Ok(());
+        }
+        //the attempt counts as a failure until the password turns out to be right, so attempts can not get ahead of the limits
+        if self.login_ip_failures.record_failure(ip, now) {
+            log::warn!("Locking out logins from {} after too many failures", ip);
+        }
+        if self
+            .login_account_failures
+            .record_failure(username.clone(), now)
+        {
+            log::warn!(
+                "Locking out logins to account {} after too many failures",
+                username
+            );
+        }
+        let attempt = self.next_login_attempt;
+        self.next_login_attempt = self.next_login_attempt.wrapping_add(1);
+        let queued = self.passwords.request(
+            sender,
+            peer,
+            PasswordRequest::Login {
+                username: username.clone(),
+                password,
+                salt: self.config.account_creation_salt.clone(),
+                create: self.config.automatic_account_creation,
+                attempt,
+            },
+        );
+        if queued {
+            self.pending_logins.insert(
+                sender,
+                PendingLogin {
+                    account: username,
+                    attempt,
+                },
+            );
+        } else {
+            self.login_ip_failures.forgive(&ip);
+            self.login_account_failures.forgive(&username);
+            Self::send_system_message(&s, BUSY_MESSAGE.to_string());
+        }
+        Ok(())
+    }
+
+    /// Finish a login attempt once the password has been checked
+    fn finish_login(
+        &mut self,
+        sender: u32,
+        peer: std::net::SocketAddr,
+        username: String,
+        attempt: u32,
+        result: LoginCheck,
+    ) -> Result<(), ClientError> {
+        match self.pending_logins.remove(&sender) {
+            Some(p) if p.attempt == attempt => {}
+            Some(p) => {
+                log::warn!("Dropping the answer to an old login of client {}", sender);
+                self.pending_logins.insert(sender, p);
+                return Ok(());
+            }
+            None => {
+                log::warn!("Dropping the answer to an old login of client {}", sender);
+                return Ok(());
+            }
+        }
+        let mut s = match self.object_senders.get(&sender) {
+            Some(s) => s.clone(),
+            None => return Ok(()),
+        };
+        let ip = peer.ip().to_canonical();
+        //the attempt was recorded as a failure when it was queued
+        let mut us = match result {
+            LoginCheck::Ok(us) => us,
+            LoginCheck::NoAccount => {
+                log::info!("User account {} does not exist!", username);
+                self.login_account_failures.forgive(&username);
+                Self::send_login_result(&s, LoginResult::WrongCredentials);
+                return Ok(());
+            }
+            LoginCheck::Failed(e) => {
+                log::error!("Failed to check the login of {}: {:?}", username, e);
+                self.login_ip_failures.forgive(&ip);
+                self.login_account_failures.forgive(&username);
+                Self::send_login_result(&s, LoginResult::WrongCredentials);
+                return Ok(());
+            }
+            LoginCheck::WrongPassword => {
+                log::info!("Wrong password for {}", username);
+                Self::send_login_result(&s, LoginResult::WrongCredentials);
+                return Ok(());
             }
+        };
+        self.login_ip_failures.forgive(&ip);
+        self.login_account_failures.clear(&username);
+        let now = chrono::Utc::now();
+        if us.is_banned(now) {
+            log::warn!(
+                "Refusing login to banned account {}: {}",
+                username,
+                us.ban_reason().unwrap_or("no reason given")
+            );
+            Self::send_login_result(&s, LoginResult::AccountBanned);
+            return Ok(());
+        }
+        if us.ban_expired(now) {
+            log::info!("The ban on account {} has expired", username);
+            us.clear_ban();
+            self.persistence
+                .request(sender, peer, StorageRequest::ExpireBan(username.clone()));
+        }
+        let mut sessions: Vec<u32> = self
+            .account_table
+            .iter()
+            .filter(|(_, a)| a.account_name() == us.account_name())
+            .map(|(id, _)| *id)
+            .collect();
+        sessions.sort_unstable_by_key(|id| (self.login_times.get(id).copied(), *id));
+        let max = self.config.max_sessions_per_account;
+        if max != 0 && sessions.len() >= max && self.config.kick_duplicate_logins {
+            for old in &sessions[..=sessions.len() - max] {
+                log::warn!(
+                    "Account {} logged in again from {}, disconnecting the older session {}",
+                    username,
+                    peer,
+                    old
+                );
+                self.kick_client(*old);
+            }
+        } else if max != 0 && sessions.len() >= max {
+            log::warn!(
+                "Refusing login to {}, already logged in {} times",
+                username,
+                sessions.len()
+            );
+            Self::send_login_result(&s, LoginResult::AccountInUse);
+            return Ok(());
+        }
+        let ip = ip.to_string();
+        us.set_login(now, &ip);
+        self.persistence.request(
+            sender,
+            peer,
+            StorageRequest::RecordLogin {
+                name: username.clone(),
+                when: now,
+                ip,
+            },
+        );
+        self.account_table.insert(sender, us);
+        self.login_times.insert(sender, std::time::Instant::now());
+        self.login_with_news(sender, peer, username, &mut s)
+    }
+
+    /// Process a request from a client to change the password of an account
+    fn change_password(
+        &mut self,
+        sender: u32,
+        peer: std::net::SocketAddr,
+        account: String,
+        oldpass: String,
+        newpass: String,
+    ) -> Result<(), ClientError> {
+        let s = match self.object_senders.get(&sender) {
+            Some(s) => s.clone(),
+            None => return Ok(()),
+        };
+        let now = std::time::Instant::now();
+        let ip = peer.ip().to_canonical();
+        if self.password_ip_failures.is_blocked(&ip, now)
+            || self.password_account_failures.is_blocked(&account, now)
+        {
+            log::warn!(
+                "Refusing password change for {} from {}, too many failures",
+                account,
+                peer
+            );
+            Self::send_login_result(&s, LoginResult::WrongCredentials);
+            return Ok(());
+        }
+        if newpass.is_empty() {
+            Self::send_login_result(&s, LoginResult::WrongCredentials);
+            return Ok(());
+        }
+        let queued = self.passwords.request(
+            sender,
+            peer,
+            PasswordRequest::ChangePassword {
+                account,
+                salt: self.config.account_creation_salt.clone(),
+                oldpass,
+                newpass,
+            },
+        );
+        if !queued {
+            Self::send_system_message(&s, BUSY_MESSAGE.to_string());
         }
         Ok(())
     }
 
+    /// Finish a password change once the storage has answered
+    fn finish_password_change(
+        &mut self,
+        sender: u32,
+        peer: std::net::SocketAddr,
+        account: String,
+        result: PasswordChange,
+    ) {
+        let s = match self.object_senders.get(&sender) {
+            Some(s) => s.clone(),
+            None => return,
+        };
+        let now = std::time::Instant::now();
+        let ip = peer.ip().to_canonical();
+        log::info!(
+            "Password change for {} from {}: {:?}",
+            account,
+            peer,
+            result
+        );
+        match result {
+            PasswordChange::Changed => {
+                self.password_ip_failures.clear(&ip);
+                self.password_account_failures.clear(&account);
+                Self::send_login_result(&s, LoginResult::PasswordChanged);
+            }
+            PasswordChange::NoSuchAccount => {
+                self.password_ip_failures.record_failure(ip, now);
+                Self::send_login_result(&s, LoginResult::WrongCredentials);
+            }
+            PasswordChange::WrongPassword => {
+                self.password_ip_failures.record_failure(ip, now);
+                self.password_account_failures.record_failure(account, now);
+                Self::send_login_result(&s, LoginResult::WrongCredentials);
+            }
+        }
+    }
+
+    /// Send the client details that happens after the news (if there was any news at all)
+    /// This still should be called even if there was no news.
+    /// The characters are sent once the storage has answered.
+    fn after_news(&mut self, id: u32, peer: std::net::SocketAddr) {
+        if let Some(account) = self.account_table.get(&id) {
+            let name = account.account_name().to_string();
+            self.persistence
+                .request(id, peer, StorageRequest::CharacterList(name));
+        }
+    }
+
+    /// Handle the answer to a storage request made for a client
+    fn storage_response(&mut self, sender: u32, peer: std::net::SocketAddr, r: StorageResponse) {
+        let s = match self.object_senders.get(&sender) {
+            Some(s) => s.clone(),
+            None => {
+                log::info!("Client {} left before storage answered", sender);
+                return;
+            }
+        };
+        match r {
+            StorageResponse::Login {
+                username,
+                attempt,
+                result,
+            } => {
+                if let Err(e) = self.finish_login(sender, peer, username, attempt, result) {
+                    log::error!("Error during login: {:?}", e);
+                }
+            }
+            StorageResponse::PasswordChange { account, result } => match result {
+                Ok(result) => self.finish_password_change(sender, peer, account, result),
+                Err(e) => log::error!("Failed to change the password of {}: {:?}", account, e),
+            },
+            StorageResponse::CharacterList(Ok(chars)) => {
+                log::info!("Characters are {:?}", chars);
+                let response = ServerPacket::NumberCharacters(chars.len() as u8, 8);
+                let _ = s.blocking_send(WorldResponse::ServerPacket(response));
+
+                for c in &chars {
+                    let response = c.get_details_packet();
+                    let _ = s.blocking_send(WorldResponse::ServerPacket(response));
+                }
+            }
+            StorageResponse::CharacterList(Err(e)) => {
+                log::error!("Failed to get characters: {:?}", e);
+            }
+            StorageResponse::CharacterCreated(Ok(c)) => {
+                let _ = s.blocking_send(WorldResponse::ServerPacket(
+                    ServerPacket::CharacterCreationStatus(CharacterCreationStatus::Ok),
+                ));
+                let _ =
+                    s.blocking_send(WorldResponse::ServerPacket(c.get_new_char_details_packet()));
+            }
+            StorageResponse::CharacterCreated(Err(ClientError::CharacterExists)) => {
+                let _ = s.blocking_send(WorldResponse::ServerPacket(
+                    ServerPacket::CharacterNameAlreadyExists,
+                ));
+            }
+            StorageResponse::CharacterCreated(Err(e)) => {
+                log::error!("Failed to create character: {:?}", e);
+                let _ = s.blocking_send(WorldResponse::ServerPacket(
+                    ServerPacket::CharacterCreationStatus(CharacterCreationStatus::Failed),
+                ));
+            }
+            StorageResponse::CharacterDeleted { name, waiting } => {
+                log::info!("Character {} deleted, waiting {}", name, waiting);
+                let p = if waiting {
+                    ServerPacket::DeleteCharacterWait
+                } else {
+                    ServerPacket::DeleteCharacterOk
+                };
+                let _ = s.blocking_send(WorldResponse::ServerPacket(p));
+            }
+            StorageResponse::CharacterLoaded { name, character } => {
+                if let Some(pc) = character {
+                    self.enter_world(sender, name, pc, s);
+                }
+            }
+            StorageResponse::AccountBanned {
+                name,
+                expires,
+                reason,
+                result,
+            } => {
+                let reply = match result {
+                    Ok(true) => {
+                        for (id, a) in &self.account_table {
+                            if a.account_name() == name {
+                                if let Some(s) = self.object_senders.get(id) {
+                                    let _ = s.blocking_send(WorldResponse::ServerPacket(
+                                        ServerPacket::Disconnect,
+                                    ));
+                                }
+                            }
+                        }
+                        log::warn!("Account {} banned until {:?}: {}", name, expires, reason);
+                        match expires {
+                            Some(e) => format!("Account {} is banned until {}", name, e),
+                            None => format!("Account {} is banned", name),
+                        }
+                    }
+                    Ok(false) => format!("Account {} does not exist", name),
+                    Err(e) => format!("Failed to ban {}: {:?}", name, e),
+                };
+                let _ = s.blocking_send(WorldResponse::ServerPacket(ServerPacket::SystemMessage(
+                    reply,
+                )));
+            }
+            StorageResponse::AccountUnbanned { name, result } => {
+                let reply = match result {
+                    Ok(true) => format!("Account {} is no longer banned", name),
+                    Ok(false) => format!("Account {} does not exist", name),
+                    Err(e) => format!("Failed to unban {}: {:?}", name, e),
+                };
+                let _ = s.blocking_send(WorldResponse::ServerPacket(ServerPacket::SystemMessage(
+                    reply,
+                )));
+            }
+            StorageResponse::ItemIdReserved {
+                item,
+                count,
+                result,
+            } => {
+                let reply = match result {
+                    Ok(item_id) => self.give_item(sender, item, item_id, count),
+                    Err(e) => format!("Failed to create item {}: {:?}", item, e),
+                };
+                let _ = s.blocking_send(WorldResponse::ServerPacket(ServerPacket::SystemMessage(
+                    reply,
+                )));
+            }
+        }
+    }
+
+    /// Put a character that was loaded for a client into the world
+    fn enter_world(
+        &mut self,
+        sender: u32,
+        name: String,
+        pc: crate::character::PartialCharacter,
+        mut s: tokio::sync::mpsc::Sender<WorldResponse>,
+    ) {
+        if !self.account_table.contains_key(&sender) {
+            log::info!("Client {} is no longer logged in to play {}", sender, name);
+            return;
+        }
+        if self.characters.contains_key(&sender) {
+            log::warn!("Client {} is already playing a character", sender);
+            return;
+        }
+        let online = self.characters.values().any(|r| {
+            self.get_object_ref(*r)
+                .and_then(|o| o.player_name())
+                .map(|n| n == name)
+                .unwrap_or(false)
+        });
+        if online {
+            log::warn!("Character {} was selected twice at the same time", name);
+            return;
+        }
+        let _ = s.blocking_send(WorldResponse::ServerPacket(ServerPacket::StartGame(0)));
+        let mut fc = pc.into_full(&self.item_table);
+        let id = fc.id();
+        self.characters.insert(sender, id);
+        fc.add_sender(s.clone());
+        if let Some(r) = self.add_player(fc, &mut s) {
+            self.object_ref_table.insert(id, r);
+        }
+    }
+
+    /// Save the character played by a client
+    fn save_character(&mut self, id: u32) {
+        let r = match self.characters.get(&id) {
+            Some(r) => *r,
+            None => return,
+        };
+        let saved = match self.get_object_mut(r) {
+            Some(object::Object::Player(fc)) => fc.saved(),
+            _ => return,
+        };
+        self.persistence.save(saved);
+    }
+
+    /// Save every character that is online
+    fn save_all_characters(&mut self) {
+        let ids: Vec<u32> = self.characters.keys().copied().collect();
+        for id in ids {
+            self.save_character(id);
+        }
+    }
+
+    /// Start sending autosave messages to the world at the configured interval
+    pub fn start_autosave(&mut self) {
+        if self.config.autosave_interval == 0 {
+            log::info!("Autosave is disabled");
+            return;
+        }
+        let period = std::time::Duration::from_secs(self.config.autosave_interval);
+        let sender = self.sender.clone();
+        self.timer_tasks.push(tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                let m = WorldMessage {
+                    data: WorldMessageData::Autosave,
+                    sender: None,
+                    peer: std::net::SocketAddr::from(([0, 0, 0, 0], 0)),
+                };
+                if sender.send(m).await.is_err() {
+                    break;
+                }
+            }
+        }));
+    }
+
+    /// Start deleting the characters whose delete time has passed, checking every minute
+    pub fn start_character_purge(&mut self) {
+        let storage = self.storage.clone();
+        self.timer_tasks.push(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let storage = storage.clone();
+                let purged = tokio::task::spawn_blocking(move || {
+                    storage.purge_deleted_characters(chrono::Utc::now())
+                })
+                .await;
+                match purged {
+                    Ok(Ok(names)) => {
+                        for n in names {
+                            log::info!("Character {} was deleted", n);
+                        }
+                    }
+                    Ok(Err(e)) => log::error!("Failed to delete characters: {:?}", e),
+                    Err(e) => log::error!("Failed to delete characters: {:?}", e),
+                }
+            }
+        }));
+    }
+
+    /// Disconnect a client from the world, saving its character first.
+    /// The connection of the client unregisters itself once it has closed.
+    fn kick_client(&mut self, id: u32) {
+        if let Some(s) = self.object_senders.get(&id) {
+            let _ = s.blocking_send(WorldResponse::ServerPacket(ServerPacket::Disconnect));
+        }
+        self.log_out_client(id);
+    }
+
+    /// Save the character of a client, then remove the character and the login of the client from the world
+    fn log_out_client(&mut self, id: u32) {
+        self.save_character(id);
+        if let Some(r) = self.characters.remove(&id) {
+            if let Some(re) = self.object_ref_table.remove(&r) {
+                if let Some(map) = self.map_info.get_mut(&re.map) {
+                    if map.get_object(re).is_some() {
+                        map.remove_object(r);
+                    }
+                }
+            }
+        }
+        self.account_table.remove(&id);
+        self.login_times.remove(&id);
+        self.users.remove(&id);
+    }
+
+    /// Remove everything the world knows about a client, including its character
+    fn unregister_client(&mut self, id: u32) {
+        self.log_out_client(id);
+        self.pending_logins.remove(&id);
+        self.object_senders.remove(&id);
+        self.client_ids.remove_entry(id);
+    }
+
     /// end the gameserver
     pub fn end(&mut self) {
         log::info!("Ending world");
         if let Some(mut ms) = self.monster_set.take() {
             ms.abort_all();
         }
+        for t in self.timer_tasks.drain(..) {
+            t.abort();
+        }
+        self.save_all_characters();
+        //answers to requests are not wanted anymore, and must not block the worker when the queue is full
+        self.recv.close();
+        self.passwords.finish();
+        self.persistence.finish();
+        log::info!("All characters are saved");
     }
 
     /// Run the game world
@@ -383,8 +1041,18 @@ impl World {
                     self.object_senders.insert(newid, s);
                 }
                 WorldMessageData::UnregisterClient(id) => {
-                    self.object_senders.remove(&id);
-                    self.client_ids.remove_entry(id);
+                    self.unregister_client(id);
+                }
+                WorldMessageData::Autosave => {
+                    self.save_all_characters();
+                }
+                WorldMessageData::Shutdown => {
+                    break;
+                }
+                WorldMessageData::Storage(r) => {
+                    if let Some(sender) = m.sender {
+                        self.storage_response(sender, m.peer, r);
+                    }
                 }
                 WorldMessageData::ClientPacket(client_packet) => match client_packet {
                     ClientPacket::NpcChat { id, message: msg } => {
@@ -570,6 +1238,7 @@ impl World {
                     ClientPacket::Restart => {
                         log::info!("Player restarts");
                         if let Some(sender) = m.sender {
+                            self.save_character(sender);
                             if let Some(r) = self.characters.get(&sender) {
                                 if let Some(re) = self.object_ref_table.get(r) {
                                     if let Some(map) = self.map_info.get_mut(&re.map) {
@@ -628,62 +1297,15 @@ impl World {
                             v7
                         );
                         if let Some(sender) = m.sender {
-                            if let Ok(mut mysql_conn) = self.get_mysql_conn() {
-                                if let Some(s) = self.object_senders.get(&sender) {
-                                    let mut s = s.clone();
-                                    let user =
-                                        crate::user::get_user_details(u.clone(), &mut mysql_conn);
-                                    match user {
-                                        Some(us) => {
-                                            log::info!("User {} exists: {:?}", u.clone(), us);
-                                            let password_success = us.check_login(
-                                                &self.config.account_creation_salt,
-                                                &p,
-                                            );
-                                            log::info!("User login check is {}", password_success);
-                                            if password_success {
-                                                self.account_table.insert(sender, us);
-                                                self.login_with_news(sender, u, &mut s);
-                                            } else {
-                                                s.blocking_send(WorldResponse::ServerPacket(
-                                                    ServerPacket::LoginResult { code: 8 },
-                                                ));
-                                            }
-                                        }
-                                        None => {
-                                            log::info!(
-                                                "User account {} does not exist!",
-                                                u.clone()
-                                            );
-                                            if self.config.automatic_account_creation {
-                                                let newaccount = UserAccount::new(
-                                                    u.clone(),
-                                                    p,
-                                                    m.peer.to_string(),
-                                                    self.config.account_creation_salt.clone(),
-                                                );
-                                                if let Ok(mut mysql) = self.get_mysql_conn() {
-                                                    newaccount.insert_into_db(&mut mysql);
-                                                }
-                                                self.login_with_news(sender, u, &mut s);
-                                            } else {
-                                                s.blocking_send(WorldResponse::ServerPacket(
-                                                    ServerPacket::LoginResult { code: 8 },
-                                                ));
-                                            }
-                                        }
-                                    }
-                                }
+                            if let Err(e) = self.login(sender, m.peer, u, p) {
+                                log::error!("Error during login: {:?}", e);
                             }
                         }
                     }
                     ClientPacket::NewsDone => {
                         //send number of characters the player has
                         if let Some(sender) = m.sender {
-                            if let Some(s) = self.object_senders.get(&sender) {
-                                let mut s = s.clone();
-                                self.after_news(sender, &mut s);
-                            }
+                            self.after_news(sender, m.peer);
                         }
                     }
                     ClientPacket::NewCharacter {
@@ -700,7 +1322,7 @@ impl World {
                         if let Some(sender) = m.sender {
                             if let Some(s) = self.object_senders.get(&sender) {
                                 if let Some(account) = self.account_table.get(&sender) {
-                                    if let Some(mut c) = crate::character::Character::new(
+                                    if let Some(c) = crate::character::Character::new(
                                         account.account_name().to_string(),
                                         sender, //TODO validate this
                                         name,
@@ -713,27 +1335,16 @@ impl World {
                                         charisma,
                                         intelligence,
                                     ) {
-                                        if let Ok(mut mysql) = self.get_mysql_conn() {
-                                            if c.save_new_to_db(&mut mysql).is_ok() {
-                                                s.blocking_send(WorldResponse::ServerPacket(
-                                                    ServerPacket::CharacterCreationStatus(0),
-                                                ));
-                                                s.blocking_send(WorldResponse::ServerPacket(
-                                                    c.get_new_char_details_packet(),
-                                                ));
-                                            } else {
-                                                s.blocking_send(WorldResponse::ServerPacket(
-                                                    ServerPacket::CharacterNameAlreadyExists,
-                                                ));
-                                            }
-                                        } else {
-                                            s.blocking_send(WorldResponse::ServerPacket(
-                                                ServerPacket::CharacterCreationStatus(1),
-                                            ));
-                                        }
+                                        self.persistence.request(
+                                            sender,
+                                            m.peer,
+                                            StorageRequest::CreateCharacter(c),
+                                        );
                                     } else {
                                         s.blocking_send(WorldResponse::ServerPacket(
-                                            ServerPacket::CharacterCreationStatus(1),
+                                            ServerPacket::CharacterCreationStatus(
+                                                CharacterCreationStatus::Failed,
+                                            ),
                                         ));
                                     }
                                 }
@@ -742,64 +1353,65 @@ impl World {
                     }
                     ClientPacket::DeleteCharacter(n) => {
                         if let Some(sender) = m.sender {
-                            if let Ok(mut mysql) = self.get_mysql_conn() {
-                                if let Some(s) = self.object_senders.get(&sender) {
-                                    let s = s.clone();
-                                    if let Some(account) = self.account_table.get_mut(&sender) {
-                                        if let Ok(chars) = account.retrieve_chars(&mut mysql) {
-                                            let char = chars.iter().find(|a| a.name() == n);
-                                            if let Some(char) = char {
-                                                if char.needs_delete_waiting() {
-                                                    //TODO implement the actual delete in a scheduled async task
-                                                    s.blocking_send(WorldResponse::ServerPacket(
-                                                        ServerPacket::DeleteCharacterWait,
-                                                    ));
-                                                } else {
-                                                    //TODO actually delete the character
-                                                    account.delete_char(&n, &mut mysql);
-                                                    s.blocking_send(WorldResponse::ServerPacket(
-                                                        ServerPacket::DeleteCharacterOk,
-                                                    ));
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
+                            if let Some(account) = self.account_table.get(&sender) {
+                                let request = StorageRequest::DeleteCharacter {
+                                    account: account.account_name().to_string(),
+                                    name: n,
+                                    wait: self.config.character_delete_wait,
+                                };
+                                self.persistence.request(sender, m.peer, request);
                             }
                         }
                     }
                     ClientPacket::CharacterSelect { name } => {
                         log::info!("login with {}", name);
                         if let Some(sender) = m.sender {
-                            if let Some(s) = self.object_senders.get(&sender) {
-                                let mut s = s.clone();
-                                let mut fco: Option<FullCharacter> = None;
-                                if let Ok(mut mysql) = self.get_mysql_conn() {
-                                    if let Some(account) = self.account_table.get(&sender) {
-                                        if let Ok(chars) = account.retrieve_chars(&mut mysql) {
-                                            let char = chars.iter().find(|a| a.name() == name);
-                                            if let Some(char) = char {
-                                                if let Ok(pc) = char.get_partial_details(
-                                                    self.id_generator.new_id(),
-                                                    &mut mysql,
-                                                ) {
-                                                    s.blocking_send(WorldResponse::ServerPacket(
-                                                        ServerPacket::StartGame(0),
-                                                    ));
-                                                    let fc = pc.into_full(&self.item_table);
-                                                    fco = Some(fc);
-                                                } else {
-                                                }
-                                            }
-                                        }
+                            if self.characters.contains_key(&sender) {
+                                log::warn!("Client {} is already playing a character", sender);
+                            } else if let Some(account) = self.account_table.get(&sender) {
+                                let account = account.account_name().to_string();
+                                let online = self
+                                    .characters
+                                    .iter()
+                                    .filter(|(c, _)| **c != sender)
+                                    .find(|(_, r)| {
+                                        self.get_object_ref(**r)
+                                            .and_then(|o| o.player_name())
+                                            .map(|n| n == name)
+                                            .unwrap_or(false)
+                                    })
+                                    .map(|(c, _)| *c);
+                                let same_account = online
+                                    .and_then(|old| self.account_table.get(&old))
+                                    .map(|a| a.account_name() == account)
+                                    .unwrap_or(false);
+                                match online {
+                                    Some(old)
+                                        if !same_account || !self.config.kick_duplicate_logins =>
+                                    {
+                                        log::warn!(
+                                            "Character {} is already online in session {}, refusing the select from {}",
+                                            name,
+                                            old,
+                                            sender
+                                        );
                                     }
-                                }
-                                if let Some(mut fc) = fco {
-                                    let id = fc.id();
-                                    self.characters.insert(sender, id);
-                                    fc.add_sender(s.clone());
-                                    if let Some(r) = self.add_player(fc, &mut s) {
-                                        self.object_ref_table.insert(id, r);
+                                    online => {
+                                        if let Some(old) = online {
+                                            log::warn!(
+                                                "Character {} is already online, disconnecting session {}",
+                                                name,
+                                                old
+                                            );
+                                            //the kicked character is saved before the load below
+                                            self.kick_client(old);
+                                        }
+                                        let request = StorageRequest::LoadCharacter {
+                                            account,
+                                            name,
+                                            world_id: self.id_generator.new_id(),
+                                        };
+                                        self.persistence.request(sender, m.peer, request);
                                     }
                                 }
                             }
@@ -810,7 +1422,11 @@ impl World {
                     ClientPacket::WindowActivate(v2) => {
                         log::info!("Client window activate {}", v2);
                     }
-                    ClientPacket::Save => {}
+                    ClientPacket::Save => {
+                        if let Some(sender) = m.sender {
+                            self.save_character(sender);
+                        }
+                    }
                     ClientPacket::MoveFrom { x, y, heading } => {
                         let (x2, y2) = match heading {
                             0 => (x, y - 1),
@@ -1023,7 +1639,7 @@ impl World {
                                                             se.blocking_send(
                                                                 WorldResponse::ServerPacket(
                                                                     ServerPacket::Message {
-                                                                        ty: 73,
+                                                                        ty: MessageId::NotPlaying,
                                                                         msgs: vec![n],
                                                                     },
                                                                 ),
@@ -1101,6 +1717,30 @@ impl World {
                                                         }
                                                     }
                                                 }
+                                                "ban" | "unban" => {
+                                                    let args: Vec<&str> = words.collect();
+                                                    if let Some(reply) =
+                                                        self.ban_command(sender, m.peer, mw, &args)
+                                                    {
+                                                        s.blocking_send(
+                                                            WorldResponse::ServerPacket(
+                                                                ServerPacket::SystemMessage(reply),
+                                                            ),
+                                                        );
+                                                    }
+                                                }
+                                                "item" => {
+                                                    let args: Vec<&str> = words.collect();
+                                                    if let Some(reply) =
+                                                        self.item_command(sender, m.peer, &args)
+                                                    {
+                                                        s.blocking_send(
+                                                            WorldResponse::ServerPacket(
+                                                                ServerPacket::SystemMessage(reply),
+                                                            ),
+                                                        );
+                                                    }
+                                                }
                                                 "quit" => {
                                                     s.blocking_send(WorldResponse::ServerPacket(
                                                         ServerPacket::Disconnect,
@@ -1174,39 +1814,13 @@ impl World {
                     ClientPacket::ChangePassword {
                         account,
                         oldpass,
-                        newpass: _,
+                        newpass,
                     } => {
-                        if let Ok(mut mysql) = self.get_mysql_conn() {
-                            if let Some(sender) = m.sender {
-                                if let Some(s) = self.object_senders.get_mut(&sender) {
-                                    let user =
-                                        crate::user::get_user_details(account.clone(), &mut mysql);
-                                    match user {
-                                        Some(us) => {
-                                            let password_success = us.check_login(
-                                                &self.config.account_creation_salt,
-                                                &oldpass,
-                                            );
-                                            if password_success {
-                                                log::info!(
-                                                    "User wants to change password and entered correct details"
-                                                );
-                                                s.blocking_send(WorldResponse::ServerPacket(
-                                                    ServerPacket::LoginResult { code: 0x30 },
-                                                ));
-                                            } else {
-                                                s.blocking_send(WorldResponse::ServerPacket(
-                                                    ServerPacket::LoginResult { code: 8 },
-                                                ));
-                                            }
-                                        }
-                                        _ => {
-                                            s.blocking_send(WorldResponse::ServerPacket(
-                                                ServerPacket::LoginResult { code: 8 },
-                                            ));
-                                        }
-                                    }
-                                }
+                        if let Some(sender) = m.sender {
+                            if let Err(e) =
+                                self.change_password(sender, m.peer, account, oldpass, newpass)
+                            {
+                                log::error!("Error changing password: {:?}", e);
                             }
                         }
                     }
@@ -1246,6 +1860,131 @@ impl World {
         None
     }
 
+    /// Returns true when the client is playing a character that is allowed to use game master commands.
+    /// This is the same rule used for shutting down the server.
+    fn is_game_master(&self, sender: u32) -> bool {
+        self.characters
+            .get(&sender)
+            .and_then(|r| self.get_object_ref(*r))
+            .map(|o| o.can_shutdown())
+            .unwrap_or(false)
+    }
+
+    /// Ban or unban an account with a game master command, returning the message to show the game master right away.
+    /// The arguments are the account name, optionally the number of hours (0 or none for a permanent ban) and a reason.
+    /// The result is shown to the game master once the storage has answered.
+    fn ban_command(
+        &self,
+        sender: u32,
+        peer: std::net::SocketAddr,
+        command: &str,
+        args: &[&str],
+    ) -> Option<String> {
+        if !self.is_game_master(sender) {
+            return Some("You are not allowed to do that".to_string());
+        }
+        let name = match args.first() {
+            Some(n) => n.to_string(),
+            None => return Some(format!("Usage: {} <account> [hours] [reason]", command)),
+        };
+        if command == "unban" {
+            self.persistence
+                .request(sender, peer, StorageRequest::UnbanAccount(name));
+            return None;
+        }
+        let mut rest = &args[1..];
+        let hours = rest.first().and_then(|h| h.parse::<i64>().ok());
+        if hours.is_some() {
+            rest = &rest[1..];
+        }
+        let expires = match hours {
+            None | Some(0) => None,
+            Some(h) if h < 0 => return Some(format!("Hours can not be negative: {}", h)),
+            Some(h) => match chrono::Duration::try_hours(h)
+                .and_then(|d| chrono::Utc::now().checked_add_signed(d))
+            {
+                Some(e) => Some(e),
+                None => {
+                    return Some(format!(
+                        "{} hours is too long, use 0 for a permanent ban",
+                        h
+                    ))
+                }
+            },
+        };
+        let reason = rest.join(" ");
+        self.persistence.request(
+            sender,
+            peer,
+            StorageRequest::BanAccount {
+                name,
+                expires,
+                reason,
+            },
+        );
+        None
+    }
+
+    /// Give an item to a game master with a game master command, returning the message to show the game master right away.
+    /// The arguments are the item id and optionally how many to give.
+    /// The item is given once the storage has handed out an id for it.
+    fn item_command(
+        &mut self,
+        sender: u32,
+        peer: std::net::SocketAddr,
+        args: &[&str],
+    ) -> Option<String> {
+        if !self.is_game_master(sender) {
+            return Some("You are not allowed to do that".to_string());
+        }
+        let id = match args.first().and_then(|a| a.parse::<u32>().ok()) {
+            Some(id) => id,
+            None => return Some("Usage: item <item id> [count]".to_string()),
+        };
+        let count = args
+            .get(1)
+            .and_then(|c| c.parse::<u32>().ok())
+            .unwrap_or(1)
+            .max(1);
+        if !self.item_table.contains_key(&id) {
+            return Some(format!("Item {} does not exist", id));
+        }
+        if !self.characters.contains_key(&sender) {
+            return Some("You are not playing a character".to_string());
+        }
+        self.persistence.request(
+            sender,
+            peer,
+            StorageRequest::ReserveItemId { item: id, count },
+        );
+        None
+    }
+
+    /// Give a newly created item to the character of a client, returning the message to show the game master.
+    fn give_item(&mut self, sender: u32, id: u32, item_id: u32, count: u32) -> String {
+        let definition = match self.item_table.get(&id) {
+            Some(d) => d.clone(),
+            None => return format!("Item {} does not exist", id),
+        };
+        let item = item::ItemInstance::new(definition, item_id, count);
+        let name = item.name();
+        let packet = ServerPacket::Inventory(item.inventory_element());
+        let r = match self.characters.get(&sender) {
+            Some(r) => *r,
+            None => return "You are not playing a character".to_string(),
+        };
+        match self.get_object_mut(r) {
+            Some(object::Object::Player(fc)) => {
+                fc.add_item(item);
+                if let Some(s) = fc.sender() {
+                    let _ = s.blocking_send(WorldResponse::ServerPacket(packet));
+                }
+            }
+            _ => return "You are not playing a character".to_string(),
+        }
+        format!("Created {}", name)
+    }
+
     /// Shutdown the server if the player is authorized to do so
     pub fn shutdown(&self, r: &ObjectRef) {
         let shutdown = {
@@ -1335,7 +2074,9 @@ impl World {
             sp: 0,
             mr: 0,
         }));
-        s.blocking_send(WorldResponse::ServerPacket(ServerPacket::Weather(0)));
+        s.blocking_send(WorldResponse::ServerPacket(ServerPacket::Weather(
+            Weather::Clear,
+        )));
 
         let obj: object::Object = p.into();
         self.add_object(obj)
@@ -1343,16 +2084,11 @@ impl World {
 
     /// (Re)load the weapons table
     pub fn load_weapons(
-        mysql: &mut mysql::PooledConn,
+        storage: &dyn Storage,
         item_table: &mut HashMap<u32, item::Item>,
         next_object_id: &mut WorldIdGenerator,
     ) -> Result<(), String> {
-        use mysql::prelude::Queryable;
-        let query = "SELECT * from weapon";
-        let s = mysql.prep(query).map_err(|e| e.to_string())?;
-        let weapons = mysql
-            .exec_map(s, (), |a: item::Weapon| a)
-            .map_err(|e| e.to_string())?;
+        let weapons = storage.weapons().map_err(|e| format!("{:?}", e))?;
         for w in weapons {
             let w = w.get_instance(next_object_id.new_id());
             item_table.insert(w.db_id(), w.into());
@@ -1362,16 +2098,11 @@ impl World {
 
     /// (Re)load the etc items table
     pub fn load_etc_items(
-        mysql: &mut mysql::PooledConn,
+        storage: &dyn Storage,
         item_table: &mut HashMap<u32, item::Item>,
         next_object_id: &mut WorldIdGenerator,
     ) -> Result<(), String> {
-        use mysql::prelude::Queryable;
-        let query = "SELECT * from etcitem";
-        let s = mysql.prep(query).map_err(|e| e.to_string())?;
-        let items = mysql
-            .exec_map(s, (), |a: item::EtcItem| a)
-            .map_err(|e| e.to_string())?;
+        let items = storage.etc_items().map_err(|e| format!("{:?}", e))?;
         for w in items {
             let w = w.get_instance(next_object_id.new_id());
             item_table.insert(w.db_id(), w.into());
@@ -1381,16 +2112,11 @@ impl World {
 
     /// (Re)load the armor table
     pub fn load_armor(
-        mysql: &mut mysql::PooledConn,
+        storage: &dyn Storage,
         item_table: &mut HashMap<u32, item::Item>,
         next_object_id: &mut WorldIdGenerator,
     ) -> Result<(), String> {
-        use mysql::prelude::Queryable;
-        let query = "SELECT * from armor";
-        let s = mysql.prep(query).map_err(|e| e.to_string())?;
-        let items = mysql
-            .exec_map(s, (), |a: item::Armor| a)
-            .map_err(|e| e.to_string())?;
+        let items = storage.armor().map_err(|e| format!("{:?}", e))?;
         for w in items {
             let w = w.get_instance(next_object_id.new_id());
             item_table.insert(w.db_id(), w.into());
@@ -1400,31 +2126,26 @@ impl World {
 
     /// (Re)load all item data from database
     pub fn load_item_data(
-        mysql: &mut mysql::PooledConn,
+        storage: &dyn Storage,
         next_object_id: &mut WorldIdGenerator,
     ) -> Result<HashMap<u32, item::Item>, String> {
         let mut item_table = HashMap::new();
         log::info!("There are {} items", item_table.len());
-        Self::load_weapons(mysql, &mut item_table, next_object_id)?;
+        Self::load_weapons(storage, &mut item_table, next_object_id)?;
         log::info!("There are {} items", item_table.len());
-        Self::load_etc_items(mysql, &mut item_table, next_object_id)?;
+        Self::load_etc_items(storage, &mut item_table, next_object_id)?;
         log::info!("There are {} items", item_table.len());
-        Self::load_armor(mysql, &mut item_table, next_object_id)?;
+        Self::load_armor(storage, &mut item_table, next_object_id)?;
         log::info!("There are {} items", item_table.len());
         Ok(item_table)
     }
 
     /// (Re)load all maps from the database
     pub fn load_maps_data(
-        mysql: &mut mysql::PooledConn,
+        storage: &dyn Storage,
     ) -> Result<(HashMap<u16, Map>, HashMap<u16, map_info::MapInfo>), String> {
         let mut hmaps = HashMap::new();
-        use mysql::prelude::Queryable;
-        let query = "SELECT mapid, locationname, startX, endX, startY, endY, monster_amount, drop_rate, underwater, markable, teleportable, escapable, resurrection, painwand, penalty, take_pets, recall_pets, usable_item, usable_skill from mapids";
-        let s = mysql.prep(query).map_err(|e| e.to_string())?;
-        let maps = mysql
-            .exec_map(s, (), |a: Map| a)
-            .map_err(|e| e.to_string())?;
+        let maps = storage.maps().map_err(|e| format!("{:?}", e))?;
         let mut hdata = HashMap::new();
         for m in maps {
             hdata
@@ -1434,25 +2155,319 @@ impl World {
         }
         Ok((hmaps, hdata))
     }
+}
 
-    /// Get a new object id as part of a transaction.
-    /// This prevents atomicity problems where two threads can get the same new id, and try to insert the same id into the database.
-    /// # Arguments:
-    /// * t - The transaction object
-    pub fn get_new_id(t: &mut mysql::Transaction<'_>) -> Result<Option<u32>, mysql::Error> {
-        use mysql::prelude::Queryable;
-        let query = "select max(id)+1 as nextid from (select id from character_items union all select id from character_teleport union all select id from character_warehouse union all select id from character_elf_warehouse union all select objid as id from characters union all select clan_id as id from clan_data union all select id from clan_warehouse union all select objid as id from pets) t";
-        let a: Vec<Option<u32>> = t.exec(query, ())?;
-        let r = if let Some(a) = a.first() {
-            Ok(*a)
-        } else {
-            Ok(None)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    /// A client connected to a world running on a thread of its own
+    struct TestClient {
+        /// The id the world gave the client
+        id: u32,
+        /// What the world sends to the client
+        r: tokio::sync::mpsc::Receiver<WorldResponse>,
+    }
+
+    /// The address used for the clients in the tests
+    fn peer() -> std::net::SocketAddr {
+        std::net::SocketAddr::from(([127, 0, 0, 1], 0))
+    }
+
+    /// Read a configuration with automatic account creation and the given extra settings
+    fn config(extra: &str) -> crate::ServerConfiguration {
+        toml::from_str(&format!(
+            "automatic_account_creation = true\naccount_creation_salt = \"\"\n{}",
+            extra
+        ))
+        .unwrap()
+    }
+
+    /// Start a world with characters for bob and alice, returning the sender for the world and the thread running it
+    fn start_world() -> (
+        tokio::sync::mpsc::Sender<WorldMessage>,
+        std::thread::JoinHandle<World>,
+    ) {
+        let storage = Arc::new(MemoryStorage::new());
+        for (account, name) in [("bob", "knight"), ("alice", "elf")].iter() {
+            crate::character::Character::new(
+                account.to_string(),
+                0,
+                name.to_string(),
+                1,
+                0,
+                16,
+                12,
+                16,
+                10,
+                10,
+                10,
+            )
+            .unwrap()
+            .save_new_to_db(storage.as_ref())
+            .unwrap();
+        }
+        let (server_s, _server_r) = tokio::sync::mpsc::channel(5);
+        let (s, r) = tokio::sync::mpsc::channel(100);
+        let mut world = World::new(storage, server_s, r, s.clone(), config("")).unwrap();
+        let thread = std::thread::spawn(move || {
+            world.run();
+            world
+        });
+        (s, thread)
+    }
+
+    /// Connect a client to the world
+    fn connect(world: &tokio::sync::mpsc::Sender<WorldMessage>) -> TestClient {
+        let (s, mut r) = tokio::sync::mpsc::channel(100);
+        world
+            .blocking_send(WorldMessage {
+                data: WorldMessageData::RegisterSender(s),
+                sender: None,
+                peer: peer(),
+            })
+            .unwrap();
+        match r.blocking_recv() {
+            Some(WorldResponse::NewClientId(id)) => TestClient { id, r },
+            r => panic!("Expected a client id, got {:?}", r),
+        }
+    }
+
+    /// Send a packet from a client to the world
+    fn send(world: &tokio::sync::mpsc::Sender<WorldMessage>, c: &TestClient, p: ClientPacket) {
+        world
+            .blocking_send(WorldMessage {
+                data: WorldMessageData::ClientPacket(p),
+                sender: Some(c.id),
+                peer: peer(),
+            })
+            .unwrap();
+    }
+
+    /// Wait for the world to send a packet matching f to the client, returning the packets sent before it
+    fn wait_for(c: &mut TestClient, f: impl Fn(&ServerPacket) -> bool) -> Vec<ServerPacket> {
+        let mut before = Vec::new();
+        loop {
+            match c.r.blocking_recv() {
+                Some(WorldResponse::ServerPacket(p)) if f(&p) => return before,
+                Some(WorldResponse::ServerPacket(p)) => before.push(p),
+                Some(_) => {}
+                None => panic!("The world closed the connection"),
+            }
+        }
+    }
+
+    /// Log a client in with the given account
+    fn login(world: &tokio::sync::mpsc::Sender<WorldMessage>, c: &mut TestClient, account: &str) {
+        send(
+            world,
+            c,
+            ClientPacket::Login(account.to_string(), "pw".to_string(), 0, 0, 0, 0, 0, 0, 0),
+        );
+        wait_for(c, |p| {
+            matches!(
+                p,
+                ServerPacket::LoginResult {
+                    code: LoginResult::Ok
+                }
+            )
+        });
+    }
+
+    /// Checks that selecting a character of another account that is online does not disconnect its player
+    #[test]
+    fn cross_account_select() {
+        let (world, thread) = start_world();
+        let mut bob = connect(&world);
+        login(&world, &mut bob, "bob");
+        send(
+            &world,
+            &bob,
+            ClientPacket::CharacterSelect {
+                name: "knight".to_string(),
+            },
+        );
+        wait_for(&mut bob, |p| matches!(p, ServerPacket::StartGame(_)));
+
+        let mut alice = connect(&world);
+        login(&world, &mut alice, "alice");
+        send(
+            &world,
+            &alice,
+            ClientPacket::CharacterSelect {
+                name: "knight".to_string(),
+            },
+        );
+        world
+            .blocking_send(WorldMessage {
+                data: WorldMessageData::Shutdown,
+                sender: None,
+                peer: peer(),
+            })
+            .unwrap();
+        let w = thread.join().unwrap();
+        assert!(w.characters.contains_key(&bob.id));
+        assert!(!w.characters.contains_key(&alice.id));
+        assert!(w.account_table.contains_key(&bob.id));
+        while let Ok(r) = bob.r.try_recv() {
+            assert!(!matches!(
+                r,
+                WorldResponse::ServerPacket(ServerPacket::Disconnect)
+            ));
+        }
+    }
+
+    /// The login result sent to a client, if one was sent
+    fn login_result(r: &mut tokio::sync::mpsc::Receiver<WorldResponse>) -> Option<LoginResult> {
+        while let Ok(m) = r.try_recv() {
+            if let WorldResponse::ServerPacket(ServerPacket::LoginResult { code }) = m {
+                return Some(code);
+            }
+        }
+        None
+    }
+
+    /// Checks that only one login per client and account is checked at a time, and that it counts as a failure until it succeeds
+    #[test]
+    fn pending_logins() {
+        let storage = Arc::new(MemoryStorage::new());
+        UserAccount::new("bob".to_string(), "pw".to_string(), "".to_string())
+            .unwrap()
+            .insert_into_db(storage.as_ref());
+        let (server_s, _server_r) = tokio::sync::mpsc::channel(5);
+        let (s, mut r) = tokio::sync::mpsc::channel(100);
+        let config = config("[login_throttle]\nmax_failures = 2\nbackoff = 0");
+        //the answers of the workers are read by the test instead of the world
+        let (_world_s, world_r) = tokio::sync::mpsc::channel(1);
+        let mut w = World::new(storage, server_s, world_r, s, config).unwrap();
+        let home = std::net::SocketAddr::from(([10, 0, 0, 1], 1000));
+        let away = std::net::SocketAddr::from(([10, 0, 0, 2], 1000));
+        let mut clients = Vec::new();
+        for id in 1..=3 {
+            let (cs, cr) = tokio::sync::mpsc::channel(100);
+            w.object_senders.insert(id, cs);
+            clients.push(cr);
+        }
+        let login = |w: &mut World, id: u32, peer, account: &str, password: &str| {
+            w.login(id, peer, account.to_string(), password.to_string())
+                .unwrap();
         };
-        r
+
+        login(&mut w, 1, home, "bob", "wrong");
+        login(&mut w, 1, home, "bob", "wrong");
+        assert_eq!(login_result(&mut clients[0]), None);
+        login(&mut w, 2, away, "bob", "wrong");
+        assert_eq!(
+            login_result(&mut clients[1]),
+            Some(LoginResult::AccountInUse)
+        );
+        //another account from the same address is checked at the same time
+        login(&mut w, 3, home, "alice", "pw");
+        assert_eq!(login_result(&mut clients[2]), None);
+        assert_eq!(w.pending_logins.len(), 2);
+
+        //handles what the world gets until the answer to a login, such as the character list after a login
+        let answer = |w: &mut World, r: &mut tokio::sync::mpsc::Receiver<WorldMessage>| loop {
+            let m = r.blocking_recv().unwrap();
+            match m.data {
+                WorldMessageData::Storage(resp) => {
+                    let login = matches!(resp, StorageResponse::Login { .. });
+                    w.storage_response(m.sender.unwrap(), m.peer, resp);
+                    if login {
+                        break;
+                    }
+                }
+                d => panic!("Unexpected message {:?}", d),
+            }
+        };
+        answer(&mut w, &mut r);
+        answer(&mut w, &mut r);
+        assert_eq!(
+            login_result(&mut clients[0]),
+            Some(LoginResult::WrongCredentials)
+        );
+        assert_eq!(login_result(&mut clients[2]), Some(LoginResult::Ok));
+        assert!(w.pending_logins.is_empty());
+
+        //the second failure locks the account while the password is checked, the right password lifts it again
+        login(&mut w, 1, home, "bob", "pw");
+        let now = std::time::Instant::now();
+        assert!(w.login_account_failures.is_blocked(&"bob".to_string(), now));
+        answer(&mut w, &mut r);
+        assert_eq!(login_result(&mut clients[0]), Some(LoginResult::Ok));
+        let now = std::time::Instant::now();
+        assert!(!w.login_account_failures.is_blocked(&"bob".to_string(), now));
+        assert!(!w.login_ip_failures.is_blocked(&home.ip(), now));
     }
 
-    /// Get a connection to the database
-    pub fn get_mysql_conn(&self) -> Result<mysql::PooledConn, mysql::Error> {
-        self.mysql.get_conn()
+    /// Checks that a login that is locked out is told how long to wait instead of getting a login result
+    #[test]
+    fn throttled_login() {
+        let storage = Arc::new(MemoryStorage::new());
+        let (server_s, _server_r) = tokio::sync::mpsc::channel(5);
+        let (s, _r) = tokio::sync::mpsc::channel(100);
+        let config = config("[login_throttle]\nmax_failures = 1\nlockout = 600\nbackoff = 0");
+        let (_world_s, world_r) = tokio::sync::mpsc::channel(1);
+        let mut w = World::new(storage, server_s, world_r, s, config).unwrap();
+        let mut clients = Vec::new();
+        for id in 1..=2 {
+            let (cs, cr) = tokio::sync::mpsc::channel(100);
+            w.object_senders.insert(id, cs);
+            clients.push(cr);
+            //the pending first login already counts as a failure, which locks the account
+            w.login(id, peer(), "bob".to_string(), "pw".to_string())
+                .unwrap();
+        }
+        match clients[1].try_recv() {
+            Ok(WorldResponse::ServerPacket(ServerPacket::SystemMessage(m))) => {
+                assert_eq!(m, "Too many failed logins, try again in 600 seconds")
+            }
+            r => panic!("Expected a system message, got {:?}", r),
+        }
+        assert_eq!(login_result(&mut clients[1]), None);
+    }
+
+    /// Checks that a banned account is told that it is banned, and that an expired ban is lifted
+    #[test]
+    fn banned_login() {
+        let storage = Arc::new(MemoryStorage::new());
+        for name in ["bob", "alice"].iter() {
+            UserAccount::new(name.to_string(), "pw".to_string(), "".to_string())
+                .unwrap()
+                .insert_into_db(storage.as_ref());
+        }
+        storage.ban_account("bob", None, "cheating").unwrap();
+        let expired = chrono::Utc::now() - chrono::Duration::hours(1);
+        storage
+            .ban_account("alice", Some(expired), "spamming")
+            .unwrap();
+        let (server_s, _server_r) = tokio::sync::mpsc::channel(5);
+        let (s, mut r) = tokio::sync::mpsc::channel(100);
+        //the answers of the workers are read by the test instead of the world
+        let (_world_s, world_r) = tokio::sync::mpsc::channel(1);
+        let mut w = World::new(storage, server_s, world_r, s, config("")).unwrap();
+        let mut clients = Vec::new();
+        for (id, account) in [(1, "bob"), (2, "alice")].iter() {
+            let (cs, cr) = tokio::sync::mpsc::channel(100);
+            w.object_senders.insert(*id, cs);
+            clients.push(cr);
+            w.login(*id, peer(), account.to_string(), "pw".to_string())
+                .unwrap();
+            let m = r.blocking_recv().unwrap();
+            match m.data {
+                WorldMessageData::Storage(resp) => {
+                    w.storage_response(m.sender.unwrap(), m.peer, resp)
+                }
+                d => panic!("Unexpected message {:?}", d),
+            }
+        }
+        assert_eq!(
+            login_result(&mut clients[0]),
+            Some(LoginResult::AccountBanned)
+        );
+        assert!(!w.account_table.contains_key(&1));
+        assert_eq!(login_result(&mut clients[1]), Some(LoginResult::Ok));
+        assert!(w.account_table.contains_key(&2));
     }
 }
@@ -92,14 +92,6 @@ impl mysql::prelude::FromRow for MonsterSpawn {
 }
 
 impl MonsterSpawn {
-    /// Load the npc spawn table from the database
-    pub fn load_table(mysql: &mut mysql::PooledConn) -> Result<Vec<Self>, super::ClientError> {
-        use mysql::prelude::Queryable;
-        let query = "SELECT * from spawnlist";
-        let s = mysql.exec_map(query, (), |a: Self| a)?;
-        Ok(s)
-    }
-
     /// Get the map that this spawn spawns monsters on
     pub fn map(&self) -> u16 {
         self.location.map
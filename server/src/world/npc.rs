@@ -23,22 +23,6 @@ pub struct NpcDefinition {
     pub max_mp: u16,
 }
 
-impl NpcDefinition {
-    /// Load the npc definition table from the database
-    pub fn load_table(mysql: &mut mysql::PooledConn) -> Result<HashMap<u32, Self>, String> {
-        use mysql::prelude::Queryable;
-        let query = "SELECT * from npc";
-        let s = mysql
-            .exec_map(query, (), |a: Self| a)
-            .map_err(|e| format!("{:?}", e))?;
-        let mut t = HashMap::new();
-        for s in s {
-            t.insert(s.id, s);
-        }
-        Ok(t)
-    }
-}
-
 impl mysql::prelude::FromRow for NpcDefinition {
     fn from_row_opt(row: mysql::Row) -> Result<Self, mysql::FromRowError>
     where
@@ -105,14 +89,6 @@ impl mysql::prelude::FromRow for NpcSpawn {
 }
 
 impl NpcSpawn {
-    /// Load the npc spawn table from the database
-    pub fn load_table(mysql: &mut mysql::PooledConn) -> Result<Vec<Self>, super::ClientError> {
-        use mysql::prelude::Queryable;
-        let query = "SELECT * from spawnlist_npc";
-        let s = mysql.exec_map(query, (), |a: Self| a)?;
-        Ok(s)
-    }
-
     /// Create an npc object
     pub fn make_npc(&self, id: super::WorldObjectId, npcs: &HashMap<u32, NpcDefinition>) -> Npc {
         let npc = npcs.get(&self.npc_definition).unwrap();